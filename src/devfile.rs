@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Device I/O that can bypass the page cache.
+
+use std::cmp::{max, min};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use nix::fcntl::O_DIRECT;
+use nix::sys::ioctl;
+
+use crate::pvlabel::SECTOR_SIZE;
+use crate::util::align_to;
+
+// O_DIRECT I/O must be aligned, in memory and on the device, to the
+// device's logical sector size. This covers any sector size in use.
+const DIRECT_ALIGN: usize = 4096;
+
+/// A block device opened either normally, or with O_DIRECT so reads and
+/// writes go straight to the device, not through the page cache.
+///
+/// With O_DIRECT, I/O of any size at any offset is done by reading, and
+/// for writes modifying and writing back, the aligned blocks around it.
+pub struct DevFile {
+    file: File,
+    direct: bool,
+    pos: u64,
+    sector_size: u64,
+}
+
+impl DevFile {
+    /// Open the device at path, for writing as well as reading if write
+    /// is true.
+    pub fn open(path: &Path, write: bool, direct: bool) -> io::Result<DevFile> {
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(write);
+        if direct {
+            opts.custom_flags(O_DIRECT.bits());
+        }
+
+        let file = opts.open(path)?;
+        let sector_size = logical_sector_size(&file);
+
+        Ok(DevFile {
+            file: file,
+            direct: direct,
+            pos: 0,
+            sector_size: sector_size,
+        })
+    }
+
+    /// The underlying file, e.g. for ioctls.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// The logical sector size of the device, or 512 bytes if it is
+    /// e.g. a regular file.
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Flush all writes to the device.
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    // Read the aligned blocks covering len bytes at pos into an aligned
+    // buffer. Returns the buffer, the offset of pos within it, and how
+    // much of it was read before the end of the device.
+    fn read_blocks(&mut self, len: usize) -> io::Result<(AlignedBuf, usize, usize)> {
+        let start = self.pos / DIRECT_ALIGN as u64 * DIRECT_ALIGN as u64;
+        let skip = (self.pos - start) as usize;
+        let blocks_len = (skip + len + DIRECT_ALIGN - 1) / DIRECT_ALIGN * DIRECT_ALIGN;
+
+        let mut buf = AlignedBuf::new(blocks_len);
+        self.file.seek(SeekFrom::Start(start))?;
+
+        // A short read means the end of the device
+        let mut done = 0;
+        while done < blocks_len {
+            match self.file.read(&mut buf.as_mut_slice()[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+
+        Ok((buf, skip, done))
+    }
+}
+
+// The logical sector size of a block device, or 512 bytes if it is e.g.
+// a regular file.
+fn logical_sector_size(file: &File) -> u64 {
+    // BLKSSZGET
+    let op = ioctl::op_none(0x12, 104);
+    let mut val: i32 = 0;
+
+    match unsafe { ioctl::read_into(file.as_raw_fd(), op, &mut val) } {
+        Ok(_) if val > 0 => val as u64,
+        _ => SECTOR_SIZE as u64,
+    }
+}
+
+impl Read for DevFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.direct {
+            let n = self.file.read(buf)?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        let (blocks, skip, done) = self.read_blocks(buf.len())?;
+        let avail = done.saturating_sub(skip);
+        let n = min(buf.len(), avail);
+        buf[..n].copy_from_slice(&blocks.as_slice()[skip..skip + n]);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Write for DevFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.direct {
+            let n = self.file.write(buf)?;
+            self.pos += n as u64;
+            return Ok(n);
+        }
+
+        let start = self.pos / DIRECT_ALIGN as u64 * DIRECT_ALIGN as u64;
+        let (mut blocks, skip, done) = self.read_blocks(buf.len())?;
+        blocks.as_mut_slice()[skip..skip + buf.len()].copy_from_slice(buf);
+
+        // Write back only what was read, plus buf, in whole sectors: the
+        // last block may run past the end of the device
+        let len = align_to(max(done, skip + buf.len()) as u64, self.sector_size) as usize;
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.write_all(&blocks.as_slice()[..len])?;
+        self.pos += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for DevFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // With O_DIRECT, the file's own position is at the end of the
+        // last block accessed, not at pos.
+        let pos = match pos {
+            SeekFrom::Current(delta) => SeekFrom::Start((self.pos as i64 + delta) as u64),
+            pos => pos,
+        };
+        self.pos = self.file.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+// A buffer whose start is aligned to DIRECT_ALIGN in memory.
+struct AlignedBuf {
+    vec: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let vec = vec![0u8; len + DIRECT_ALIGN];
+        let addr = vec.as_ptr() as usize;
+        let offset = (DIRECT_ALIGN - addr % DIRECT_ALIGN) % DIRECT_ALIGN;
+
+        AlignedBuf {
+            vec: vec,
+            offset: offset,
+            len: len,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.vec[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.vec[self.offset..self.offset + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{metadata, File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::testutil::TestImage;
+
+    // An image a sector past a DIRECT_ALIGN boundary, so its last
+    // aligned block runs past its end
+    const IMAGE_SIZE: u64 = 16 * DIRECT_ALIGN as u64 + 512;
+
+    fn patterned_image() -> (TestImage, Vec<u8>) {
+        let image = TestImage::new(IMAGE_SIZE);
+        let pattern: Vec<u8> = (0..IMAGE_SIZE).map(|x| (x % 251) as u8).collect();
+        OpenOptions::new()
+            .write(true)
+            .open(image.path())
+            .unwrap()
+            .write_all(&pattern)
+            .unwrap();
+        (image, pattern)
+    }
+
+    #[test]
+    fn direct_reads_at_any_offset() {
+        let (image, pattern) = patterned_image();
+        let mut f = DevFile::open(image.path(), false, true).unwrap();
+
+        for &(offset, len) in &[(0, 512), (1, 10), (4095, 2), (5000, 9000), (65000, 1000)] {
+            let mut buf = vec![0; len];
+            f.seek(SeekFrom::Start(offset as u64)).unwrap();
+            f.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &pattern[offset..offset + len]);
+        }
+
+        // Reads stop at the end of the device
+        let mut buf = vec![0; 1024];
+        f.seek(SeekFrom::Start(IMAGE_SIZE - 100)).unwrap();
+        assert_eq!(f.read(&mut buf).unwrap(), 100);
+    }
+
+    #[test]
+    fn direct_writes_stay_within_device() {
+        let (image, mut pattern) = patterned_image();
+        let mut f = DevFile::open(image.path(), true, true).unwrap();
+
+        for &offset in &[3, 4000, IMAGE_SIZE as usize - 200] {
+            f.seek(SeekFrom::Start(offset as u64)).unwrap();
+            f.write_all(&[0xaa; 150]).unwrap();
+            for x in &mut pattern[offset..offset + 150] {
+                *x = 0xaa;
+            }
+        }
+        f.sync_all().unwrap();
+
+        assert_eq!(metadata(image.path()).unwrap().len(), IMAGE_SIZE);
+        let mut contents = Vec::new();
+        File::open(image.path())
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, pattern);
+    }
+}
@@ -0,0 +1,247 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Activating LVs as device-mapper devices.
+
+use std::io;
+
+use devicemapper::{DevId, DmFlags, DmName, DmOptions, DmUuid, DM};
+
+use crate::lv::LV;
+use crate::vg::VG;
+use crate::{Error, Result};
+
+/// The device-mapper table for an LV, with a line for each segment
+/// mapping its extents onto the PVs: (start, length, target type,
+/// params), with start and length in sectors.
+pub fn lv_table(vg: &VG, lv: &LV) -> Vec<(u64, u64, String, String)> {
+    lv.segments
+        .iter()
+        .map(|seg| {
+            (
+                seg.start_extent() * vg.extent_size(),
+                seg.extent_count() * vg.extent_size(),
+                seg.dm_type().to_string(),
+                seg.dm_params(vg),
+            )
+        })
+        .collect()
+}
+
+/// Create the device-mapper device for an LV, which appears as
+/// /dev/mapper/<vg>-<lv>, and load and resume its table. The device is
+/// read-only if the LV is. If the table can't be loaded and resumed, the
+/// device is removed again.
+pub fn activate(vg: &VG, lv: &LV) -> Result<()> {
+    let dm = DM::new()?;
+    let name = lv.dm_name(vg);
+    let uuid = lv.dm_uuid(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    let opts = if lv.is_writable() {
+        DmOptions::new()
+    } else {
+        DmOptions::new().set_flags(DmFlags::DM_READONLY)
+    };
+
+    dm.device_create(DmName::new(&name)?, Some(DmUuid::new(&uuid)?), &opts)?;
+
+    // Without the suspend flag, device_suspend resumes the device
+    let res = dm
+        .table_load(&id, &lv_table(vg, lv), &opts)
+        .and_then(|_| dm.device_suspend(&id, &DmOptions::new()));
+    if let Err(e) = res {
+        dm.device_remove(&id, &DmOptions::new())?;
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Remove the device-mapper device for an LV. Fails with EBUSY if the
+/// device is open, e.g. by a mounted filesystem.
+pub fn deactivate(vg: &VG, lv: &LV) -> Result<()> {
+    let dm = DM::new()?;
+    let name = lv.dm_name(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    if dm.device_info(&id)?.open_count() > 0 {
+        return Err(Error::Io(io::Error::from_raw_os_error(libc::EBUSY)));
+    }
+
+    dm.device_remove(&id, &DmOptions::new())?;
+
+    Ok(())
+}
+
+/// Replace the table of an LV's active device-mapper device with one
+/// built from its current segments. The new table is loaded alongside
+/// the live one first, so the device is only suspended, with I/O held
+/// rather than failed, while the two are swapped. If that fails, the
+/// device is resumed with its old table.
+pub fn reload(vg: &VG, lv: &LV) -> Result<()> {
+    let dm = DM::new()?;
+    let name = lv.dm_name(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    // A table loaded without the read-only flag is read-write
+    let opts = if lv.is_writable() {
+        DmOptions::new()
+    } else {
+        DmOptions::new().set_flags(DmFlags::DM_READONLY)
+    };
+    dm.table_load(&id, &lv_table(vg, lv), &opts)?;
+
+    if let Err(e) = dm.device_suspend(&id, &DmOptions::new().set_flags(DmFlags::DM_SUSPEND)) {
+        // Don't leave the new table to be swapped in by a later resume
+        let _ = dm.table_clear(&id);
+        return Err(e.into());
+    }
+
+    // Without the suspend flag, this swaps in the new table and resumes
+    if let Err(e) = dm.device_suspend(&id, &DmOptions::new()) {
+        let _ = dm.table_clear(&id);
+        let _ = dm.device_suspend(&id, &DmOptions::new());
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Suspend an LV's active device-mapper device, holding I/O to it until
+/// it is resumed.
+pub fn suspend(vg: &VG, lv: &LV) -> Result<()> {
+    let dm = DM::new()?;
+    let name = lv.dm_name(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    dm.device_suspend(&id, &DmOptions::new().set_flags(DmFlags::DM_SUSPEND))?;
+
+    Ok(())
+}
+
+/// Resume an LV's suspended device-mapper device.
+pub fn resume(vg: &VG, lv: &LV) -> Result<()> {
+    let dm = DM::new()?;
+    let name = lv.dm_name(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    dm.device_suspend(&id, &DmOptions::new())?;
+
+    Ok(())
+}
+
+/// Send a message, such as "create_snap 2 1", to the thin-pool target of
+/// an active thin pool LV.
+pub fn pool_message(vg: &VG, pool: &LV, msg: &str) -> Result<()> {
+    let dm = DM::new()?;
+    let name = pool.dm_name(vg);
+    let id = DevId::Name(DmName::new(&name)?);
+
+    dm.target_msg(&id, None, msg)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+    use std::path::Path;
+
+    use super::*;
+    use crate::testutil::{can_use_dm, LoopDevice};
+    use crate::{PvHeader, VG};
+
+    #[test]
+    fn activate_read_only() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-ro", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        vg.deactivate_lv("lv0").unwrap();
+        vg.set_lv_permission("lv0", false).unwrap();
+
+        vg.activate_lv("lv0").unwrap();
+        let dm = DM::new().unwrap();
+        let name = vg.lv_get("lv0").unwrap().dm_name(&vg);
+        let info = dm
+            .device_info(&DevId::Name(DmName::new(&name).unwrap()))
+            .unwrap();
+        vg.deactivate_lv("lv0").unwrap();
+
+        assert!(info.flags().contains(DmFlags::DM_READONLY));
+    }
+
+    #[test]
+    fn deactivate_removes_device() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-deact", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        let node = Path::new("/dev/mapper").join(vg.lv_get("lv0").unwrap().dm_name(&vg));
+        vg.deactivate_lv("lv0").unwrap();
+        vg.activate_lv("lv0").unwrap();
+        assert!(node.exists());
+
+        vg.deactivate_lv("lv0").unwrap();
+        assert!(!node.exists());
+
+        // Deactivating an inactive LV does nothing
+        vg.deactivate_lv("lv0").unwrap();
+    }
+
+    #[test]
+    fn resize_active_lv() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-grow", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        let node = Path::new("/dev/mapper").join(vg.lv_get("lv0").unwrap().dm_name(&vg));
+        let extent_bytes = vg.extent_size() * 512;
+
+        let size = || File::open(&node).unwrap().seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(size(), extent_bytes);
+
+        vg.lv_resize("lv0", 3).unwrap();
+        let grown = size();
+        vg.deactivate_lv("lv0").unwrap();
+
+        assert_eq!(grown, 3 * extent_bytes);
+    }
+
+    #[test]
+    fn set_read_only_while_active() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-ro-active", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        vg.set_lv_permission("lv0", false).unwrap();
+
+        let dm = DM::new().unwrap();
+        let name = vg.lv_get("lv0").unwrap().dm_name(&vg);
+        let info = dm
+            .device_info(&DevId::Name(DmName::new(&name).unwrap()))
+            .unwrap();
+        vg.deactivate_lv("lv0").unwrap();
+
+        assert!(info.flags().contains(DmFlags::DM_READONLY));
+    }
+}
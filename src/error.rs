@@ -2,16 +2,54 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::error;
+use std::fmt;
 use std::io;
 
 #[derive(Debug)]
 pub enum Error {
+    /// An I/O error, or another error not covered below.
     Io(io::Error),
+    /// An error from device-mapper.
     Dm(devicemapper::DmError),
+    /// No PV label was found on the device.
+    LabelNotFound,
+    /// A label, metadata area header, or metadata text failed its
+    /// checksum.
+    ChecksumMismatch,
+    /// There are not enough free extents for the allocation.
+    NoSpace,
+    /// Text metadata or configuration could not be parsed.
+    Parse(String),
+    /// The named object, e.g. an LV, does not exist.
+    NotFound(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Dm(ref err) => write!(f, "device-mapper error: {:?}", err),
+            Error::LabelNotFound => write!(f, "Label not found"),
+            Error::ChecksumMismatch => write!(f, "Checksum mismatch"),
+            Error::NoSpace => write!(f, "Not enough free space"),
+            Error::Parse(ref msg) => write!(f, "Parse error: {}", msg),
+            Error::NotFound(ref what) => write!(f, "{} not found", what),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::Io(err)
@@ -0,0 +1,136 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Filtering devices to scan, in the style of lvm.conf's devices/filter.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::parser::{Entry, LvmTextMap, TextMapOps};
+use crate::{Error, Result};
+
+/// A list of rules deciding which devices to scan for PVs.
+///
+/// Each rule is written as in lvm.conf: "a" to accept or "r" to reject,
+/// then a regular expression between a pair of delimiters, e.g.
+/// `"a|^/dev/sd|"` or `"r/loop/"`. A device is accepted or rejected by
+/// the first rule whose expression matches its path. Devices that match
+/// no rule are accepted.
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    rules: Vec<(bool, Regex)>,
+}
+
+impl DeviceFilter {
+    /// Create a filter from a list of rules.
+    pub fn new(rules: &[&str]) -> Result<DeviceFilter> {
+        let mut parsed = Vec::new();
+
+        for rule in rules {
+            let err = || {
+                Error::Io(io::Error::new(
+                    Other,
+                    format!("Invalid filter rule {}", rule),
+                ))
+            };
+
+            let mut chars = rule.chars();
+            let accept = match chars.next() {
+                Some('a') => true,
+                Some('r') => false,
+                _ => return Err(err()),
+            };
+            let delim = chars.next().ok_or_else(err)?;
+            let pattern = chars.as_str();
+            if !pattern.ends_with(delim) {
+                return Err(err());
+            }
+            let pattern = &pattern[..pattern.len() - delim.len_utf8()];
+
+            let re = Regex::new(pattern).map_err(|e| {
+                Error::Io(io::Error::new(
+                    Other,
+                    format!("Invalid filter rule {}: {}", rule, e),
+                ))
+            })?;
+
+            parsed.push((accept, re));
+        }
+
+        Ok(DeviceFilter { rules: parsed })
+    }
+
+    /// Create a filter from the devices/filter setting in a parsed
+    /// lvm.conf. If there is no such setting, all devices are accepted.
+    pub fn from_config(conf: &LvmTextMap) -> Result<DeviceFilter> {
+        let list = match conf
+            .textmap_from_textmap("devices")
+            .and_then(|devices| devices.list_from_textmap("filter"))
+        {
+            Some(list) => list,
+            None => return Ok(DeviceFilter { rules: Vec::new() }),
+        };
+
+        let mut rules = Vec::new();
+        for entry in list {
+            match entry {
+                &Entry::String(ref rule) => rules.push(&rule[..]),
+                _ => {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        "devices/filter must be a list of strings",
+                    )))
+                }
+            }
+        }
+
+        DeviceFilter::new(&rules)
+    }
+
+    /// Whether the device at path should be scanned.
+    pub fn accepts(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+
+        self.rules
+            .iter()
+            .find(|&&(_, ref re)| re.is_match(&path))
+            .map_or(true, |&(accept, _)| accept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::buf_to_textmap;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let filter = DeviceFilter::new(&["a|^/dev/sd|", "r/loop/", "a|loop0|"]).unwrap();
+        assert!(filter.accepts(Path::new("/dev/sda")));
+        assert!(!filter.accepts(Path::new("/dev/loop0")));
+        assert!(filter.accepts(Path::new("/dev/vda")));
+
+        for bad in &["", "x|sd|", "a", "a|sd"] {
+            assert!(DeviceFilter::new(&[bad]).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn filter_from_config() {
+        let conf = buf_to_textmap(b"devices {\nfilter = [\"a|loop0|\", \"r|.*|\"]\n}\n").unwrap();
+        let filter = DeviceFilter::from_config(&conf).unwrap();
+        assert!(filter.accepts(Path::new("/dev/loop0")));
+        assert!(!filter.accepts(Path::new("/dev/sda")));
+
+        let conf = buf_to_textmap(b"global {\nlocking_type = 1\n}\n").unwrap();
+        let filter = DeviceFilter::from_config(&conf).unwrap();
+        assert!(filter.accepts(Path::new("/dev/sda")));
+
+        let conf = buf_to_textmap(b"devices {\nfilter = [1]\n}\n").unwrap();
+        assert!(DeviceFilter::from_config(&conf).is_err());
+    }
+}
@@ -11,20 +11,39 @@ extern crate byteorder;
 extern crate crc;
 extern crate libc;
 extern crate nix;
+extern crate regex;
+extern crate rustc_serialize;
 extern crate time;
 extern crate unix_socket;
 extern crate uuid;
 
+mod devfile;
+mod dm;
 mod error;
+mod filter;
 mod lv;
+pub mod lvmetad;
 pub mod parser;
 mod pv;
 mod pvlabel;
+mod status;
+#[cfg(test)]
+mod testutil;
 mod util;
 mod vg;
 
 pub use error::{Error, Result};
+pub use filter::DeviceFilter;
 pub use lv::LV;
 pub use pv::PV;
-pub use pvlabel::{pvheader_scan, PvHeader};
-pub use vg::VG;
+pub use pvlabel::{
+    compute_layout, initialize_many, list_vgs, pvheader_scan, pvheader_scan_by_uuid,
+    pvheader_scan_files, pvheader_scan_files_by_uuid, pvheader_scan_filtered, read_vg_metadata,
+    vg_uuids, PvCreateOptions, PvHeader, PvLayout, WriteOptions,
+};
+pub use status::{LvStatus, StatusFlag, StatusSet, VgStatus};
+pub use util::{format_size, is_valid_lvm_name, parse_size, NameKind};
+pub use vg::{
+    AllocConstraints, AllocPolicy, Reservation, SizeDiscrepancy, Transaction, VgAvailability,
+    VgBuilder, VgSummary, VG,
+};
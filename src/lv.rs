@@ -4,18 +4,28 @@
 
 //! Logical Volumes
 
+use std::cmp::min;
 use std::collections::BTreeMap;
+use std::fs::OpenOptions;
 use std::io;
 use std::io::ErrorKind::Other;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
 use devicemapper::Device;
 
-use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
-use crate::PV;
+use crate::parser::{status_from_textmap, strings_from_textmap, Entry, LvmTextMap, TextMapOps};
+use crate::pvlabel::SECTOR_SIZE;
+use crate::status::{LvStatus, StatusSet};
 use crate::{Error, Result};
+use crate::{PV, VG};
+
+// How much to zero at each end of an LV when wiping signatures. This is
+// enough to cover the superblocks of common filesystems and of MD RAID.
+const WIPE_SIZE: u64 = 128 * 1024;
 
 /// A Logical Volume that is created from a Volume Group.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LV {
     /// The name.
     pub name: String,
@@ -25,10 +35,14 @@ pub struct LV {
     pub status: Vec<String>,
     /// Flags.
     pub flags: Vec<String>,
+    /// Tags, e.g. for selecting LVs to activate or back up.
+    pub tags: Vec<String>,
     /// Created by this host.
     pub creation_host: String,
     /// Created at this Unix time.
     pub creation_time: i64,
+    /// The allocation policy, if not inherited from the VG.
+    pub alloc_policy: Option<String>,
     /// A list of the segments comprising the LV.
     pub segments: Vec<Box<dyn segment::Segment>>,
     /// The major/minor number of the LV.
@@ -40,6 +54,63 @@ impl LV {
     pub fn used_extents(&self) -> u64 {
         self.segments.iter().map(|x| x.extent_count()).sum()
     }
+
+    /// The LV's status, parsed into known values.
+    pub fn status_set(&self) -> StatusSet<LvStatus> {
+        StatusSet::from_strings(&self.status)
+    }
+
+    /// Whether the LV is visible to the user.
+    pub fn is_visible(&self) -> bool {
+        self.status_set().contains(LvStatus::Visible)
+    }
+
+    /// Whether the LV may be written.
+    pub fn is_writable(&self) -> bool {
+        self.status_set().contains(LvStatus::Write)
+    }
+
+    /// The name of the device-mapper device for this LV, in the form
+    /// "<vg>-<lv>" with any hyphens in either name doubled.
+    pub fn dm_name(&self, vg: &VG) -> String {
+        dm_name_for(vg.name(), &self.name)
+    }
+
+    /// The UUID of the device-mapper device for this LV, in the form
+    /// "LVM-<vg uuid><lv uuid>" with the hyphens removed from both UUIDs.
+    pub fn dm_uuid(&self, vg: &VG) -> String {
+        format!(
+            "LVM-{}{}",
+            vg.id().replace("-", ""),
+            self.id.replace("-", "")
+        )
+    }
+
+    /// Zero the beginning and end of the activated LV, so that
+    /// signatures left over from whatever previously occupied its
+    /// extents (filesystem superblocks, RAID metadata, partition tables)
+    /// are not detected on it.
+    pub fn wipe_signatures(&self, vg: &VG) -> Result<()> {
+        let path = PathBuf::from(format!("/dev/mapper/{}", self.dm_name(vg)));
+        let size = self.used_extents() * vg.extent_size() * SECTOR_SIZE as u64;
+
+        wipe_ends(&path, size)
+    }
+}
+
+// Zero the first and last WIPE_SIZE bytes of the size bytes at path.
+fn wipe_ends(path: &Path, size: u64) -> Result<()> {
+    let wipe_len = min(WIPE_SIZE, size);
+    let zeroes = vec![0u8; wipe_len as usize];
+
+    let mut f = OpenOptions::new().write(true).open(path)?;
+
+    f.write_all(&zeroes)?;
+    f.seek(SeekFrom::Start(size - wipe_len))?;
+    f.write_all(&zeroes)?;
+    f.sync_all()?;
+
+    Ok(())
 }
 
 impl PartialEq for LV {
@@ -48,6 +119,15 @@ impl PartialEq for LV {
     }
 }
 
+/// The name of the device-mapper device for LV lv_name in VG vg_name.
+pub fn dm_name_for(vg_name: &str, lv_name: &str) -> String {
+    format!(
+        "{}-{}",
+        vg_name.replace("-", "--"),
+        lv_name.replace("-", "--")
+    )
+}
+
 pub fn used_areas(lv: &LV) -> Vec<(Device, u64, u64)> {
     let mut v = Vec::new();
     for seg in &lv.segments {
@@ -86,13 +166,19 @@ pub fn from_textmap(name: &str, map: &LvmTextMap, pvs: &BTreeMap<String, PV>) ->
         })
         .collect();
 
+    let tags = strings_from_textmap(map, "tags");
+
     Ok(LV {
         name: name.to_string(),
         id: id.to_string(),
         status: status,
         flags: flags,
+        tags: tags,
         creation_host: creation_host.to_string(),
         creation_time: creation_time,
+        alloc_policy: map
+            .string_from_textmap("allocation_policy")
+            .map(|x| x.to_string()),
         segments: segments,
         device: None,
     })
@@ -117,6 +203,15 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
         )),
     );
 
+    if !lv.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(Box::new(
+                lv.tags.iter().map(|x| Entry::String(x.clone())).collect(),
+            )),
+        );
+    }
+
     map.insert(
         "creation_host".to_string(),
         Entry::String(lv.creation_host.clone()),
@@ -126,6 +221,13 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
         Entry::Number(lv.creation_time as i64),
     );
 
+    if let Some(ref policy) = lv.alloc_policy {
+        map.insert(
+            "allocation_policy".to_string(),
+            Entry::String(policy.clone()),
+        );
+    }
+
     map.insert(
         "segment_count".to_string(),
         Entry::Number(lv.segments.len() as i64),
@@ -142,22 +244,86 @@ pub fn to_textmap(lv: &LV, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
 }
 
 pub mod segment {
+    use std::any::Any;
     use std::collections::BTreeMap;
     use std::fmt;
     use std::io::Error;
     use std::io::ErrorKind::Other;
     use std::io::Result;
+    use std::str::FromStr;
 
     use devicemapper::Device;
+    use rustc_serialize::json::{Json, ToJson};
 
     use crate::parser::{Entry, LvmTextMap, TextMapOps};
     use crate::PV;
     use crate::VG;
 
+    /// The type of a segment, as given by "type" in its metadata.
+    ///
+    /// Only striped (including linear), thin-pool, and thin segments
+    /// are supported; metadata with other types can't be loaded.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub enum SegmentType {
+        /// Striped across one or more PVs. LVM writes linear segments
+        /// as striped with a single stripe.
+        Striped,
+        /// An alias for striped with a single stripe.
+        Linear,
+        /// Legacy LVM mirroring.
+        Mirror,
+        /// A thin LV, allocated from a thin pool.
+        Thin,
+        /// A thin pool.
+        ThinPool,
+        /// MD RAID, e.g. level "1" or "5_ls".
+        Raid { level: String },
+        /// A cached LV.
+        Cache,
+    }
+
+    impl fmt::Display for SegmentType {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                SegmentType::Striped => write!(f, "striped"),
+                SegmentType::Linear => write!(f, "linear"),
+                SegmentType::Mirror => write!(f, "mirror"),
+                SegmentType::Thin => write!(f, "thin"),
+                SegmentType::ThinPool => write!(f, "thin-pool"),
+                SegmentType::Raid { ref level } => write!(f, "raid{}", level),
+                SegmentType::Cache => write!(f, "cache"),
+            }
+        }
+    }
+
+    impl FromStr for SegmentType {
+        type Err = Error;
+
+        fn from_str(s: &str) -> Result<SegmentType> {
+            match s {
+                "striped" => Ok(SegmentType::Striped),
+                "linear" => Ok(SegmentType::Linear),
+                "mirror" => Ok(SegmentType::Mirror),
+                "thin" => Ok(SegmentType::Thin),
+                "thin-pool" => Ok(SegmentType::ThinPool),
+                "cache" => Ok(SegmentType::Cache),
+                _ if s.starts_with("raid") && s.len() > 4 => Ok(SegmentType::Raid {
+                    level: s[4..].to_string(),
+                }),
+                _ => Err(Error::new(Other, format!("Unknown segment type \"{}\"", s))),
+            }
+        }
+    }
+
     /// Used to treat segment types polymorphically
     pub trait Segment: fmt::Debug {
+        /// Returns the segment's type.
+        fn segment_type(&self) -> SegmentType;
         /// Convert this segment to an LvmTextMap.
         fn to_textmap(&self, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap;
+        /// Convert this segment to JSON. Unlike the textmap, this names
+        /// PVs by device number, and does not follow the on-disk format.
+        fn to_json(&self) -> Json;
         /// Returns the first extent of the segment.
         fn start_extent(&self) -> u64;
         /// Returns how many extents are in the segment.
@@ -170,19 +336,72 @@ pub mod segment {
         fn dm_type(&self) -> &'static str;
         /// Generates the parameters to send to DM for this segment.
         fn dm_params(&self, vg: &VG) -> String;
+        /// Returns a copy of the segment.
+        fn clone_box(&self) -> Box<dyn Segment>;
+        /// Returns the segment as Any, so that once its type is known
+        /// from `segment_type()`, its fields can be reached by
+        /// downcasting.
+        fn as_any(&self) -> &dyn Any;
+        /// Returns the segment as mutable Any.
+        fn as_any_mut(&mut self) -> &mut dyn Any;
+        /// Moves the areas that make up the segment, replacing the device
+        /// and starting extent of each with the result of remap.
+        fn remap_areas(&mut self, remap: &dyn Fn(Device, u64) -> (Device, u64));
+        /// Updates any references the segment has to another LV by name,
+        /// after that LV is renamed from old to new.
+        fn rename_lv_refs(&mut self, old: &str, new: &str);
+    }
+
+    impl Clone for Box<dyn Segment> {
+        fn clone(&self) -> Box<dyn Segment> {
+            self.clone_box()
+        }
     }
 
     pub fn from_textmap(map: &LvmTextMap, pvs: &BTreeMap<String, PV>) -> Result<Box<dyn Segment>> {
-        match map.string_from_textmap("type") {
-            Some("striped") => StripedSegment::from_textmap(map, pvs),
-            Some("thin-pool") => ThinpoolSegment::from_textmap(map),
-            Some("thin") => ThinSegment::from_textmap(map),
-            _ => unimplemented!(),
+        let ty = map
+            .string_from_textmap("type")
+            .ok_or_else(|| Error::new(Other, "Segment has no type"))?;
+
+        match ty.parse()? {
+            SegmentType::Striped | SegmentType::Linear => StripedSegment::from_textmap(map, pvs),
+            SegmentType::ThinPool => ThinpoolSegment::from_textmap(map),
+            SegmentType::Thin => ThinSegment::from_textmap(map),
+            ty => Err(unsupported(&ty)),
         }
     }
 
+    /// Construct a segment from JSON, as written by `Segment::to_json`.
+    pub fn from_json(json: &Json) -> Result<Box<dyn Segment>> {
+        let ty = json
+            .find("type")
+            .and_then(|x| x.as_string())
+            .ok_or_else(|| Error::new(Other, "Segment has no type"))?;
+
+        match ty.parse()? {
+            SegmentType::Striped | SegmentType::Linear => StripedSegment::from_json(json),
+            SegmentType::ThinPool => ThinpoolSegment::from_json(json),
+            SegmentType::Thin => ThinSegment::from_json(json),
+            ty => Err(unsupported(&ty)),
+        }
+    }
+
+    fn unsupported(ty: &SegmentType) -> Error {
+        Error::new(Other, format!("Unsupported segment type \"{}\"", ty))
+    }
+
+    fn json_u64(json: &Json, name: &str) -> Option<u64> {
+        json.find(name).and_then(|x| x.as_u64())
+    }
+
+    fn json_string(json: &Json, name: &str) -> Option<String> {
+        json.find(name)
+            .and_then(|x| x.as_string())
+            .map(|x| x.to_string())
+    }
+
     /// A striped Logical Volume Segment.
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
     pub struct StripedSegment {
         /// The first extent within the LV this segment comprises.
         pub start_extent: u64,
@@ -224,12 +443,40 @@ pub mod segment {
                 extent_count: map.i64_from_textmap("extent_count").ok_or(err())? as u64,
                 stripes: stripes,
                 // optional
-                stripe_size: map.i64_from_textmap("start_extent").map(|x| x as u64),
+                stripe_size: map.i64_from_textmap("stripe_size").map(|x| x as u64),
+            }))
+        }
+
+        pub fn from_json(json: &Json) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "striped segment JSON parsing error");
+
+            let mut stripes = Vec::new();
+            for stripe in json
+                .find("stripes")
+                .and_then(|x| x.as_array())
+                .ok_or(err())?
+            {
+                let dev = json_string(stripe, "device")
+                    .and_then(|x| Device::from_str(&x).ok())
+                    .ok_or(err())?;
+                stripes.push((dev, json_u64(stripe, "start_extent").ok_or(err())?));
+            }
+
+            Ok(Box::new(StripedSegment {
+                start_extent: json_u64(json, "start_extent").ok_or(err())?,
+                extent_count: json_u64(json, "extent_count").ok_or(err())?,
+                stripes: stripes,
+                // optional
+                stripe_size: json_u64(json, "stripe_size"),
             }))
         }
     }
 
     impl Segment for StripedSegment {
+        fn segment_type(&self) -> SegmentType {
+            SegmentType::Striped
+        }
+
         fn to_textmap(&self, dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
             let mut map = LvmTextMap::new();
 
@@ -241,7 +488,10 @@ pub mod segment {
                 "extent_count".to_string(),
                 Entry::Number(self.extent_count as i64),
             );
-            map.insert("type".to_string(), Entry::String("striped".to_string()));
+            map.insert(
+                "type".to_string(),
+                Entry::String(self.segment_type().to_string()),
+            );
             map.insert(
                 "stripe_count".to_string(),
                 Entry::Number(self.stripes.len() as i64),
@@ -266,6 +516,37 @@ pub mod segment {
             map
         }
 
+        fn to_json(&self) -> Json {
+            let mut obj = BTreeMap::new();
+            obj.insert(
+                "type".to_string(),
+                self.segment_type().to_string().to_json(),
+            );
+            obj.insert("start_extent".to_string(), self.start_extent.to_json());
+            obj.insert("extent_count".to_string(), self.extent_count.to_json());
+            if let Some(stripe_size) = self.stripe_size {
+                obj.insert("stripe_size".to_string(), stripe_size.to_json());
+            }
+            obj.insert(
+                "stripes".to_string(),
+                Json::Array(
+                    self.stripes
+                        .iter()
+                        .map(|&(dev, start)| {
+                            let mut stripe = BTreeMap::new();
+                            stripe.insert(
+                                "device".to_string(),
+                                format!("{}:{}", dev.major, dev.minor).to_json(),
+                            );
+                            stripe.insert("start_extent".to_string(), start.to_json());
+                            Json::Object(stripe)
+                        })
+                        .collect(),
+                ),
+            );
+            Json::Object(obj)
+        }
+
         fn start_extent(&self) -> u64 {
             self.start_extent
         }
@@ -280,9 +561,11 @@ pub mod segment {
 
         // returns (device, start_extent, length)
         fn used_areas(&self) -> Vec<(Device, u64, u64)> {
+            // The segment's extents are split evenly across the stripes
+            let stripe_len = self.extent_count / self.stripes.len() as u64;
             self.stripes
                 .iter()
-                .map(|&(dev, ext)| (dev, ext, self.extent_count))
+                .map(|&(dev, ext)| (dev, ext, stripe_len))
                 .collect()
         }
 
@@ -327,9 +610,29 @@ pub mod segment {
                 )
             }
         }
+
+        fn clone_box(&self) -> Box<dyn Segment> {
+            Box::new(self.clone())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn remap_areas(&mut self, remap: &dyn Fn(Device, u64) -> (Device, u64)) {
+            for stripe in &mut self.stripes {
+                *stripe = remap(stripe.0, stripe.1);
+            }
+        }
+
+        fn rename_lv_refs(&mut self, _old: &str, _new: &str) {}
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
     pub enum DiscardPolicy {
         Passdown,
         NoPassdown,
@@ -337,7 +640,7 @@ pub mod segment {
     }
 
     /// A Thinpool Logical Volume Segment, tying together data and metadata LVs
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
     pub struct ThinpoolSegment {
         /// The first extent within the LV this segment comprises.
         pub start_extent: u64,
@@ -384,12 +687,46 @@ pub mod segment {
                 transaction_id: map.i64_from_textmap("transaction_id").ok_or(err())? as u64,
                 chunk_size: map.i64_from_textmap("chunk_size").ok_or(err())? as u64,
                 discards: discards,
-                zero_new_blocks: map.i64_from_textmap("start_extent").ok_or(err())? != 0,
+                zero_new_blocks: map.i64_from_textmap("zero_new_blocks").ok_or(err())? != 0,
+            }))
+        }
+
+        pub fn from_json(json: &Json) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "thinpool segment JSON parsing error");
+
+            let discards = match json.find("discards").and_then(|x| x.as_string()) {
+                Some("passdown") => DiscardPolicy::Passdown,
+                Some("nopassdown") => DiscardPolicy::NoPassdown,
+                Some("ignore") => DiscardPolicy::Ignore,
+                _ => {
+                    return Err(Error::new(
+                        Other,
+                        "Invalid text for \"discards\" in thinpool segment",
+                    ))
+                }
+            };
+
+            Ok(Box::new(ThinpoolSegment {
+                start_extent: json_u64(json, "start_extent").ok_or(err())?,
+                extent_count: json_u64(json, "extent_count").ok_or(err())?,
+                metadata_lv: json_string(json, "metadata_lv").ok_or(err())?,
+                data_lv: json_string(json, "data_lv").ok_or(err())?,
+                transaction_id: json_u64(json, "transaction_id").ok_or(err())?,
+                chunk_size: json_u64(json, "chunk_size").ok_or(err())?,
+                discards: discards,
+                zero_new_blocks: json
+                    .find("zero_new_blocks")
+                    .and_then(|x| x.as_boolean())
+                    .ok_or(err())?,
             }))
         }
     }
 
     impl Segment for ThinpoolSegment {
+        fn segment_type(&self) -> SegmentType {
+            SegmentType::ThinPool
+        }
+
         fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
             let mut map = LvmTextMap::new();
 
@@ -407,7 +744,10 @@ pub mod segment {
                 "extent_count".to_string(),
                 Entry::Number(self.extent_count as i64),
             );
-            map.insert("type".to_string(), Entry::String("thin-pool".to_string()));
+            map.insert(
+                "type".to_string(),
+                Entry::String(self.segment_type().to_string()),
+            );
             map.insert(
                 "metadata".to_string(),
                 Entry::String(self.metadata_lv.clone()),
@@ -430,6 +770,32 @@ pub mod segment {
             map
         }
 
+        fn to_json(&self) -> Json {
+            let discards = match self.discards {
+                DiscardPolicy::Passdown => "passdown",
+                DiscardPolicy::NoPassdown => "nopassdown",
+                DiscardPolicy::Ignore => "ignore",
+            };
+
+            let mut obj = BTreeMap::new();
+            obj.insert(
+                "type".to_string(),
+                self.segment_type().to_string().to_json(),
+            );
+            obj.insert("start_extent".to_string(), self.start_extent.to_json());
+            obj.insert("extent_count".to_string(), self.extent_count.to_json());
+            obj.insert("metadata_lv".to_string(), self.metadata_lv.to_json());
+            obj.insert("data_lv".to_string(), self.data_lv.to_json());
+            obj.insert("transaction_id".to_string(), self.transaction_id.to_json());
+            obj.insert("chunk_size".to_string(), self.chunk_size.to_json());
+            obj.insert("discards".to_string(), discards.to_json());
+            obj.insert(
+                "zero_new_blocks".to_string(),
+                self.zero_new_blocks.to_json(),
+            );
+            Json::Object(obj)
+        }
+
         fn start_extent(&self) -> u64 {
             self.start_extent
         }
@@ -474,11 +840,35 @@ pub mod segment {
 
             ctor
         }
+
+        fn clone_box(&self) -> Box<dyn Segment> {
+            Box::new(self.clone())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        // None, they're all on subordinate devs
+        fn remap_areas(&mut self, _remap: &dyn Fn(Device, u64) -> (Device, u64)) {}
+
+        fn rename_lv_refs(&mut self, old: &str, new: &str) {
+            if self.metadata_lv == old {
+                self.metadata_lv = new.to_string();
+            }
+            if self.data_lv == old {
+                self.data_lv = new.to_string();
+            }
+        }
     }
 
     /// A Thin Logical Volume Segment, a copy-on-write segment
     /// allocated from a thinpool.
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone)]
     pub struct ThinSegment {
         /// The first extent within the LV this segment comprises.
         pub start_extent: u64,
@@ -490,6 +880,11 @@ pub mod segment {
         pub transaction_id: u64,
         /// Device ID within the thinpool
         pub device_id: u64,
+        /// For a thin snapshot, the name of the thin LV it is a snapshot of
+        pub origin: Option<String>,
+        /// The name of a read-only LV outside the pool that provides any
+        /// blocks not yet written to this one
+        pub external_origin: Option<String>,
     }
 
     impl ThinSegment {
@@ -505,11 +900,35 @@ pub mod segment {
                     .to_string(),
                 transaction_id: map.i64_from_textmap("transaction_id").ok_or(err())? as u64,
                 device_id: map.i64_from_textmap("device_id").ok_or(err())? as u64,
+                // optional
+                origin: map.string_from_textmap("origin").map(|x| x.to_string()),
+                external_origin: map
+                    .string_from_textmap("external_origin")
+                    .map(|x| x.to_string()),
+            }))
+        }
+
+        pub fn from_json(json: &Json) -> Result<Box<dyn Segment>> {
+            let err = || Error::new(Other, "thin segment JSON parsing error");
+
+            Ok(Box::new(ThinSegment {
+                start_extent: json_u64(json, "start_extent").ok_or(err())?,
+                extent_count: json_u64(json, "extent_count").ok_or(err())?,
+                thin_pool: json_string(json, "thin_pool").ok_or(err())?,
+                transaction_id: json_u64(json, "transaction_id").ok_or(err())?,
+                device_id: json_u64(json, "device_id").ok_or(err())?,
+                // optional
+                origin: json_string(json, "origin"),
+                external_origin: json_string(json, "external_origin"),
             }))
         }
     }
 
     impl Segment for ThinSegment {
+        fn segment_type(&self) -> SegmentType {
+            SegmentType::Thin
+        }
+
         fn to_textmap(&self, _dev_to_idx: &BTreeMap<Device, usize>) -> LvmTextMap {
             let mut map = LvmTextMap::new();
 
@@ -521,7 +940,10 @@ pub mod segment {
                 "extent_count".to_string(),
                 Entry::Number(self.extent_count as i64),
             );
-            map.insert("type".to_string(), Entry::String("thin".to_string()));
+            map.insert(
+                "type".to_string(),
+                Entry::String(self.segment_type().to_string()),
+            );
             map.insert(
                 "thin_pool".to_string(),
                 Entry::String(self.thin_pool.clone()),
@@ -534,10 +956,39 @@ pub mod segment {
                 "device_id".to_string(),
                 Entry::Number(self.device_id as i64),
             );
+            if let Some(ref origin) = self.origin {
+                map.insert("origin".to_string(), Entry::String(origin.clone()));
+            }
+            if let Some(ref external_origin) = self.external_origin {
+                map.insert(
+                    "external_origin".to_string(),
+                    Entry::String(external_origin.clone()),
+                );
+            }
 
             map
         }
 
+        fn to_json(&self) -> Json {
+            let mut obj = BTreeMap::new();
+            obj.insert(
+                "type".to_string(),
+                self.segment_type().to_string().to_json(),
+            );
+            obj.insert("start_extent".to_string(), self.start_extent.to_json());
+            obj.insert("extent_count".to_string(), self.extent_count.to_json());
+            obj.insert("thin_pool".to_string(), self.thin_pool.to_json());
+            obj.insert("transaction_id".to_string(), self.transaction_id.to_json());
+            obj.insert("device_id".to_string(), self.device_id.to_json());
+            if let Some(ref origin) = self.origin {
+                obj.insert("origin".to_string(), origin.to_json());
+            }
+            if let Some(ref external_origin) = self.external_origin {
+                obj.insert("external_origin".to_string(), external_origin.to_json());
+            }
+            Json::Object(obj)
+        }
+
         fn start_extent(&self) -> u64 {
             self.start_extent
         }
@@ -564,5 +1015,178 @@ pub mod segment {
             let pool_dev = pool_lv.device.unwrap();
             format!("{}:{} {}", pool_dev.major, pool_dev.minor, self.device_id)
         }
+
+        fn clone_box(&self) -> Box<dyn Segment> {
+            Box::new(self.clone())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        // None, they're all on subordinate devs
+        fn remap_areas(&mut self, _remap: &dyn Fn(Device, u64) -> (Device, u64)) {}
+
+        fn rename_lv_refs(&mut self, old: &str, new: &str) {
+            if self.thin_pool == old {
+                self.thin_pool = new.to_string();
+            }
+            if self.origin.as_ref().map_or(false, |x| x == old) {
+                self.origin = Some(new.to_string());
+            }
+            if self.external_origin.as_ref().map_or(false, |x| x == old) {
+                self.external_origin = Some(new.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::testutil::TestImage;
+    use crate::vg::VgBuilder;
+
+    // An image of size bytes, all 0xaa.
+    fn filled_image(size: u64) -> TestImage {
+        let image = TestImage::new(size);
+        OpenOptions::new()
+            .write(true)
+            .open(image.path())
+            .unwrap()
+            .write_all(&vec![0xaa; size as usize])
+            .unwrap();
+        image
+    }
+
+    fn contents(image: &TestImage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        File::open(image.path())
+            .unwrap()
+            .read_to_end(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn thin_segment_round_trip() {
+        let seg = segment::ThinSegment {
+            start_extent: 0,
+            extent_count: 10,
+            thin_pool: "pool".to_string(),
+            transaction_id: 3,
+            device_id: 7,
+            origin: None,
+            external_origin: Some("ext0".to_string()),
+        };
+        let thin = |seg: Box<dyn segment::Segment>| {
+            seg.as_any()
+                .downcast_ref::<segment::ThinSegment>()
+                .unwrap()
+                .clone()
+        };
+
+        let map = segment::Segment::to_textmap(&seg, &BTreeMap::new());
+        let parsed = thin(segment::ThinSegment::from_textmap(&map).unwrap());
+        assert_eq!(parsed, seg);
+        assert_eq!(parsed.device_id, 7);
+        assert_eq!(parsed.external_origin, Some("ext0".to_string()));
+
+        let json = segment::Segment::to_json(&seg);
+        assert_eq!(thin(segment::ThinSegment::from_json(&json).unwrap()), seg);
+
+        // Neither origin is required
+        let plain = segment::ThinSegment {
+            external_origin: None,
+            ..seg.clone()
+        };
+        let map = segment::Segment::to_textmap(&plain, &BTreeMap::new());
+        assert!(!map.contains_key("external_origin"));
+        assert_eq!(
+            thin(segment::ThinSegment::from_textmap(&map).unwrap()),
+            plain
+        );
+    }
+
+    #[test]
+    fn segment_type_round_trip() {
+        use self::segment::SegmentType;
+
+        let types = vec![
+            SegmentType::Striped,
+            SegmentType::Linear,
+            SegmentType::Mirror,
+            SegmentType::Thin,
+            SegmentType::ThinPool,
+            SegmentType::Raid {
+                level: "1".to_string(),
+            },
+            SegmentType::Raid {
+                level: "5_ls".to_string(),
+            },
+            SegmentType::Cache,
+        ];
+        for seg_type in types {
+            let s = seg_type.to_string();
+            assert_eq!(s.parse::<SegmentType>().unwrap(), seg_type, "{}", s);
+        }
+
+        assert_eq!(
+            "thin-pool".parse::<SegmentType>().unwrap(),
+            SegmentType::ThinPool
+        );
+        for bad in &["", "raid", "snapshot", "Striped"] {
+            assert!(bad.parse::<SegmentType>().is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn wipe_ends_zeroes_start_and_end() {
+        let image = filled_image(4 * WIPE_SIZE);
+        wipe_ends(image.path(), 4 * WIPE_SIZE).unwrap();
+
+        let buf = contents(&image);
+        let wipe = WIPE_SIZE as usize;
+        assert!(buf[..wipe].iter().all(|&x| x == 0));
+        assert!(buf[wipe..buf.len() - wipe].iter().all(|&x| x == 0xaa));
+        assert!(buf[buf.len() - wipe..].iter().all(|&x| x == 0));
+
+        // Something smaller than the wipe size is zeroed entirely
+        let image = filled_image(4096);
+        wipe_ends(image.path(), 4096).unwrap();
+        assert_eq!(contents(&image), vec![0; 4096]);
+    }
+
+    #[test]
+    fn dm_names_and_uuids() {
+        let vg = VgBuilder::new("my-vg")
+            .id("AbCdEf-0123-4567-89ab-cdef-ghij-klmnop")
+            .build();
+        let lv = LV {
+            name: "my-lv".to_string(),
+            id: "QrStUv-0123-4567-89ab-cdef-ghij-klmnop".to_string(),
+            status: Vec::new(),
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: "test".to_string(),
+            creation_time: 0,
+            alloc_policy: None,
+            segments: Vec::new(),
+            device: None,
+        };
+
+        assert_eq!(lv.dm_name(&vg), "my--vg-my--lv");
+        assert_eq!(
+            lv.dm_uuid(&vg),
+            "LVM-AbCdEf0123456789abcdefghijklmnopQrStUv0123456789abcdefghijklmnop"
+        );
+        assert_eq!(lv.dm_uuid(&vg).len(), 4 + 32 + 32);
     }
 }
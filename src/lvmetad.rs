@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A client for lvmetad, LVM's metadata caching daemon.
+//!
+//! Requests and responses are in LVM's text configuration format, each
+//! ended by a line containing only "##". Every response has a
+//! "response" value, "OK" on success.
+
+use std::io;
+use std::io::ErrorKind::Other;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::parser::{buf_to_textmap, textmap_to_buf, Entry, LvmTextMap, TextMapOps};
+use crate::util::is_lvm_uuid;
+use crate::vg;
+use crate::{Error, Result, VG};
+
+const LVMETAD_SOCKET: &str = "/run/lvm/lvmetad.socket";
+
+const END_OF_MESSAGE: &[u8] = b"\n##\n";
+
+// lvmetad answers most requests only if given the token it was last
+// told about, which LVM derives from its device filter. This is the
+// token LVM uses when no filter is configured. If lvmetad has another,
+// it says which in its reply, and the request is sent again with that.
+const TOKEN: &str = "filter:0";
+
+// How long to wait for lvmetad to accept a request or reply to it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Get the names of the VGs lvmetad knows about.
+pub fn vg_list() -> Result<Vec<String>> {
+    let response = request("vg_list", LvmTextMap::new())?;
+
+    // volume_groups { <uuid> { name = "<name>" } ... }
+    Ok(response
+        .textmap_from_textmap("volume_groups")
+        .map(|vgs| {
+            vgs.values()
+                .filter_map(|vg| match vg {
+                    &Entry::TextMap(ref vg) => vg.string_from_textmap("name"),
+                    _ => None,
+                })
+                .map(|x| x.to_string())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Get a VG from lvmetad by its name or uuid, without scanning any
+/// devices. Returns `Error::NotFound` if lvmetad doesn't know of it.
+pub fn vg_lookup(name_or_uuid: &str) -> Result<VG> {
+    let mut args = LvmTextMap::new();
+    let key = if is_lvm_uuid(name_or_uuid) {
+        "uuid"
+    } else {
+        "name"
+    };
+    args.insert(key.to_string(), Entry::String(name_or_uuid.to_string()));
+
+    vg_from_lookup(name_or_uuid, request_unchecked("vg_lookup", args)?)
+}
+
+// The VG in lvmetad's response to a vg_lookup request.
+fn vg_from_lookup(name_or_uuid: &str, response: LvmTextMap) -> Result<VG> {
+    if response.string_from_textmap("response") == Some("unknown") {
+        return Err(Error::NotFound(format!("VG {}", name_or_uuid)));
+    }
+    let response = check_response("vg_lookup", response)?;
+
+    // name = "<name>" metadata { <the VG's metadata> }
+    let err = || Error::Parse("lvmetad vg_lookup response is incomplete".to_string());
+    let name = response.string_from_textmap("name").ok_or_else(err)?;
+    let metadata = response.textmap_from_textmap("metadata").ok_or_else(err)?;
+
+    VG::from_textmap(name, metadata)
+}
+
+/// Tell lvmetad about a VG's new metadata, after it has been written to
+/// disk, so that LVM's tools don't see an out-of-date copy.
+///
+/// If lvmetad isn't running, there is no cache to update, so this
+/// succeeds without doing anything.
+pub fn vg_update(vg: &VG) -> Result<()> {
+    match request("vg_update", update_args(vg)) {
+        Err(Error::Io(ref e))
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            Ok(())
+        }
+        res => res.map(|_| ()),
+    }
+}
+
+// The arguments of a vg_update request: the VG's name and metadata.
+fn update_args(vg: &VG) -> LvmTextMap {
+    let mut args = LvmTextMap::new();
+    args.insert("vgname".to_string(), Entry::String(vg.name().to_string()));
+    args.insert(
+        "metadata".to_string(),
+        Entry::TextMap(Box::new(vg::to_textmap(vg))),
+    );
+    args
+}
+
+// Send a request to lvmetad, with any arguments, and return its
+// successful response.
+fn request(name: &str, args: LvmTextMap) -> Result<LvmTextMap> {
+    check_response(name, request_unchecked(name, args)?)
+}
+
+// Send a request to lvmetad and return its response, whether or not
+// the request succeeded.
+fn request_unchecked(name: &str, args: LvmTextMap) -> Result<LvmTextMap> {
+    let response = send(name, args.clone(), TOKEN)?;
+
+    // Only a token derived from a filter is worth retrying with: others
+    // mean lvmetad is being updated, e.g. by pvscan
+    match expected_token(&response) {
+        Some(token) if token.starts_with("filter:") => send(name, args, token),
+        _ => Ok(response),
+    }
+}
+
+// Send one request to lvmetad, with the given token, and return its
+// response.
+fn send(name: &str, args: LvmTextMap, token: &str) -> Result<LvmTextMap> {
+    let mut stream = UnixStream::connect(LVMETAD_SOCKET)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let response = exchange(&mut stream, &request_buf(name, args, token))?;
+    buf_to_textmap(&response)
+}
+
+// If lvmetad refused a request for having the wrong token, the token
+// it expected.
+fn expected_token(response: &LvmTextMap) -> Option<&str> {
+    match response.string_from_textmap("response") {
+        Some("token_mismatch") => response.string_from_textmap("expected"),
+        _ => None,
+    }
+}
+
+// The text of a request.
+fn request_buf(name: &str, mut args: LvmTextMap, token: &str) -> Vec<u8> {
+    args.insert("request".to_string(), Entry::String(name.to_string()));
+    args.insert("token".to_string(), Entry::String(token.to_string()));
+
+    let mut buf = textmap_to_buf(&args);
+    if buf.last() != Some(&b'\n') {
+        buf.push(b'\n');
+    }
+    buf.extend_from_slice(&END_OF_MESSAGE[1..]);
+    buf
+}
+
+// Write a request to the stream and read back the response, up to its
+// end marker.
+fn exchange<S: Read + Write>(stream: &mut S, request: &[u8]) -> Result<Vec<u8>> {
+    stream.write_all(request)?;
+    stream.flush()?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !response.ends_with(END_OF_MESSAGE) {
+        match stream.read(&mut buf)? {
+            0 => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "lvmetad closed the connection mid-response",
+                )))
+            }
+            n => response.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    response.truncate(response.len() - END_OF_MESSAGE.len() + 1);
+    Ok(response)
+}
+
+// Check that a response reports success.
+fn check_response(name: &str, map: LvmTextMap) -> Result<LvmTextMap> {
+    match map.string_from_textmap("response") {
+        Some("OK") => Ok(map),
+        Some(response) => Err(Error::Io(io::Error::new(
+            Other,
+            format!(
+                "lvmetad {} request failed: {}{}",
+                name,
+                response,
+                map.string_from_textmap("reason")
+                    .map(|x| format!(" ({})", x))
+                    .unwrap_or_default()
+            ),
+        ))),
+        None => Err(Error::Parse(format!(
+            "lvmetad {} response has no status",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::min;
+
+    use super::*;
+
+    // Replies with reply, a few bytes per read, and records what is
+    // written to it.
+    struct MockStream {
+        reply: Vec<u8>,
+        pos: usize,
+        written: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(reply: &[u8]) -> MockStream {
+            MockStream {
+                reply: reply.to_vec(),
+                pos: 0,
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = min(min(buf.len(), 7), self.reply.len() - self.pos);
+            buf[..n].copy_from_slice(&self.reply[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_and_response() {
+        let request = request_buf("vg_list", LvmTextMap::new(), TOKEN);
+        assert!(request.ends_with(END_OF_MESSAGE));
+        let map = buf_to_textmap(&request[..request.len() - 3]).unwrap();
+        assert_eq!(map.string_from_textmap("request"), Some("vg_list"));
+        assert_eq!(map.string_from_textmap("token"), Some(TOKEN));
+
+        let mut stream = MockStream::new(b"response = \"OK\"\nvolume_groups {\n}\n##\n");
+        let response = exchange(&mut stream, &request).unwrap();
+        assert_eq!(stream.written, request);
+        assert_eq!(response, b"response = \"OK\"\nvolume_groups {\n}\n");
+        assert!(check_response("vg_list", buf_to_textmap(&response).unwrap()).is_ok());
+
+        let mut stream = MockStream::new(b"response = \"OK\"\n");
+        assert!(exchange(&mut stream, &request).is_err());
+    }
+
+    #[test]
+    fn failed_responses() {
+        let failed = buf_to_textmap(b"response = \"failed\"\nreason = \"no\"\n").unwrap();
+        match check_response("vg_list", failed) {
+            Err(Error::Io(e)) => assert!(e.to_string().ends_with("failed (no)")),
+            res => panic!("{:?}", res),
+        }
+
+        let no_status = buf_to_textmap(b"volume_groups {\n}\n").unwrap();
+        assert!(check_response("vg_list", no_status).is_err());
+    }
+
+    #[test]
+    fn lookup_responses() {
+        let metadata = b"id = \"Xk0iUZ-7vQm-yQfc-TpnB-GxKw-Fm3n-fz8Ezc\"\nseqno = 3\n\
+                         format = \"lvm2\"\nstatus = [\"READ\", \"WRITE\"]\nflags = []\n\
+                         extent_size = 8192\nmax_lv = 0\nmax_pv = 0\nmetadata_copies = 0\n\
+                         physical_volumes {\n}\n";
+        let mut response = LvmTextMap::new();
+        response.insert("response".to_string(), Entry::String("OK".to_string()));
+        response.insert("name".to_string(), Entry::String("vg0".to_string()));
+        response.insert(
+            "metadata".to_string(),
+            Entry::TextMap(Box::new(buf_to_textmap(metadata).unwrap())),
+        );
+
+        let found = vg_from_lookup("vg0", response.clone()).unwrap();
+        assert_eq!(found.name(), "vg0");
+        assert_eq!(found.id(), "Xk0iUZ-7vQm-yQfc-TpnB-GxKw-Fm3n-fz8Ezc");
+
+        response.remove("metadata");
+        assert!(vg_from_lookup("vg0", response).is_err());
+
+        let unknown = buf_to_textmap(b"response = \"unknown\"\n").unwrap();
+        match vg_from_lookup("vg1", unknown) {
+            Err(Error::NotFound(_)) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn update_carries_metadata() {
+        let vg = crate::VgBuilder::new("vg0").build();
+        let args = update_args(&vg);
+        assert_eq!(args.string_from_textmap("vgname"), Some("vg0"));
+        let metadata = args.textmap_from_textmap("metadata").unwrap();
+        let sent = VG::from_textmap("vg0", metadata).unwrap();
+        assert!(sent.structurally_eq(&vg));
+    }
+
+    #[test]
+    fn token_mismatch() {
+        let mismatch =
+            buf_to_textmap(b"response = \"token_mismatch\"\nexpected = \"filter:123\"\n").unwrap();
+        assert_eq!(expected_token(&mismatch), Some("filter:123"));
+
+        let ok = buf_to_textmap(b"response = \"OK\"\nexpected = \"filter:123\"\n").unwrap();
+        assert_eq!(expected_token(&ok), None);
+
+        // The retry carries lvmetad's token, and the same arguments
+        let mut args = LvmTextMap::new();
+        args.insert("vgname".to_string(), Entry::String("vg0".to_string()));
+        let request = request_buf("vg_update", args, "filter:123");
+        let map = buf_to_textmap(&request[..request.len() - 3]).unwrap();
+        assert_eq!(map.string_from_textmap("token"), Some("filter:123"));
+        assert_eq!(map.string_from_textmap("vgname"), Some("vg0"));
+    }
+}
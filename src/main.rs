@@ -5,11 +5,16 @@
 #![allow(dead_code)]
 
 extern crate melvin;
+extern crate rustc_serialize;
 
+use std::collections::BTreeMap;
+use std::env;
 use std::io::{self, ErrorKind::Other};
 use std::path;
 //use std::path::Path;
 
+use rustc_serialize::json::{Json, ToJson};
+
 use melvin::parser;
 use melvin::{pvheader_scan, PvHeader};
 use melvin::{Error, Result};
@@ -25,6 +30,33 @@ fn print_pvheaders() -> Result<()> {
     Ok(())
 }
 
+// Emit the scanned pvheaders and the first VG's metadata as a single
+// JSON object, suitable for consumption by e.g. jq.
+fn print_json() -> Result<()> {
+    let dirs = vec![path::Path::new("/dev")];
+
+    let mut pvheaders = Vec::new();
+    for path in pvheader_scan(&dirs)? {
+        // Skip devices that have gone away or can't be read since the scan
+        if let Ok(pvheader) = PvHeader::find_in_dev(&path) {
+            pvheaders.push(pvheader.to_json());
+        }
+    }
+
+    let mut obj = BTreeMap::new();
+    obj.insert("pvheaders".to_string(), Json::Array(pvheaders));
+
+    let mut vgs = BTreeMap::new();
+    if let Ok((name, map)) = get_first_vg_meta() {
+        vgs.insert(name, map.to_json());
+    }
+    obj.insert("vgs".to_string(), Json::Object(vgs));
+
+    println!("{}", Json::Object(obj).pretty());
+
+    Ok(())
+}
+
 fn get_first_vg_meta() -> Result<(String, parser::LvmTextMap)> {
     let dirs = vec![path::Path::new("/dev")];
 
@@ -58,6 +90,10 @@ fn get_conf() -> Result<parser::LvmTextMap> {
 }
 
 fn main() -> Result<()> {
+    if env::args().skip(1).any(|arg| arg == "--json") {
+        return print_json();
+    }
+
     // println!("{:?}", PvHeader::initialize(Path::new("/dev/vdc1")));
     print_pvheaders()?;
     let (name, map) = get_first_vg_meta().unwrap();
@@ -70,10 +106,10 @@ fn main() -> Result<()> {
     //    let pvh1 = PvHeader::find_in_dev(Path::new("/dev/vdc1")).expect("pvheader not found");
 
     // let _vg = VG::create("vg-dopey", vec![path1, path2]).expect("vgcreate failed yo");
-    // vg.add_pv(&pvh1).unwrap();
-    // vg.add_pv(&pvh2).unwrap();
+    // vg.pv_add(path1).unwrap();
+    // vg.pv_add(path2).unwrap();
 
-    // match vg.new_linear_lv("grover125", 2021) {
+    // match vg.lv_create_linear("grover125", 2021) {
     //     Ok(_) => {},
     //     Err(x) => {
     //         println!("err {:?}", x);
@@ -33,6 +33,8 @@ use std::io::ErrorKind::Other;
 
 use std::collections::BTreeMap;
 
+use rustc_serialize::json::{Json, ToJson};
+
 use crate::{Error, Result};
 
 #[derive(Debug, PartialEq, Clone)]
@@ -83,11 +85,27 @@ impl<'a> AsRef<str> for Token<'a> {
     }
 }
 
+impl<'a> Token<'a> {
+    // How to refer to the token in an error message.
+    fn describe(&self) -> String {
+        match *self {
+            Token::String(x) => format!("string \"{}\"", String::from_utf8_lossy(x)),
+            Token::Ident(x) => format!("'{}'", String::from_utf8_lossy(x)),
+            Token::Number(x) => format!("number {}", x),
+            Token::Comment(_) => "comment".to_string(),
+            Token::Invalid(c) => format!("'{}'", c as char),
+            _ => format!("'{}'", self.as_ref()),
+        }
+    }
+}
+
 struct Lexer<'a> {
     chars: &'a [u8],
     next_byte: Option<u8>,
     cursor: usize,
     next_is_ident: bool,
+    // Where the last token returned started
+    token_start: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -98,6 +116,7 @@ impl<'a> Lexer<'a> {
             next_byte: None,
             cursor: 0,
             next_is_ident: false,
+            token_start: 0,
         }
     }
 
@@ -124,6 +143,16 @@ impl<'a> Lexer<'a> {
             }
         }
     }
+
+    // The token for a number that started at first and ends at the cursor.
+    fn number_token(&self, first: usize) -> Token<'a> {
+        let s = String::from_utf8_lossy(&self.chars[first..self.cursor]).into_owned();
+        match s.parse() {
+            Ok(x) => Token::Number(x),
+            // e.g. a lone '-', or too many digits
+            Err(_) => Token::Invalid(self.chars[first]),
+        }
+    }
 }
 
 // Identifies the state of the lexer
@@ -147,6 +176,7 @@ impl<'a> Iterator for Lexer<'a> {
         while let Some(c) = self.next_byte() {
             match state {
                 Mode::Main => {
+                    self.token_start = self.cursor - 1;
                     match c {
                         b'{' => {
                             self.next_is_ident = true;
@@ -183,8 +213,9 @@ impl<'a> Iterator for Lexer<'a> {
                         b',' => {
                             return Some(Token::Comma);
                         }
-                        b' ' | b'\n' | b'\t' | b'\0' => {
-                            // ignore whitespace
+                        b' ' | b'\n' | b'\t' | b'\r' | b'\x0b' | b'\x0c' | b'\0' => {
+                            // ignore whitespace, including from hand-edited
+                            // files with DOS line endings
                         }
                         _ => {
                             return Some(Token::Invalid(c));
@@ -215,9 +246,7 @@ impl<'a> Iterator for Lexer<'a> {
                     }
                     _ => {
                         self.put_back(c);
-                        let s =
-                            String::from_utf8_lossy(&self.chars[first..self.cursor]).into_owned();
-                        return Some(Token::Number(s.parse().unwrap()));
+                        return Some(self.number_token(first));
                     }
                 },
                 Mode::Comment(first) => match c {
@@ -232,7 +261,17 @@ impl<'a> Iterator for Lexer<'a> {
             }
         }
 
-        None
+        // Input may end without whitespace after the last token
+        match state {
+            Mode::Main => None,
+            Mode::String(first) => Some(Token::Invalid(self.chars[first])),
+            Mode::Ident(first) => {
+                self.next_is_ident = false;
+                Some(Token::Ident(&self.chars[first..self.cursor]))
+            }
+            Mode::Number(first) => Some(self.number_token(first)),
+            Mode::Comment(first) => Some(Token::Comment(&self.chars[first..self.cursor])),
+        }
     }
 }
 
@@ -256,6 +295,17 @@ pub enum Entry {
     TextMap(Box<LvmTextMap>),
 }
 
+impl ToJson for Entry {
+    fn to_json(&self) -> Json {
+        match self {
+            &Entry::Number(x) => Json::I64(x),
+            &Entry::String(ref x) => Json::String(x.clone()),
+            &Entry::List(ref x) => Json::Array(x.iter().map(|e| e.to_json()).collect()),
+            &Entry::TextMap(ref x) => x.to_json(),
+        }
+    }
+}
+
 /// Operations that can be used to extract values from an `LvmTextMap`.
 ///
 /// One usually knows the type of a given attribute in an `LvmTextMap`,
@@ -300,11 +350,18 @@ impl TextMapOps for LvmTextMap {
     }
 }
 
+// A parse error: the byte offset where it was found, and a message.
+type ParseResult<T> = std::result::Result<T, (usize, String)>;
+
+// In the functions below, offsets[i] is the byte offset where tokens[i]
+// starts in the buffer being parsed.
+
 fn find_matching_token<'a, 'b>(
     tokens: &'b [Token<'a>],
+    offsets: &[usize],
     begin: &Token<'a>,
     end: &Token<'a>,
-) -> Result<&'b [Token<'a>]> {
+) -> ParseResult<&'b [Token<'a>]> {
     let mut brace_count = 0;
 
     for (i, x) in tokens.iter().enumerate() {
@@ -321,41 +378,42 @@ fn find_matching_token<'a, 'b>(
             _ => {}
         }
     }
-    Err(Error::Io(io::Error::new(Other, "token mismatch")))
+    Err((offsets[0], format!("Unmatched {}", begin.describe())))
 }
 
 // lists can only contain strings and numbers, yay
-fn get_list<'a>(tokens: &[Token<'a>]) -> Result<Vec<Entry>> {
+fn get_list<'a>(tokens: &[Token<'a>], offsets: &[usize]) -> ParseResult<Vec<Entry>> {
     let mut v = Vec::new();
 
     assert_eq!(*tokens.first().unwrap(), Token::BracketOpen);
     assert_eq!(*tokens.last().unwrap(), Token::BracketClose);
 
     // Omit enclosing brackets
-    for tok in &tokens[1..tokens.len() - 1] {
+    for (tok, &offset) in tokens[1..tokens.len() - 1].iter().zip(&offsets[1..]) {
         match *tok {
             Token::Number(x) => v.push(Entry::Number(x)),
             Token::String(x) => v.push(Entry::String(String::from_utf8_lossy(x).into_owned())),
             Token::Comma => {}
-            _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?}", *tok),
-                )))
-            }
+            _ => return Err((offset, format!("Unexpected {} in list", tok.describe()))),
         }
     }
 
     Ok(v)
 }
 
-// TODO: More appropriate error type than Result
-fn get_textmap<'a>(tokens: &[Token<'a>]) -> Result<LvmTextMap> {
+fn get_textmap<'a>(tokens: &[Token<'a>], offsets: &[usize]) -> ParseResult<LvmTextMap> {
     let mut ret: LvmTextMap = BTreeMap::new();
 
     assert_eq!(*tokens.first().unwrap(), Token::CurlyOpen);
     assert_eq!(*tokens.last().unwrap(), Token::CurlyClose);
 
+    let unexpected = |cur: usize, what: &str| {
+        (
+            offsets[cur],
+            format!("Unexpected {} {}", tokens[cur].describe(), what),
+        )
+    };
+
     let mut cur = 1;
 
     while tokens[cur] != Token::CurlyClose {
@@ -365,12 +423,7 @@ fn get_textmap<'a>(tokens: &[Token<'a>]) -> Result<LvmTextMap> {
                 cur += 1;
                 continue;
             }
-            _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?} when seeking ident", tokens[cur]),
-                )))
-            }
+            _ => return Err(unexpected(cur, "when seeking ident")),
         };
 
         cur += 1;
@@ -392,52 +445,166 @@ fn get_textmap<'a>(tokens: &[Token<'a>]) -> Result<LvmTextMap> {
                     Token::BracketOpen => {
                         let slc = find_matching_token(
                             &tokens[cur..],
+                            &offsets[cur..],
                             &Token::BracketOpen,
                             &Token::BracketClose,
                         )?;
-                        ret.insert(ident, Entry::List(Box::new(get_list(&slc)?)));
+                        ret.insert(
+                            ident,
+                            Entry::List(Box::new(get_list(&slc, &offsets[cur..])?)),
+                        );
                         cur += slc.len();
                     }
-                    _ => {
-                        return Err(Error::Io(io::Error::new(
-                            Other,
-                            format!("Unexpected {:?} as rvalue", tokens[cur]),
-                        )))
-                    }
+                    _ => return Err(unexpected(cur, "as rvalue")),
                 }
             }
             Token::CurlyOpen => {
-                let slc =
-                    find_matching_token(&tokens[cur..], &Token::CurlyOpen, &Token::CurlyClose)?;
-                ret.insert(ident, Entry::TextMap(Box::new(get_textmap(&slc)?)));
+                // e.g. two LVs with the same name. Don't silently drop one.
+                if ret.contains_key(&ident) {
+                    return Err((offsets[cur - 1], format!("Duplicate section {}", ident)));
+                }
+
+                let slc = find_matching_token(
+                    &tokens[cur..],
+                    &offsets[cur..],
+                    &Token::CurlyOpen,
+                    &Token::CurlyClose,
+                )?;
+                ret.insert(
+                    ident,
+                    Entry::TextMap(Box::new(get_textmap(&slc, &offsets[cur..])?)),
+                );
                 cur += slc.len();
             }
-            _ => {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    format!("Unexpected {:?} after an ident", tokens[cur]),
-                )))
-            }
+            _ => return Err(unexpected(cur, "after an ident")),
         };
     }
 
     Ok(ret)
 }
 
+// Check that braces and brackets are balanced and properly nested, so
+// a mistake is reported where it was made, not where the parser that
+// relies on them later stops making sense of things.
+fn check_nesting(tokens: &[Token], offsets: &[usize]) -> ParseResult<()> {
+    let mut open = Vec::new();
+
+    for (i, tok) in tokens.iter().enumerate() {
+        match *tok {
+            Token::CurlyOpen | Token::BracketOpen => open.push(i),
+            Token::CurlyClose | Token::BracketClose => {
+                let matches = match open.pop().map(|x| &tokens[x]) {
+                    Some(&Token::CurlyOpen) => *tok == Token::CurlyClose,
+                    Some(&Token::BracketOpen) => *tok == Token::BracketClose,
+                    _ => false,
+                };
+                if !matches {
+                    return Err((offsets[i], format!("Unexpected {}", tok.describe())));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some(i) => Err((offsets[i], format!("Unclosed {}", tokens[i].describe()))),
+        None => Ok(()),
+    }
+}
+
+// The 1-based line and column of a byte offset within buf.
+fn line_col(buf: &[u8], offset: usize) -> (usize, usize) {
+    let before = &buf[..offset];
+    let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map(|x| x + 1)
+        .unwrap_or(0);
+    (line, offset - line_start + 1)
+}
+
 /// Generate an `LvmTextMap` from a textual LVM configuration string.
 ///
 /// LVM uses the same configuration file format for it's on-disk metadata,
 /// as well as for the lvm.conf configuration file.
+///
+/// If the text is malformed, the error gives the line, column, and byte
+/// offset where the problem was found.
 pub fn buf_to_textmap(buf: &[u8]) -> Result<LvmTextMap> {
     let mut tokens: Vec<Token> = Vec::new();
+    let mut offsets = Vec::new();
 
     // LVM vsn1 is implicitly a map at the top level, so add
     // the appropriate tokens
     tokens.push(Token::CurlyOpen);
-    tokens.extend(&mut Lexer::new(&buf));
+    offsets.push(0);
+
+    let mut lexer = Lexer::new(&buf);
+    while let Some(tok) = lexer.next() {
+        tokens.push(tok);
+        offsets.push(lexer.token_start);
+    }
+
     tokens.push(Token::CurlyClose);
+    offsets.push(buf.len());
+
+    let last = tokens.len() - 1;
+    check_nesting(&tokens[1..last], &offsets[1..last])
+        .and_then(|_| get_textmap(&tokens, &offsets))
+        .map_err(|(offset, msg)| {
+            let (line, col) = line_col(buf, offset);
+            Error::Parse(format!(
+                "{} at line {}, col {} (byte {})",
+                msg, line, col, offset
+            ))
+        })
+}
+
+/// Get just the VG name and seqno from on-disk metadata, without
+/// parsing the rest of it. Lexing stops once the seqno is found, which
+/// LVM writes before the VG's PVs and LVs.
+pub fn parse_header_only(buf: &[u8]) -> Result<(String, u64)> {
+    let err = |msg: &str| Error::Parse(msg.to_string());
+
+    let mut lexer = Lexer::new(buf).filter(|tok| match *tok {
+        Token::Comment(_) => false,
+        _ => true,
+    });
+    let mut depth = 0;
+    let mut prev_ident = None;
+    let mut vg_name = None;
+
+    while let Some(tok) = lexer.next() {
+        match tok {
+            Token::Ident(x) => prev_ident = Some(x),
+            Token::CurlyOpen => {
+                depth += 1;
+                if depth == 1 {
+                    let name = prev_ident.ok_or_else(|| err("Section without a name"))?;
+                    vg_name = Some(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            Token::CurlyClose => {
+                depth -= 1;
+                if depth == 0 {
+                    return Err(err("No seqno found in VG metadata"));
+                }
+            }
+            Token::Equals if depth == 1 && prev_ident == Some(&b"seqno"[..]) => {
+                match lexer.next() {
+                    Some(Token::Number(x)) => {
+                        let name = vg_name.ok_or_else(|| err("No VG section found"))?;
+                        return Ok((name, x as u64));
+                    }
+                    _ => return Err(err("seqno is not a number")),
+                }
+            }
+            _ => {}
+        }
+    }
 
-    get_textmap(&tokens)
+    Err(err("No VG section found"))
 }
 
 /// Status may be either a string or a list of strings. Convert either
@@ -460,11 +627,146 @@ pub fn status_from_textmap(map: &LvmTextMap) -> Result<Vec<String>> {
     }
 }
 
+/// The strings in the list called name, or none if there is no such
+/// list, e.g. for "tags", which is only present if there are any.
+pub fn strings_from_textmap(map: &LvmTextMap, name: &str) -> Vec<String> {
+    map.list_from_textmap(name)
+        .map(|list| {
+            list.iter()
+                .filter_map(|item| match item {
+                    &Entry::String(ref x) => Some(x.clone()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A difference between two `LvmTextMap`s.
+///
+/// Each variant holds the path to the differing entry: the keys leading
+/// to it from the top level, joined by `/`, e.g.
+/// `"logical_volumes/lvol0/segment_count"`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MapDiff {
+    /// The entry is only in the new map.
+    Added(String),
+    /// The entry is only in the old map.
+    Removed(String),
+    /// The entry is in both maps, but with different values.
+    Changed(String),
+}
+
+/// Compare two `LvmTextMap`s, returning the differences between them.
+///
+/// Nested maps present in both are compared entry by entry, rather than
+/// being reported as changed as a whole.
+pub fn diff_textmaps(old: &LvmTextMap, new: &LvmTextMap) -> Vec<MapDiff> {
+    let mut diffs = Vec::new();
+    diff_textmaps_at("", old, new, &mut diffs);
+    diffs
+}
+
+fn diff_textmaps_at(prefix: &str, old: &LvmTextMap, new: &LvmTextMap, diffs: &mut Vec<MapDiff>) {
+    for (key, old_val) in old {
+        let path = format!("{}{}", prefix, key);
+        match (old_val, new.get(key)) {
+            (_, None) => diffs.push(MapDiff::Removed(path)),
+            (&Entry::TextMap(ref x), Some(&Entry::TextMap(ref y))) => {
+                diff_textmaps_at(&format!("{}/", path), x, y, diffs)
+            }
+            (x, Some(y)) if x != y => diffs.push(MapDiff::Changed(path)),
+            _ => {}
+        }
+    }
+
+    for key in new.keys() {
+        if !old.contains_key(key) {
+            diffs.push(MapDiff::Added(format!("{}{}", prefix, key)));
+        }
+    }
+}
+
+/// Controls the layout of the text generated by `textmap_to_buf_with_format`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TextMapFormat {
+    /// Every list is written on a single line, as LVM does.
+    SingleLine,
+    /// Lists whose single-line form would be longer than the given
+    /// number of bytes are wrapped across multiple lines.
+    Wrapped(usize),
+}
+
+impl Default for TextMapFormat {
+    fn default() -> TextMapFormat {
+        TextMapFormat::SingleLine
+    }
+}
+
+// Keys in the order LVM writes them. Other keys follow in name order,
+// with a trailing number compared numerically, so "pv10" comes after
+// "pv9".
+const KEY_ORDER: &[&str] = &[
+    "contents",
+    "version",
+    "description",
+    "id",
+    "seqno",
+    "format",
+    "device",
+    "status",
+    "flags",
+    "tags",
+    "extent_size",
+    "max_lv",
+    "max_pv",
+    "metadata_copies",
+    "dev_size",
+    "pe_start",
+    "pe_count",
+    "creation_time",
+    "creation_host",
+    "segment_count",
+    "start_extent",
+    "extent_count",
+    "type",
+    "stripe_count",
+    "stripe_size",
+    "stripes",
+    "physical_volumes",
+    "logical_volumes",
+];
+
+// The sort key for an entry, per KEY_ORDER.
+fn key_rank(key: &str) -> (usize, &str, u64) {
+    if let Some(idx) = KEY_ORDER.iter().position(|k| *k == key) {
+        return (idx, "", 0);
+    }
+
+    let stem = key.trim_end_matches(|c: char| c.is_ascii_digit());
+    let num = key[stem.len()..].parse().unwrap_or(0);
+
+    (KEY_ORDER.len(), stem, num)
+}
+
 /// Generate a textual LVM configuration string from an LvmTextMap.
+///
+/// Keys are written in a fixed order, matching LVM's for the keys it
+/// uses, so the same map always gives the same output.
 pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
+    textmap_to_buf_with_format(tm, TextMapFormat::default())
+}
+
+/// Generate a textual LVM configuration string from an LvmTextMap,
+/// laid out according to `format`. Output in any format can be read
+/// back by `buf_to_textmap`.
+pub fn textmap_to_buf_with_format(tm: &LvmTextMap, format: TextMapFormat) -> Vec<u8> {
     let mut vec = Vec::new();
 
-    for (k, v) in tm {
+    let mut entries: Vec<_> = tm.iter().collect();
+    entries.sort_by(|a, b| key_rank(a.0).cmp(&key_rank(b.0)).then(a.0.cmp(b.0)));
+
+    for (k, v) in entries {
         match v {
             &Entry::String(ref x) => {
                 vec.extend(k.as_bytes());
@@ -488,13 +790,19 @@ pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
                         _ => panic!("should not be in lists"),
                     })
                     .collect();
-                vec.extend(z.join(", ").as_bytes());
+                let single_line = z.join(", ");
+                match format {
+                    TextMapFormat::Wrapped(width) if k.len() + single_line.len() + 5 > width => {
+                        vec.extend(wrap_list_items(&z, width).as_bytes());
+                    }
+                    _ => vec.extend(single_line.as_bytes()),
+                }
                 vec.extend(b"]\n");
             }
             &Entry::TextMap(ref x) => {
                 vec.extend(k.as_bytes());
                 vec.extend(b" {\n");
-                vec.extend(textmap_to_buf(x));
+                vec.extend(textmap_to_buf_with_format(x, format));
                 vec.extend(b"}\n");
             }
         };
@@ -502,3 +810,146 @@ pub fn textmap_to_buf(tm: &LvmTextMap) -> Vec<u8> {
 
     vec
 }
+
+// Lay out list items one or more per line, each line indented by a tab
+// and no longer than width (unless a single item is longer).
+fn wrap_list_items(items: &[String], width: usize) -> String {
+    let mut out = String::from("\n");
+    let mut line = String::new();
+
+    for (i, item) in items.iter().enumerate() {
+        let sep = if i + 1 == items.len() { "" } else { "," };
+
+        if !line.is_empty() && line.len() + 1 + item.len() + sep.len() > width {
+            out.push('\t');
+            out.push_str(&line);
+            out.push('\n');
+            line.clear();
+        }
+
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(item);
+        line.push_str(sep);
+    }
+
+    if !line.is_empty() {
+        out.push('\t');
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_lists_round_trip() {
+        let items = (0..50).map(|x| Entry::String(format!("pv{}", x))).collect();
+        let mut map = LvmTextMap::new();
+        map.insert("stripes".to_string(), Entry::List(Box::new(items)));
+
+        let single = textmap_to_buf(&map);
+        assert_eq!(single.iter().filter(|&&c| c == b'\n').count(), 1);
+        assert_eq!(buf_to_textmap(&single).unwrap(), map);
+
+        let wrapped = textmap_to_buf_with_format(&map, TextMapFormat::Wrapped(40));
+        let text = String::from_utf8(wrapped.clone()).unwrap();
+        assert!(text.lines().count() > 2);
+        assert!(text.lines().all(|line| line.trim_start().len() <= 40));
+        assert_eq!(buf_to_textmap(&wrapped).unwrap(), map);
+    }
+
+    #[test]
+    fn carriage_returns_and_end_of_input() {
+        let unix = buf_to_textmap(b"a = 1\nb = \"x\"\nc = [1, 2]\n").unwrap();
+        let dos = buf_to_textmap(b"a = 1\r\nb = \"x\"\r\nc = [1, 2]\r\n").unwrap();
+        assert_eq!(dos, unix);
+
+        // Nothing after the last token
+        assert_eq!(
+            buf_to_textmap(b"a = 12").unwrap().i64_from_textmap("a"),
+            Some(12)
+        );
+        assert!(buf_to_textmap(b"a = 1\n# comment").is_ok());
+        assert!(buf_to_textmap(b"a = \"unterminated").is_err());
+        assert!(buf_to_textmap(b"a = 1\nb").is_err());
+    }
+
+    #[test]
+    fn duplicate_sections() {
+        let buf = b"logical_volumes {\nlv0 {\na = 1\n}\nlv0 {\na = 2\n}\n}\n";
+        match buf_to_textmap(buf) {
+            Err(Error::Parse(msg)) => assert!(msg.starts_with("Duplicate section lv0")),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn keys_in_lvm_order() {
+        let mut map = LvmTextMap::new();
+        for key in &["pv10", "zzz", "pv9", "logical_volumes", "seqno", "id"] {
+            map.insert(key.to_string(), Entry::Number(1));
+        }
+
+        let buf = textmap_to_buf(&map);
+        let keys: Vec<_> = String::from_utf8(buf.clone())
+            .unwrap()
+            .lines()
+            .map(|line| line.split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            keys,
+            ["id", "seqno", "logical_volumes", "pv9", "pv10", "zzz"]
+        );
+        assert_eq!(buf_to_textmap(&buf).unwrap(), map);
+    }
+
+    #[test]
+    fn header_only() {
+        let buf = b"# Generated by LVM2\nvg0 {\nid = \"x\"\nlv0 {\nseqno = 1\n}\nseqno = 42\n}\n";
+        assert_eq!(parse_header_only(buf).unwrap(), ("vg0".to_string(), 42));
+
+        // The same as a full parse of what we write
+        let map = buf_to_textmap(buf).unwrap();
+        let vg = map.textmap_from_textmap("vg0").unwrap();
+        assert_eq!(
+            parse_header_only(&textmap_to_buf(&map)).unwrap(),
+            (
+                "vg0".to_string(),
+                vg.i64_from_textmap("seqno").unwrap() as u64
+            )
+        );
+
+        assert!(parse_header_only(b"vg0 {\nid = \"x\"\n}\n").is_err());
+        assert!(parse_header_only(b"vg0 {\nseqno = \"1\"\n}\n").is_err());
+        assert!(parse_header_only(b"seqno = 1\n").is_err());
+    }
+
+    fn parse_error(buf: &[u8]) -> String {
+        match buf_to_textmap(buf) {
+            Err(Error::Parse(msg)) => msg,
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(
+            parse_error(b"a = 1\nb = ]\n"),
+            "Unexpected ']' at line 2, col 5 (byte 10)"
+        );
+        assert_eq!(
+            parse_error(b"a = 1\nvg0 {\nb = 2\n"),
+            "Unclosed '{' at line 2, col 5 (byte 10)"
+        );
+        assert_eq!(
+            parse_error(b"a {\n  b = c\n}\n"),
+            "Unexpected 'c' as rvalue at line 2, col 7 (byte 10)"
+        );
+    }
+}
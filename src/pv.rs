@@ -12,7 +12,7 @@ use std::path::PathBuf;
 
 use devicemapper::Device;
 
-use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
+use crate::parser::{status_from_textmap, strings_from_textmap, Entry, LvmTextMap, TextMapOps};
 use crate::{Error, Result};
 
 pub fn dev_from_textmap(map: &LvmTextMap) -> Result<Device> {
@@ -37,7 +37,7 @@ pub fn dev_from_textmap(map: &LvmTextMap) -> Result<Device> {
 }
 
 /// A Physical Volume that is part of a Volume Group.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PV {
     /// Its UUID
     pub id: String,
@@ -47,6 +47,8 @@ pub struct PV {
     pub status: Vec<String>,
     /// Flags
     pub flags: Vec<String>,
+    /// Tags
+    pub tags: Vec<String>,
     /// The device's size, in sectors
     pub dev_size: u64,
     /// The offset in sectors of where the first extent starts
@@ -100,11 +102,14 @@ pub fn from_textmap(map: &LvmTextMap) -> Result<PV> {
         })
         .collect();
 
+    let tags = strings_from_textmap(map, "tags");
+
     Ok(PV {
         id: id.to_string(),
         device: device,
         status: status,
         flags: flags,
+        tags: tags,
         dev_size: dev_size as u64,
         pe_start: pe_start as u64,
         pe_count: pe_count as u64,
@@ -132,6 +137,15 @@ pub fn to_textmap(pv: &PV) -> LvmTextMap {
         )),
     );
 
+    if !pv.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(Box::new(
+                pv.tags.iter().map(|x| Entry::String(x.clone())).collect(),
+            )),
+        );
+    }
+
     map.insert("dev_size".to_string(), Entry::Number(pv.dev_size as i64));
     map.insert("pe_start".to_string(), Entry::Number(pv.pe_start as i64));
     map.insert("pe_count".to_string(), Entry::Number(pv.pe_count as i64));
@@ -19,17 +19,22 @@
 //   increments seqno.
 //
 
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{read_dir, File, OpenOptions};
 use std::io::ErrorKind::Other;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::thread;
 
 use byteorder::{ByteOrder, LittleEndian};
 use nix::sys::{ioctl, stat};
+use rustc_serialize::json::{Json, ToJson};
 
-use crate::parser::{buf_to_textmap, textmap_to_buf, LvmTextMap};
+use crate::devfile::DevFile;
+use crate::filter::DeviceFilter;
+use crate::parser::{buf_to_textmap, textmap_to_buf, Entry, LvmTextMap, TextMapOps};
 use crate::util::{align_to, crc32_calc, hyphenate_uuid, make_uuid};
 use crate::{Error, Result};
 
@@ -42,7 +47,13 @@ const LABEL_SECTOR: usize = 1;
 pub const SECTOR_SIZE: usize = 512;
 const MDA_HEADER_SIZE: usize = 512;
 const DEFAULT_MDA_SIZE: u64 = 1024 * 1024;
+const DEFAULT_DATA_ALIGNMENT: u64 = 1024 * 1024;
+const LABEL_TYPE_LEN: usize = 8;
+const DEFAULT_LABEL_TYPE: &'static str = "LVM2 001";
 const EXTENSION_VERSION: u32 = 1;
+const S_IFMT: u32 = 0o170000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
 
 #[derive(Debug)]
 struct LabelHeader {
@@ -54,13 +65,15 @@ struct LabelHeader {
 }
 
 impl LabelHeader {
-    fn from_buf(buf: &[u8]) -> Result<LabelHeader> {
+    /// Find and parse the label header in buf. If check_crc is false, a
+    /// label with a bad CRC is accepted, to recover from a damaged label.
+    fn from_buf(buf: &[u8], check_crc: bool) -> Result<LabelHeader> {
         for x in 0..LABEL_SCAN_SECTORS {
             let sec_buf = &buf[x * SECTOR_SIZE..x * SECTOR_SIZE + SECTOR_SIZE];
             if &sec_buf[..8] == b"LABELONE" {
                 let crc = LittleEndian::read_u32(&sec_buf[16..20]);
-                if crc != crc32_calc(&sec_buf[20..SECTOR_SIZE]) {
-                    return Err(Error::Io(io::Error::new(Other, "Label CRC error")));
+                if check_crc && crc != crc32_calc(&sec_buf[20..SECTOR_SIZE]) {
+                    return Err(Error::ChecksumMismatch);
                 }
 
                 let sector = LittleEndian::read_u64(&sec_buf[8..16]);
@@ -71,27 +84,35 @@ impl LabelHeader {
                     )));
                 }
 
+                // switch from "offset from label" to "offset from start", more convenient.
+                let offset = LittleEndian::read_u32(&sec_buf[20..24])
+                    .checked_add((x * SECTOR_SIZE) as u32)
+                    .ok_or_else(|| {
+                        Error::Io(io::Error::new(Other, "Label header offset is out of range"))
+                    })?;
+
                 return Ok(LabelHeader {
                     id: String::from_utf8_lossy(&sec_buf[..8]).into_owned(),
                     sector: sector,
                     crc: crc,
-                    // switch from "offset from label" to "offset from start", more convenient.
-                    offset: LittleEndian::read_u32(&sec_buf[20..24])
-                        + (x * SECTOR_SIZE as usize) as u32,
-                    label: String::from_utf8_lossy(&sec_buf[24..32]).into_owned(),
+                    offset: offset,
+                    label: String::from_utf8_lossy(&sec_buf[24..32])
+                        .trim_end_matches('\0')
+                        .to_string(),
                 });
             }
         }
 
-        Err(Error::Io(io::Error::new(Other, "Label not found")))
+        Err(Error::LabelNotFound)
     }
 
-    /// Initialize a device with a label header.
-    fn initialize(sec_buf: &mut [u8; SECTOR_SIZE]) -> () {
+    /// Initialize a device with a label header. label must be at most
+    /// LABEL_TYPE_LEN bytes, and is padded with zeroes.
+    fn initialize(sec_buf: &mut [u8; SECTOR_SIZE], label: &str) -> () {
         sec_buf[..8].copy_from_slice(b"LABELONE");
         LittleEndian::write_u64(&mut sec_buf[8..16], LABEL_SECTOR as u64);
         LittleEndian::write_u32(&mut sec_buf[20..24], LABEL_SIZE as u32);
-        sec_buf[24..32].copy_from_slice(b"LVM2 001");
+        sec_buf[24..24 + label.len()].copy_from_slice(label.as_bytes());
         let crc_val = crc32_calc(&sec_buf[20..]);
         LittleEndian::write_u32(&mut sec_buf[16..20], crc_val);
     }
@@ -106,6 +127,15 @@ pub struct PvArea {
     pub size: u64,
 }
 
+impl ToJson for PvArea {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("offset".to_string(), self.offset.to_json());
+        obj.insert("size".to_string(), self.size.to_json());
+        Json::Object(obj)
+    }
+}
+
 #[derive(Debug)]
 struct PvAreaIter<'a> {
     area: &'a [u8],
@@ -119,6 +149,10 @@ impl<'a> Iterator for PvAreaIter<'a> {
     type Item = PvArea;
 
     fn next(&mut self) -> Option<PvArea> {
+        if self.area.len() < 16 {
+            return None;
+        }
+
         let off = LittleEndian::read_u64(&self.area[..8]);
         let size = LittleEndian::read_u64(&self.area[8..16]);
 
@@ -134,6 +168,119 @@ impl<'a> Iterator for PvAreaIter<'a> {
     }
 }
 
+/// Options controlling how `PvHeader::write_metadata_with_options`
+/// writes metadata.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WriteOptions {
+    /// The text area of an MDA is a circular buffer, and metadata
+    /// written near its end normally wraps around to its start. If
+    /// false, fail instead of wrapping, so metadata is always written at
+    /// a predictable offset.
+    pub allow_wrap: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions { allow_wrap: true }
+    }
+}
+
+/// Options controlling how `PvHeader::initialize_with_options`
+/// initializes a PV.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PvCreateOptions {
+    /// The label type written after the "LABELONE" magic. At most 8
+    /// bytes. LVM2 uses "LVM2 001".
+    pub label: String,
+    /// Whether to overwrite a device that already has a PV label.
+    pub force: bool,
+    /// The size in bytes of a bootloader area to set aside between the
+    /// first metadata area and the data area, or 0 for none.
+    pub bootloader_area_size: u64,
+}
+
+impl Default for PvCreateOptions {
+    fn default() -> PvCreateOptions {
+        PvCreateOptions {
+            label: DEFAULT_LABEL_TYPE.to_string(),
+            force: false,
+            bootloader_area_size: 0,
+        }
+    }
+}
+
+/// Where the label, metadata areas, and data area go on a new PV.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PvLayout {
+    /// The offset of the label in bytes.
+    pub label_offset: u64,
+    /// The metadata area at the start of the device.
+    pub mda0: PvArea,
+    /// The metadata area at the end of the device.
+    pub mda1: PvArea,
+    /// The data area, between the two metadata areas.
+    pub data: PvArea,
+}
+
+/// Compute the layout of a new PV on a device of the given size.
+///
+/// The label goes in the second 512-byte sector, whatever the device's
+/// sector size. The first MDA starts 4KiB into the device, or at the
+/// second sector if sectors are larger, and runs up to the data area, which is aligned to
+/// `data_alignment` bytes and starts no sooner than `mda_size` bytes
+/// into the device. The second MDA is `mda_size` bytes at the end of the
+/// device. All sizes are in bytes.
+pub fn compute_layout(
+    device_size: u64,
+    mda_size: u64,
+    data_alignment: u64,
+    sector_size: u64,
+) -> Result<PvLayout> {
+    if sector_size == 0 || data_alignment == 0 || data_alignment % sector_size != 0 {
+        return Err(Error::Io(io::Error::new(
+            Other,
+            "Data alignment must be a non-zero multiple of the sector size",
+        )));
+    }
+
+    if mda_size % sector_size != 0 {
+        return Err(Error::Io(io::Error::new(
+            Other,
+            "MDA size must be a multiple of the sector size",
+        )));
+    }
+
+    // mda0 starts at 9th 512-byte sector, if that is sector-aligned
+    let mda0_offset = align_to(8 * SECTOR_SIZE as u64, sector_size);
+
+    if mda_size <= mda0_offset + MDA_HEADER_SIZE as u64 {
+        return Err(Error::Io(io::Error::new(Other, "MDA size too small")));
+    }
+
+    let data_offset = (mda_size + data_alignment - 1) / data_alignment * data_alignment;
+    let mda1_offset = device_size.saturating_sub(mda_size) / sector_size * sector_size;
+
+    if mda1_offset <= data_offset {
+        return Err(Error::Io(io::Error::new(Other, "Device too small")));
+    }
+
+    Ok(PvLayout {
+        label_offset: LABEL_SECTOR as u64 * SECTOR_SIZE as u64,
+        mda0: PvArea {
+            offset: mda0_offset,
+            size: data_offset - mda0_offset,
+        },
+        mda1: PvArea {
+            offset: mda1_offset,
+            size: mda_size,
+        },
+        data: PvArea {
+            offset: data_offset,
+            size: mda1_offset - data_offset,
+        },
+    })
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 struct RawLocn {
     offset: u64,
@@ -182,8 +329,11 @@ pub struct PvHeader {
     pub uuid: String,
     /// Size in bytes of the entire PV.
     pub size: u64,
+    /// The device's logical sector size in bytes. Metadata is written
+    /// aligned to it.
+    pub sector_size: u64,
     /// Extension version. If 1, we look for an extension header that may contain a reference
-    /// to a bootloader area.
+    /// to a bootloader area. Later versions are kept, but their extension is not parsed.
     ext_version: u32,
     /// Extension flags, of which there are none.
     ext_flags: u32,
@@ -193,8 +343,13 @@ pub struct PvHeader {
     pub metadata_areas: Vec<PvArea>,
     /// A list of the bootloader areas.
     pub bootloader_areas: Vec<PvArea>,
+    /// The label type from the label header, e.g. "LVM2 001".
+    pub label: String,
     /// The path to the device this pvheader is within.
     pub dev_path: PathBuf,
+    /// Whether label and metadata I/O bypasses the page cache, using
+    /// O_DIRECT, so changes made by other tools are always seen.
+    pub direct_io: bool,
 }
 
 impl PvHeader {
@@ -212,20 +367,29 @@ impl PvHeader {
     //
     // Parse a buf containing the on-disk pvheader and create a struct
     // representing it.
-    fn from_buf(buf: &[u8], path: &Path) -> Result<PvHeader> {
+    fn from_buf(buf: &[u8], label: &str, path: &Path) -> Result<PvHeader> {
+        if buf.len() < ID_LEN + 8 {
+            return Err(Error::Io(io::Error::new(Other, "pvheader truncated")));
+        }
+
         let mut da_buf = &buf[ID_LEN + 8..];
 
         let da_vec: Vec<_> = iter_pv_area(da_buf).collect();
 
         // move slice past any actual entries plus blank
         // terminating entry
-        da_buf = &da_buf[(da_vec.len() + 1) * 16..];
+        da_buf = Self::skip_area_list(da_buf, da_vec.len())?;
 
         let md_vec: Vec<_> = iter_pv_area(da_buf).collect();
 
-        da_buf = &da_buf[(md_vec.len() + 1) * 16..];
+        da_buf = Self::skip_area_list(da_buf, md_vec.len())?;
 
-        let ext_version = LittleEndian::read_u32(&da_buf[..4]);
+        // No room for the extension header means there isn't one
+        let ext_version = if da_buf.len() >= 8 {
+            LittleEndian::read_u32(&da_buf[..4])
+        } else {
+            0
+        };
         let mut ext_flags = 0;
         let mut ba_vec = Vec::new();
 
@@ -234,36 +398,91 @@ impl PvHeader {
 
             da_buf = &da_buf[8..];
 
-            ba_vec = iter_pv_area(da_buf).collect();
+            // A later version may lay out what follows differently, so
+            // only parse bootloader areas for versions we know.
+            if ext_version <= EXTENSION_VERSION {
+                ba_vec = iter_pv_area(da_buf).collect();
+            }
         }
 
         Ok(PvHeader {
             uuid: hyphenate_uuid(&buf[..ID_LEN]),
             size: LittleEndian::read_u64(&buf[ID_LEN..ID_LEN + 8]),
+            sector_size: SECTOR_SIZE as u64,
             ext_version: ext_version,
             ext_flags: ext_flags,
             data_areas: da_vec,
             metadata_areas: md_vec,
             bootloader_areas: ba_vec,
+            label: label.to_string(),
             dev_path: path.to_owned(),
+            direct_io: false,
         })
     }
 
+    // Return the remainder of buf after a list of count pvareas and its
+    // blank terminating entry.
+    fn skip_area_list(buf: &[u8], count: usize) -> Result<&[u8]> {
+        let len = (count + 1) * 16;
+
+        if buf.len() < len {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "pvheader area list is missing its terminator",
+            )));
+        }
+
+        Ok(&buf[len..])
+    }
+
     /// Find the PvHeader struct in a given device.
     pub fn find_in_dev(path: &Path) -> Result<PvHeader> {
-        let mut f = File::open(path)?;
+        Self::find_in_dev_checked(path, true, false)
+    }
+
+    /// Find the PvHeader struct in a given device, as `find_in_dev()`
+    /// does, but reading with O_DIRECT. The PvHeader's later metadata
+    /// reads and writes also use O_DIRECT.
+    pub fn open_direct(path: &Path) -> Result<PvHeader> {
+        Self::find_in_dev_checked(path, true, true)
+    }
+
+    /// Find the PvHeader struct in a given device, as `find_in_dev()`
+    /// does, but accept a label whose CRC does not match. This is for
+    /// recovering data from a damaged PV; what is found may be garbage.
+    pub fn find_in_dev_lenient(path: &Path) -> Result<PvHeader> {
+        Self::find_in_dev_checked(path, false, false)
+    }
+
+    fn find_in_dev_checked(path: &Path, check_crc: bool, direct: bool) -> Result<PvHeader> {
+        let mut f = DevFile::open(path, false, direct)?;
 
         let mut buf = [0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE];
 
         f.read(&mut buf)?;
 
-        let label_header = LabelHeader::from_buf(&buf)?;
-        let pvheader = Self::from_buf(&buf[label_header.offset as usize..], path)?;
+        let label_header = LabelHeader::from_buf(&buf, check_crc)?;
+        let pvheader_buf =
+            buf.get(label_header.offset as usize..)
+                .ok_or(Error::Io(io::Error::new(
+                    Other,
+                    "pvheader offset beyond label scan area",
+                )))?;
+        let mut pvheader = Self::from_buf(pvheader_buf, &label_header.label, path)?;
+        pvheader.sector_size = f.sector_size();
+        pvheader.direct_io = direct;
 
         return Ok(pvheader);
     }
 
+    // The size in bytes of a block device, or of a regular file, as
+    // used in place of a device for testing.
     fn blkdev_size(file: &File) -> Result<u64> {
+        let metadata = file.metadata()?;
+        if metadata.file_type().is_file() {
+            return Ok(metadata.len());
+        }
+
         // BLKGETSIZE64
         let op = ioctl::op_read(0x12, 114, 8);
         let mut val: u64 = 0;
@@ -274,124 +493,486 @@ impl PvHeader {
         }
     }
 
+    /// Query the current size in bytes of the device this PV is on.
+    ///
+    /// This may differ from `size`, which is what was recorded in the
+    /// pvheader when the PV was initialized, e.g. if the device has since
+    /// grown. For a regular file, this is the file's size.
+    pub fn device_size(&self) -> Result<u64> {
+        let f = File::open(&self.dev_path)?;
+        Self::blkdev_size(&f)
+    }
+
     /// Initialize a device as a PV with reasonable defaults: two metadata
     /// areas, no bootsector area, and size based on the device's size.
+    /// A device that already has a PV label is left alone.
     pub fn initialize(path: &Path) -> Result<PvHeader> {
-        let mut f = OpenOptions::new().write(true).open(path)?;
+        Self::initialize_with_options(path, &PvCreateOptions::default())
+    }
+
+    /// Initialize a device as a PV, as `initialize()` does, but with
+    /// the given options.
+    pub fn initialize_with_options(path: &Path, opts: &PvCreateOptions) -> Result<PvHeader> {
+        if opts.label.len() > LABEL_TYPE_LEN {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("Label type may be at most {} bytes", LABEL_TYPE_LEN),
+            )));
+        }
 
-        // mda0 starts at 9th sector
-        let mda0_offset = (8 * SECTOR_SIZE) as u64;
-        // mda0's length is reduced a little by the header length,
-        // maybe to keep the data area aligned to 1MB?
-        let mda0_length = DEFAULT_MDA_SIZE - mda0_offset;
-        let dev_size = Self::blkdev_size(&f)?;
+        if !opts.force && Self::find_in_dev(path).is_ok() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already a PV", path.display()),
+            )));
+        }
 
-        if dev_size < ((DEFAULT_MDA_SIZE * 2) + mda0_offset) {
-            return Err(Error::Io(io::Error::new(Other, "Device too small")));
+        let mut f = DevFile::open(path, true, false)?;
+
+        let dev_size = Self::blkdev_size(f.file())?;
+        let sector_size = f.sector_size();
+        let layout = compute_layout(
+            dev_size,
+            DEFAULT_MDA_SIZE,
+            DEFAULT_DATA_ALIGNMENT,
+            sector_size,
+        )?;
+
+        // The bootloader area takes the start of the data area, which
+        // then starts at the next alignment boundary after it.
+        let mut bootloader_areas = Vec::new();
+        let mut data_offset = layout.data.offset;
+        if opts.bootloader_area_size != 0 {
+            let ba_size = align_to(opts.bootloader_area_size, sector_size);
+            bootloader_areas.push(PvArea {
+                offset: data_offset,
+                size: ba_size,
+            });
+            data_offset = align_to(data_offset + ba_size, DEFAULT_DATA_ALIGNMENT);
         }
 
         let pvh = PvHeader {
             uuid: make_uuid(),
             size: dev_size,
+            sector_size: sector_size,
             ext_version: EXTENSION_VERSION,
             ext_flags: 0,
             data_areas: vec![
                 // da0 length is not used
                 PvArea {
-                    offset: mda0_offset + mda0_length,
+                    offset: data_offset,
                     size: 0,
                 },
             ],
-            metadata_areas: vec![
-                PvArea {
-                    offset: mda0_offset,
-                    size: mda0_length,
-                },
-                PvArea {
-                    offset: dev_size - DEFAULT_MDA_SIZE,
-                    size: DEFAULT_MDA_SIZE,
-                },
-            ],
-            bootloader_areas: Vec::new(),
+            metadata_areas: vec![layout.mda0, layout.mda1],
+            bootloader_areas: bootloader_areas,
+            label: opts.label.clone(),
             dev_path: path.to_owned(),
+            direct_io: false,
         };
 
+        pvh.validate_layout()?;
+
         let mut sec_buf = [0u8; SECTOR_SIZE];
 
         // Translate to on-disk format
-        {
-            let slc = &mut sec_buf[LABEL_SIZE..];
+        pvh.to_buf(&mut sec_buf[LABEL_SIZE..]);
 
-            let uuid = pvh.uuid.replace("-", "");
-            slc[..ID_LEN].copy_from_slice(uuid.as_bytes());
-            let slc = &mut slc[ID_LEN..];
+        // Must do label last since it calcs crc over everything
+        LabelHeader::initialize(&mut sec_buf, &pvh.label);
 
-            LittleEndian::write_u64(slc, dev_size);
-            let slc = &mut slc[8..];
+        f.seek(SeekFrom::Start(layout.label_offset))?;
+        f.write_all(&mut sec_buf)?;
 
-            // da0 defined first, but "in the middle"
-            LittleEndian::write_u64(slc, pvh.data_areas[0].offset);
-            let slc = &mut slc[8..];
-            LittleEndian::write_u64(slc, pvh.data_areas[0].size);
-            let slc = &mut slc[8..];
+        for area in &pvh.metadata_areas {
+            let new_rl = RawLocn {
+                offset: 0,
+                size: 0,
+                checksum: 0,
+                ignored: false,
+            };
+            Self::write_mda_header(area, &mut f, &new_rl)?;
+        }
 
-            // skip 16 bytes to indicate end of da list
-            let slc = &mut slc[16..];
+        Ok(pvh)
+    }
+
+    /// Returns the metadata areas that are in use: those whose headers
+    /// can be read and that are not marked as ignored.
+    pub fn active_metadata_areas(&self) -> Result<Vec<PvArea>> {
+        let mut f = DevFile::open(&self.dev_path, false, self.direct_io)?;
+
+        Ok(self
+            .metadata_areas
+            .iter()
+            .filter(|area| match Self::read_mda_header(area, &mut f) {
+                Ok(Some(rl)) => !rl.ignored,
+                Ok(None) => true,
+                Err(_) => false,
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Read the contents of one of the PV's bootloader areas.
+    pub fn read_bootloader_area(&self, index: usize) -> Result<Vec<u8>> {
+        if self.bootloader_areas.is_empty() {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "PV has no bootloader areas",
+            )));
+        }
 
-            // mda0 at start of PV
-            LittleEndian::write_u64(slc, pvh.metadata_areas[0].offset);
-            let slc = &mut slc[8..];
-            LittleEndian::write_u64(slc, pvh.metadata_areas[0].size);
-            let slc = &mut slc[8..];
+        let area = self
+            .bootloader_areas
+            .get(index)
+            .ok_or_else(|| Error::NotFound(format!("Bootloader area {}", index)))?;
 
-            // mda1 at end of PV
-            LittleEndian::write_u64(slc, pvh.metadata_areas[1].offset);
-            let slc = &mut slc[8..];
-            LittleEndian::write_u64(slc, pvh.metadata_areas[1].size);
-            let slc = &mut slc[8..];
+        let mut f = DevFile::open(&self.dev_path, false, self.direct_io)?;
+        f.seek(SeekFrom::Start(area.offset))?;
+        let mut buf = vec![0u8; area.size as usize];
+        f.read_exact(&mut buf)?;
 
-            // skip 16 bytes to indicate end of mda list
-            let slc = &mut slc[16..];
+        Ok(buf)
+    }
 
-            // Extension header
-            LittleEndian::write_u32(slc, pvh.ext_version);
+    /// Write data to the start of one of the PV's bootloader areas. The
+    /// data must fit in the area.
+    pub fn write_bootloader_area(&self, index: usize, data: &[u8]) -> Result<()> {
+        let area = self
+            .bootloader_areas
+            .get(index)
+            .ok_or_else(|| Error::NotFound(format!("Bootloader area {}", index)))?;
 
-            // everything else is 0 (no bas) so we're finished
+        if data.len() as u64 > area.size {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!(
+                    "{} bytes do not fit in a {} byte bootloader area",
+                    data.len(),
+                    area.size
+                ),
+            )));
         }
 
-        // Must do label last since it calcs crc over everything
-        LabelHeader::initialize(&mut sec_buf);
+        let mut f = DevFile::open(&self.dev_path, true, self.direct_io)?;
+        f.seek(SeekFrom::Start(area.offset))?;
+        f.write_all(data)?;
+        f.sync_all()?;
 
-        f.seek(SeekFrom::Start(LABEL_SECTOR as u64 * SECTOR_SIZE as u64))?;
-        f.write_all(&mut sec_buf)?;
+        Ok(())
+    }
 
-        for area in &pvh.metadata_areas {
-            let new_rl = RawLocn {
+    /// Returns the (start, length) ranges, in the device's sectors,
+    /// holding everything on the PV other than LV data: the label and
+    /// pvheader, the metadata areas, and any bootloader areas. Sorted by
+    /// start.
+    ///
+    /// The label may be in any of the first four 512-byte sectors, so
+    /// they are all included.
+    pub fn reserved_sector_ranges(&self) -> Vec<(u64, u64)> {
+        let sector_size = self.sector_size;
+
+        let label_end = align_to((LABEL_SCAN_SECTORS * SECTOR_SIZE) as u64, sector_size);
+        let mut ranges = vec![(0, label_end / sector_size)];
+        ranges.extend(
+            self.metadata_areas
+                .iter()
+                .chain(self.bootloader_areas.iter())
+                .map(|area| {
+                    let start = area.offset / sector_size;
+                    let end = align_to(area.offset + area.size, sector_size) / sector_size;
+                    (start, end - start)
+                }),
+        );
+        ranges.sort();
+
+        ranges
+    }
+
+    /// Remove the PV label from a device, as pvremove does, along with
+    /// the headers of its metadata areas. A PV whose metadata shows it
+    /// is in a VG is only wiped if force is true.
+    pub fn wipe(path: &Path, force: bool) -> Result<()> {
+        let mdas = match Self::find_in_dev(path) {
+            Ok(pvh) => {
+                if !force && pvh.read_metadata().is_ok() {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        format!("{} is in a VG", path.display()),
+                    )));
+                }
+                pvh.metadata_areas
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let mut f = OpenOptions::new().write(true).open(path)?;
+
+        for mda in &mdas {
+            f.seek(SeekFrom::Start(mda.offset))?;
+            f.write_all(&[0u8; MDA_HEADER_SIZE])?;
+        }
+
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&[0u8; LABEL_SCAN_SECTORS * SECTOR_SIZE])?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Change the size of the first metadata area, moving the start of
+    /// the data area to directly after it, and rewrite the label. The
+    /// metadata in the area is discarded, so the VG should be committed
+    /// afterwards to write it again.
+    ///
+    /// No data is moved: nothing may be using the space the metadata
+    /// area grows into.
+    pub fn resize_first_mda(&mut self, size: u64) -> Result<()> {
+        let mut pvh = self.clone();
+        {
+            let mda0 = pvh
+                .metadata_areas
+                .get_mut(0)
+                .ok_or(Error::Io(io::Error::new(Other, "PV has no metadata area")))?;
+            let da = pvh
+                .data_areas
+                .get_mut(0)
+                .ok_or(Error::Io(io::Error::new(Other, "PV has no data area")))?;
+
+            if da.offset != mda0.offset + mda0.size {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    "First metadata area is not directly before the data area",
+                )));
+            }
+
+            mda0.size = size;
+            da.offset = mda0.offset + size;
+        }
+        pvh.validate_layout()?;
+
+        let mut f = DevFile::open(&self.dev_path, true, self.direct_io)?;
+
+        // Text in the old ring may wrap at its old end, so start afresh.
+        // An rlocn is needed only to keep the area marked as ignored.
+        let ignored = match Self::read_mda_header(&self.metadata_areas[0], &mut f) {
+            Ok(Some(rl)) => rl.ignored,
+            _ => false,
+        };
+        let new_rl = RawLocn {
+            offset: if ignored { MDA_HEADER_SIZE as u64 } else { 0 },
+            size: 0,
+            checksum: 0,
+            ignored: ignored,
+        };
+        Self::write_mda_header(&pvh.metadata_areas[0], &mut f, &new_rl)?;
+
+        pvh.write_label(&mut f)?;
+        f.sync_all()?;
+
+        *self = pvh;
+
+        Ok(())
+    }
+
+    /// Grow the PV to the current size of its device, e.g. after the
+    /// device has been extended, like pvresize. A metadata area at the
+    /// end of the device is moved to the new end, and the data area
+    /// grows to meet it. The label is then rewritten with the new size.
+    ///
+    /// The VG's extent count for the PV is not changed; see
+    /// `VG::pv_resize`.
+    pub fn resize(&mut self) -> Result<()> {
+        let new_size = self.device_size()?;
+        if new_size < self.size {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Device is smaller than the PV, shrinking is not supported",
+            )));
+        }
+        if new_size == self.size {
+            return Ok(());
+        }
+
+        let mut pvh = self.clone();
+        pvh.size = new_size;
+
+        // Metadata areas after the start of the last data area are at
+        // the end of the device.
+        let last_da_offset = self.data_areas.iter().map(|a| a.offset).max().unwrap_or(0);
+        let mut moves = Vec::new();
+        for mda in pvh
+            .metadata_areas
+            .iter_mut()
+            .filter(|a| a.offset > last_da_offset)
+        {
+            let new_offset = (new_size - mda.size) / self.sector_size * self.sector_size;
+            moves.push((mda.offset, new_offset, mda.size));
+            mda.offset = new_offset;
+        }
+
+        // A data area of size 0 already extends to the end of the device
+        let data_end = pvh
+            .metadata_areas
+            .iter()
+            .filter(|a| a.offset > last_da_offset)
+            .map(|a| a.offset)
+            .min()
+            .unwrap_or(new_size);
+        if let Some(da) = pvh.data_areas.iter_mut().max_by_key(|a| a.offset) {
+            if da.size != 0 {
+                da.size = data_end - da.offset;
+            }
+        }
+        pvh.validate_layout()?;
+
+        let mut f = DevFile::open(&self.dev_path, true, self.direct_io)?;
+
+        // Metadata locations are relative to the start of their area,
+        // so the area can be copied as a whole. Read it all first, in
+        // case the new location overlaps the old. Its header records
+        // where the area starts, so is rewritten.
+        for (old_offset, new_offset, size) in moves {
+            let old_area = PvArea {
+                offset: old_offset,
+                size: size,
+            };
+            let new_area = PvArea {
+                offset: new_offset,
+                size: size,
+            };
+            let rl = Self::read_mda_header(&old_area, &mut f)?.unwrap_or(RawLocn {
                 offset: 0,
                 size: 0,
                 checksum: 0,
                 ignored: false,
-            };
-            Self::write_mda_header(area, &mut f, &new_rl)?;
+            });
+
+            let mut buf = vec![0u8; size as usize];
+            f.seek(SeekFrom::Start(old_offset))?;
+            f.read_exact(&mut buf)?;
+            f.seek(SeekFrom::Start(new_offset))?;
+            f.write_all(&buf)?;
+
+            Self::write_mda_header(&new_area, &mut f, &rl)?;
         }
+        f.sync_all()?;
 
-        Ok(pvh)
+        pvh.write_label(&mut f)?;
+        f.sync_all()?;
+
+        *self = pvh;
+
+        Ok(())
+    }
+
+    // Write the label and pvheader, with a new checksum.
+    fn write_label(&self, f: &mut DevFile) -> Result<()> {
+        let mut sec_buf = [0u8; SECTOR_SIZE];
+        self.to_buf(&mut sec_buf[LABEL_SIZE..]);
+        LabelHeader::initialize(&mut sec_buf, &self.label);
+
+        f.seek(SeekFrom::Start(LABEL_SECTOR as u64 * SECTOR_SIZE as u64))?;
+        f.write_all(&sec_buf)?;
+
+        Ok(())
+    }
+
+    /// Check that the PV's areas all lie within the device and do not
+    /// overlap one another.
+    ///
+    /// A data area of size 0 extends to the end of the device, less any
+    /// metadata area there, so a bootloader area anywhere after the start
+    /// of such a data area would be overwritten by LV data.
+    pub fn validate_layout(&self) -> Result<()> {
+        let err = |msg: &str| Err(Error::Io(io::Error::new(Other, msg)));
+
+        let mut areas: Vec<(&str, &PvArea)> = Vec::new();
+        areas.extend(
+            self.data_areas
+                .iter()
+                .filter(|a| a.size != 0)
+                .map(|a| ("data", a)),
+        );
+        areas.extend(self.metadata_areas.iter().map(|a| ("metadata", a)));
+        areas.extend(self.bootloader_areas.iter().map(|a| ("bootloader", a)));
+
+        for &(kind, area) in &areas {
+            if area.offset + area.size > self.size {
+                return err(&format!("{} area extends past the end of the device", kind));
+            }
+        }
+
+        for (i, &(kind, area)) in areas.iter().enumerate() {
+            for &(other_kind, other) in &areas[i + 1..] {
+                if area.offset < other.offset + other.size && other.offset < area.offset + area.size
+                {
+                    return err(&format!("{} area overlaps {} area", kind, other_kind));
+                }
+            }
+        }
+
+        for da in self.data_areas.iter().filter(|a| a.size == 0) {
+            for ba in &self.bootloader_areas {
+                if ba.offset + ba.size > da.offset {
+                    return err("bootloader area overlaps data area");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The inverse of from_buf: write the on-disk pvheader into buf, which
+    // must be zeroed.
+    fn to_buf(&self, buf: &mut [u8]) {
+        let uuid = self.uuid.replace("-", "");
+        buf[..ID_LEN].copy_from_slice(uuid.as_bytes());
+        let slc = &mut buf[ID_LEN..];
+
+        LittleEndian::write_u64(slc, self.size);
+        let slc = &mut slc[8..];
+
+        // da0 defined first, but "in the middle"
+        let slc = Self::write_area_list(slc, &self.data_areas);
+        let slc = Self::write_area_list(slc, &self.metadata_areas);
+
+        // Extension header
+        LittleEndian::write_u32(slc, self.ext_version);
+        LittleEndian::write_u32(&mut slc[4..], self.ext_flags);
+        let slc = &mut slc[8..];
+
+        if self.ext_version != 0 {
+            Self::write_area_list(slc, &self.bootloader_areas);
+        }
+    }
+
+    // Write a list of pvareas and its blank terminating entry, returning
+    // the remainder of buf.
+    fn write_area_list<'a>(buf: &'a mut [u8], areas: &[PvArea]) -> &'a mut [u8] {
+        let mut slc = buf;
+        for area in areas {
+            LittleEndian::write_u64(slc, area.offset);
+            LittleEndian::write_u64(&mut slc[8..], area.size);
+            slc = &mut { slc }[16..];
+        }
+
+        // skip 16 bytes to indicate end of list
+        &mut slc[16..]
     }
 
     // For the moment, the only important thing in the MDA header is rlocn0,
     // so we don't need separate functions that return anything in it except
     // rlocn0.
-    fn read_mda_header(area: &PvArea, file: &mut File) -> Result<Option<RawLocn>> {
-        assert!(area.size as usize > MDA_HEADER_SIZE);
+    fn read_mda_header(area: &PvArea, file: &mut DevFile) -> Result<Option<RawLocn>> {
+        assert!(area.size > MDA_HEADER_SIZE as u64);
         file.seek(SeekFrom::Start(area.offset))?;
         let mut hdr = [0u8; MDA_HEADER_SIZE];
         file.read(&mut hdr)?;
 
         if LittleEndian::read_u32(&hdr[..4]) != crc32_calc(&hdr[4..MDA_HEADER_SIZE]) {
-            return Err(Error::Io(io::Error::new(
-                Other,
-                "MDA header checksum failure",
-            )));
+            return Err(Error::ChecksumMismatch);
         }
 
         if &hdr[4..20] != MDA_MAGIC {
@@ -434,7 +1015,7 @@ impl PvHeader {
         Ok(iter_raw_locn(&hdr[40..]).next())
     }
 
-    fn write_mda_header(area: &PvArea, file: &mut File, rl: &RawLocn) -> Result<()> {
+    fn write_mda_header(area: &PvArea, file: &mut DevFile, rl: &RawLocn) -> Result<()> {
         let mut hdr = [0u8; MDA_HEADER_SIZE];
 
         hdr[4..20].copy_from_slice(MDA_MAGIC);
@@ -461,52 +1042,226 @@ impl PvHeader {
         Ok(())
     }
 
+    // Read the text from a metadata area and verify its checksum.
+    // Returns None if the area is ignored or has never been written.
+    fn read_mda_text(pvarea: &PvArea, f: &mut DevFile) -> Result<Option<Vec<u8>>> {
+        let rl = match Self::read_mda_header(&pvarea, f)? {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+
+        if rl.ignored {
+            return Ok(None);
+        }
+
+        // Check before using these as offsets and lengths, so a bad value
+        // can't cause an underflow or a huge allocation.
+        if rl.offset < MDA_HEADER_SIZE as u64
+            || rl.offset >= pvarea.size
+            || rl.size > pvarea.size - MDA_HEADER_SIZE as u64
+        {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "MDA rlocn is outside the metadata area",
+            )));
+        }
+
+        // Offsets into the device stay u64 so they can't be truncated on
+        // 32-bit targets. Only lengths within the MDA become usize.
+        let mut text = vec![0; rl.size as usize];
+        let first_read = min(pvarea.size - rl.offset, rl.size) as usize;
+
+        f.seek(SeekFrom::Start(pvarea.offset + rl.offset))?;
+        f.read_exact(&mut text[..first_read])?;
+
+        if first_read != text.len() {
+            f.seek(SeekFrom::Start(pvarea.offset + MDA_HEADER_SIZE as u64))?;
+            f.read_exact(&mut text[first_read..])?;
+        }
+
+        if rl.checksum != crc32_calc(&text) {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        Ok(Some(text))
+    }
+
     /// Read the metadata contained in the metadata area.
     /// In the case of multiple metadata areas, return the information
     /// from the first valid one.
     pub fn read_metadata(&self) -> Result<LvmTextMap> {
-        let mut f = OpenOptions::new().read(true).open(&self.dev_path)?;
+        self.read_metadata_checked().map(|(map, _)| map)
+    }
+
+    /// Read the metadata contained in the metadata area, checking every
+    /// metadata area on the PV.
+    ///
+    /// Returns the information from the first valid area, along with a
+    /// list of the areas that could not be read and why. This allows
+    /// damage to a redundant copy to be reported even though the
+    /// metadata itself could be recovered. Fails only if no area holds
+    /// valid metadata.
+    pub fn read_metadata_checked(&self) -> Result<(LvmTextMap, Vec<(PvArea, Error)>)> {
+        let mut f = DevFile::open(&self.dev_path, false, self.direct_io)?;
+
+        let mut map = None;
+        let mut failed = Vec::new();
 
         for pvarea in &self.metadata_areas {
-            let rl = match Self::read_mda_header(&pvarea, &mut f)? {
-                None => continue,
-                Some(x) => x,
+            let text = match Self::read_mda_text(pvarea, &mut f) {
+                Ok(Some(text)) => text,
+                Ok(None) => continue,
+                Err(e) => {
+                    failed.push((*pvarea, e));
+                    continue;
+                }
             };
 
-            if rl.ignored {
+            if map.is_some() {
                 continue;
             }
 
-            let mut text = vec![0; rl.size as usize];
-            let first_read = min(pvarea.size - rl.offset, rl.size) as usize;
+            match buf_to_textmap(&text) {
+                Ok(x) => map = Some(x),
+                Err(e) => failed.push((*pvarea, e)),
+            }
+        }
+
+        match map {
+            Some(map) => Ok((map, failed)),
+            None => match failed.into_iter().next() {
+                Some((_, e)) => Err(e),
+                None => Err(Error::Io(io::Error::new(Other, "No valid metadata found"))),
+            },
+        }
+    }
 
-            f.seek(SeekFrom::Start(pvarea.offset + rl.offset))?;
-            f.read(&mut text[..first_read])?;
+    /// Attempt to recover metadata from a PV whose MDA headers are
+    /// damaged, by examining the text areas directly. This is a last
+    /// resort, for when `read_metadata` fails.
+    ///
+    /// If the location recorded in an MDA header still points at text
+    /// matching its recorded checksum, that text is used even though the
+    /// header itself fails its checksum. Otherwise, each sector of the
+    /// text area is tried as the start of a copy of the metadata. Of all
+    /// the copies found, the one with the highest seqno is returned.
+    pub fn read_metadata_salvage(&self) -> Result<LvmTextMap> {
+        let mut f = DevFile::open(&self.dev_path, false, self.direct_io)?;
 
-            if first_read != rl.size as usize {
-                f.seek(SeekFrom::Start(pvarea.offset + MDA_HEADER_SIZE as u64))?;
-                f.read(&mut text[rl.size as usize - first_read..])?;
+        let mut best: Option<(i64, LvmTextMap)> = None;
+
+        for pvarea in &self.metadata_areas {
+            let mut area_buf = vec![0; pvarea.size as usize];
+            f.seek(SeekFrom::Start(pvarea.offset))?;
+            f.read_exact(&mut area_buf)?;
+
+            for map in Self::salvage_area(&area_buf) {
+                let seqno = match vg_seqno(&map) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if best
+                    .as_ref()
+                    .map_or(true, |&(best_seqno, _)| seqno > best_seqno)
+                {
+                    best = Some((seqno, map));
+                }
             }
+        }
 
-            if rl.checksum != crc32_calc(&text) {
-                return Err(Error::Io(io::Error::new(
-                    Other,
-                    "MDA text checksum failure",
-                )));
+        best.map(|(_, map)| map).ok_or(Error::Io(io::Error::new(
+            Other,
+            "No metadata could be salvaged",
+        )))
+    }
+
+    // Find all plausible copies of metadata in the contents of an MDA.
+    fn salvage_area(area_buf: &[u8]) -> Vec<LvmTextMap> {
+        let mut maps = Vec::new();
+        let area_size = area_buf.len();
+
+        if area_size <= MDA_HEADER_SIZE {
+            return maps;
+        }
+
+        // First, trust the rlocn if its text checksum is still good.
+        if let Some(rl) = iter_raw_locn(&area_buf[40..MDA_HEADER_SIZE]).next() {
+            let offset = rl.offset as usize;
+            let size = rl.size as usize;
+
+            if offset >= MDA_HEADER_SIZE
+                && offset < area_size
+                && size <= area_size - MDA_HEADER_SIZE
+            {
+                let first_read = min(area_size - offset, size);
+                let mut text = area_buf[offset..offset + first_read].to_vec();
+                text.extend(&area_buf[MDA_HEADER_SIZE..MDA_HEADER_SIZE + size - first_read]);
+
+                if rl.checksum == crc32_calc(&text) {
+                    if let Ok(map) = buf_to_textmap(&text) {
+                        maps.push(map);
+                        return maps;
+                    }
+                }
+            }
+        }
+
+        // Otherwise, metadata is always written starting on a sector
+        // boundary and ends with a null, so try each sector.
+        for start in (MDA_HEADER_SIZE..area_size).step_by(SECTOR_SIZE) {
+            if area_buf[start] == b'\0' {
+                continue;
             }
 
-            return buf_to_textmap(&text);
+            let end = match area_buf[start..].iter().position(|&b| b == b'\0') {
+                Some(len) => start + len,
+                None => continue,
+            };
+
+            if let Ok(map) = buf_to_textmap(&area_buf[start..end]) {
+                if vg_seqno(&map).is_some() {
+                    maps.push(map);
+                }
+            }
         }
 
-        return Err(Error::Io(io::Error::new(Other, "No valid metadata found")));
+        maps
+    }
+
+    /// Increment the VG's seqno in the given metadata, starting at 1 if
+    /// it has none, then write it to all active metadata areas in the PV.
+    /// Returns the new seqno.
+    pub fn write_metadata(&mut self, map: &mut LvmTextMap) -> Result<u64> {
+        let seqno = {
+            // The VG's textmap is the only textmap in the metadata
+            let vg_map = map
+                .values_mut()
+                .filter_map(|value| match value {
+                    &mut Entry::TextMap(ref mut vg_map) => Some(vg_map),
+                    _ => None,
+                })
+                .next()
+                .ok_or(Error::Io(io::Error::new(Other, "No VG found in metadata")))?;
+
+            let seqno = vg_map.i64_from_textmap("seqno").unwrap_or(0) + 1;
+            vg_map.insert("seqno".to_string(), Entry::Number(seqno));
+            seqno as u64
+        };
+
+        self.write_metadata_with_options(map, &WriteOptions::default())?;
+
+        Ok(seqno)
     }
 
-    /// Write the given metadata to all active metadata areas in the PV.
-    pub fn write_metadata(&mut self, map: &LvmTextMap) -> Result<()> {
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&self.dev_path)?;
+    /// Write the given metadata to all active metadata areas in the PV,
+    /// as controlled by `opts`. The metadata is written as given, so a VG
+    /// can write the same seqno to all its PVs.
+    pub fn write_metadata_with_options(
+        &mut self,
+        map: &LvmTextMap,
+        opts: &WriteOptions,
+    ) -> Result<()> {
+        let mut f = DevFile::open(&self.dev_path, true, self.direct_io)?;
 
         let mut text = textmap_to_buf(map);
         // Ends with one null
@@ -528,16 +1283,57 @@ impl PvHeader {
                 continue;
             }
 
-            // start at next sector in loop, but skip 0th sector
-            let start_off = min(
-                MDA_HEADER_SIZE as u64,
-                (align_to((rl.offset + rl.size) as usize, SECTOR_SIZE) % pvarea.size as usize)
-                    as u64,
-            );
-            let tail_space = pvarea.size as u64 - start_off;
+            // The new copy must not overwrite the old one: if we crash
+            // before the header is updated, the header still points to
+            // the old copy, which must still be intact.
+            let ring_size = pvarea.size - MDA_HEADER_SIZE as u64;
+            if rl.size != 0
+                && (rl.offset < MDA_HEADER_SIZE as u64
+                    || rl.offset >= pvarea.size
+                    || rl.size > ring_size)
+            {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    "MDA rlocn is outside the metadata area",
+                )));
+            }
+            let old_ranges = mda_text_ranges(pvarea.size, rl.offset, rl.size);
+
+            // start at next sector after the old copy, wherever it
+            // ended, but skip 0th sector
+            let text_start = align_to(MDA_HEADER_SIZE as u64, self.sector_size);
+            let old_end = old_ranges.last().map_or(rl.offset, |r| r.1);
+            let start_off = match align_to(old_end, self.sector_size) {
+                x if x < pvarea.size => max(text_start, x),
+                _ => text_start,
+            };
+
+            let new_ranges = mda_text_ranges(pvarea.size, start_off, text.len() as u64);
+            let overlaps = new_ranges
+                .iter()
+                .any(|new| old_ranges.iter().any(|old| new.0 < old.1 && old.0 < new.1));
+            if text.len() as u64 > ring_size || overlaps {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    "Metadata too large to write without overwriting the previous copy",
+                )));
+            }
+
+            let tail_space = pvarea.size.saturating_sub(start_off);
+
+            if start_off % SECTOR_SIZE as u64 != 0 {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    "Metadata would not start on a sector boundary",
+                )));
+            }
 
-            assert_eq!(start_off % SECTOR_SIZE as u64, 0);
-            assert_eq!(tail_space % SECTOR_SIZE as u64, 0);
+            if !opts.allow_wrap && text.len() as u64 > tail_space {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    "Metadata does not fit in MDA without wrapping",
+                )));
+            }
 
             let written = if tail_space != 0 {
                 f.seek(SeekFrom::Start(pvarea.offset + start_off))?;
@@ -552,6 +1348,9 @@ impl PvHeader {
                 f.write_all(&text[written as usize..])?;
             }
 
+            // The text must be on disk before the header points to it.
+            f.sync_all()?;
+
             let new_rl = RawLocn {
                 offset: start_off,
                 size: text.len() as u64,
@@ -565,18 +1364,1073 @@ impl PvHeader {
     }
 }
 
+// The ranges of offsets within an MDA that size bytes of text at offset
+// occupy: up to the end of the area, then, if the text wraps, on from
+// the end of the MDA header.
+fn mda_text_ranges(area_size: u64, offset: u64, size: u64) -> Vec<(u64, u64)> {
+    let first = min(area_size.saturating_sub(offset), size);
+    let mut ranges = Vec::new();
+    if first != 0 {
+        ranges.push((offset, offset + first));
+    }
+    if first != size {
+        let wrapped = MDA_HEADER_SIZE as u64;
+        ranges.push((wrapped, wrapped + size - first));
+    }
+    ranges
+}
+
+impl ToJson for PvHeader {
+    fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("uuid".to_string(), self.uuid.to_json());
+        obj.insert("size".to_string(), self.size.to_json());
+        obj.insert("sector_size".to_string(), self.sector_size.to_json());
+        obj.insert("ext_version".to_string(), self.ext_version.to_json());
+        obj.insert("data_areas".to_string(), self.data_areas.to_json());
+        obj.insert("metadata_areas".to_string(), self.metadata_areas.to_json());
+        obj.insert(
+            "bootloader_areas".to_string(),
+            self.bootloader_areas.to_json(),
+        );
+        obj.insert("label".to_string(), self.label.to_json());
+        obj.insert(
+            "dev_path".to_string(),
+            self.dev_path.to_string_lossy().into_owned().to_json(),
+        );
+        Json::Object(obj)
+    }
+}
+
 /// Scan a list of directories for block devices containing LVM PV labels.
 pub fn pvheader_scan(dirs: &[&Path]) -> Result<Vec<PathBuf>> {
+    scan_dirs(dirs, |mode| mode & S_IFMT == S_IFBLK, |_| true)
+}
+
+/// Scan a list of directories for block devices containing LVM PV labels,
+/// skipping any devices the filter rejects without opening them.
+pub fn pvheader_scan_filtered(dirs: &[&Path], filter: &DeviceFilter) -> Result<Vec<PathBuf>> {
+    scan_dirs(
+        dirs,
+        |mode| mode & S_IFMT == S_IFBLK,
+        |path| filter.accepts(path),
+    )
+}
+
+/// Scan a list of directories for block devices or regular files
+/// containing LVM PV labels.
+///
+/// This allows PV images, such as VM disk images or test fixtures, to be
+/// inspected without attaching them to a loop device first.
+pub fn pvheader_scan_files(dirs: &[&Path]) -> Result<Vec<PathBuf>> {
+    scan_dirs(
+        dirs,
+        |mode| mode & S_IFMT == S_IFBLK || mode & S_IFMT == S_IFREG,
+        |_| true,
+    )
+}
+
+/// Scan a list of directories for block devices containing LVM PV labels,
+/// and group them by PV UUID.
+///
+/// A UUID with more than one device means the PV has been duplicated,
+/// e.g. by cloning a disk with dd, and it is ambiguous which device
+/// belongs in the VG. Each UUID's devices are sorted by path, so a
+/// caller can pick one consistently. Paths to the same device, such as
+/// /dev/dm-0 and a link to it in /dev/mapper, are only listed once, by
+/// the first path in sorted order.
+pub fn pvheader_scan_by_uuid(dirs: &[&Path]) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let found = scan_dirs_headers(dirs, |mode| mode & S_IFMT == S_IFBLK, |_| true)?;
+    Ok(group_by_uuid(found))
+}
+
+/// As `pvheader_scan_by_uuid`, but also including regular files, such as
+/// PV images, as `pvheader_scan_files` does.
+pub fn pvheader_scan_files_by_uuid(dirs: &[&Path]) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let found = scan_dirs_headers(
+        dirs,
+        |mode| mode & S_IFMT == S_IFBLK || mode & S_IFMT == S_IFREG,
+        |_| true,
+    )?;
+    Ok(group_by_uuid(found))
+}
+
+// Group the paths found by scan_dirs_headers by PV UUID, in sorted
+// order, dropping any that are the same device or file as an earlier one.
+fn group_by_uuid(
+    mut found: Vec<(PathBuf, stat::FileStat, PvHeader)>,
+) -> BTreeMap<String, Vec<PathBuf>> {
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Block devices are identified by device number, files by inode
+    let mut seen = BTreeSet::new();
+    let mut by_uuid = BTreeMap::new();
+    for (path, st, pvh) in found {
+        let id = if st.st_mode as u32 & S_IFMT == S_IFBLK {
+            (true, st.st_rdev as u64, 0)
+        } else {
+            (false, st.st_dev as u64, st.st_ino as u64)
+        };
+        if seen.insert(id) {
+            by_uuid.entry(pvh.uuid).or_insert_with(Vec::new).push(path);
+        }
+    }
+
+    by_uuid
+}
+
+// Return paths in dirs that path_filter accepts, whose file type is
+// accepted by mode_filter, and which contain a PV label.
+fn scan_dirs<F, P>(dirs: &[&Path], mode_filter: F, path_filter: P) -> Result<Vec<PathBuf>>
+where
+    F: Fn(u32) -> bool,
+    P: Fn(&Path) -> bool,
+{
+    Ok(scan_dirs_headers(dirs, mode_filter, path_filter)?
+        .into_iter()
+        .map(|(path, _, _)| path)
+        .collect())
+}
+
+// As scan_dirs, but also return each path's stat and PV header.
+fn scan_dirs_headers<F, P>(
+    dirs: &[&Path],
+    mode_filter: F,
+    path_filter: P,
+) -> Result<Vec<(PathBuf, stat::FileStat, PvHeader)>>
+where
+    F: Fn(u32) -> bool,
+    P: Fn(&Path) -> bool,
+{
     let mut ret_vec = Vec::new();
 
     for dir in dirs {
         ret_vec.extend(
             read_dir(dir)?
-                .map(|res| res.unwrap().path())
-                .filter(|path| (stat::stat(path).unwrap().st_mode & 0x6000) == 0x6000) // S_IFBLK
-                .filter(|path| PvHeader::find_in_dev(path).is_ok()),
+                .filter_map(|res| res.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path_filter(path))
+                .filter_map(|path| match stat::stat(&path) {
+                    Ok(st) if mode_filter(st.st_mode as u32) => Some((path, st)),
+                    _ => None,
+                })
+                .filter_map(|(path, st)| {
+                    PvHeader::find_in_dev(&path).ok().map(|pvh| (path, st, pvh))
+                }),
         )
     }
 
     Ok(ret_vec)
 }
+
+// Get the seqno of the VG described by a PV's metadata.
+pub fn vg_seqno(map: &LvmTextMap) -> Option<i64> {
+    // The VG's textmap is the only textmap in the metadata
+    map.values()
+        .filter_map(|value| match value {
+            &Entry::TextMap(ref vg_map) => vg_map.i64_from_textmap("seqno"),
+            _ => None,
+        })
+        .next()
+}
+
+/// Scan a list of directories for PVs, and return the name and metadata
+/// of each VG they belong to, keyed by VG UUID, since two VGs may share
+/// a name.
+///
+/// A VG's metadata lists all its PVs, so once it has been read from one
+/// of them, it is not read again from the others. PVs that are not in a
+/// VG, or whose metadata cannot be read, are skipped.
+pub fn list_vgs(dirs: &[&Path]) -> Result<BTreeMap<String, (String, LvmTextMap)>> {
+    let mut vgs = BTreeMap::new();
+    let mut seen_pvs = BTreeSet::new();
+
+    for path in pvheader_scan(dirs)? {
+        let pvh = match PvHeader::find_in_dev(&path) {
+            Ok(pvh) => pvh,
+            Err(_) => continue,
+        };
+
+        if seen_pvs.contains(&pvh.uuid) {
+            continue;
+        }
+
+        let map = match pvh.read_metadata() {
+            Ok(map) => map,
+            Err(_) => continue,
+        };
+
+        // The VG's textmap is the only textmap in the metadata
+        for (name, value) in map {
+            if let Entry::TextMap(vg_map) = value {
+                if let Some(pvs) = vg_map.textmap_from_textmap("physical_volumes") {
+                    for pv in pvs.values() {
+                        if let &Entry::TextMap(ref pv_map) = pv {
+                            if let Some(id) = pv_map.string_from_textmap("id") {
+                                seen_pvs.insert(id.to_string());
+                            }
+                        }
+                    }
+                }
+                if let Some(id) = vg_map.string_from_textmap("id").map(|x| x.to_string()) {
+                    vgs.insert(id, (name, *vg_map));
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(vgs)
+}
+
+/// Initialize several devices as PVs at once, each in its own thread,
+/// as `PvHeader::initialize_with_options` does.
+///
+/// If any device cannot be initialized, the labels written to the others
+/// are wiped again, so either all devices become PVs or none do. Since a
+/// label that was overwritten could not be put back, `opts.force` is not
+/// allowed, and neither is giving the same device twice.
+pub fn initialize_many(paths: &[&Path], opts: &PvCreateOptions) -> Result<Vec<PvHeader>> {
+    if opts.force {
+        return Err(Error::Io(io::Error::new(
+            Other,
+            "Cannot force initialization of several PVs at once",
+        )));
+    }
+
+    let mut seen = BTreeSet::new();
+    for path in paths {
+        let real_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if !seen.insert(real_path) {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("{} is given more than once", path.display()),
+            )));
+        }
+    }
+
+    let handles: Vec<_> = paths
+        .iter()
+        .map(|path| {
+            let path = path.to_path_buf();
+            let opts = opts.clone();
+            thread::spawn(move || {
+                PvHeader::initialize_with_options(&path, &opts).map_err(|e| match e {
+                    Error::Io(e) => e,
+                    e => io::Error::new(Other, format!("{:?}", e)),
+                })
+            })
+        })
+        .collect();
+
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| {
+            handle
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::new(Other, "PV initialization panicked")))
+        })
+        .collect();
+
+    if results.iter().all(|res| res.is_ok()) {
+        return Ok(results.into_iter().filter_map(|res| res.ok()).collect());
+    }
+
+    let wipe_errors: Vec<String> = results
+        .iter()
+        .filter_map(|res| res.as_ref().ok())
+        .filter_map(|pvh| {
+            PvHeader::wipe(&pvh.dev_path, true)
+                .err()
+                .map(|e| format!("{}: {}", pvh.dev_path.display(), e))
+        })
+        .collect();
+
+    let err = results
+        .into_iter()
+        .filter_map(|res| res.err())
+        .next()
+        .expect("a result was an error");
+    if wipe_errors.is_empty() {
+        Err(Error::Io(err))
+    } else {
+        Err(Error::Io(io::Error::new(
+            err.kind(),
+            format!(
+                "{}, and the new labels could not be wiped from {}",
+                err,
+                wipe_errors.join(", ")
+            ),
+        )))
+    }
+}
+
+/// Read the metadata from each of a VG's PVs, and return the most
+/// recent copy: the one with the highest seqno.
+///
+/// Each PV is read in its own thread, so slow devices are read
+/// concurrently rather than one after another. PVs whose metadata cannot
+/// be read are skipped.
+pub fn read_vg_metadata(headers: &[PvHeader]) -> Result<LvmTextMap> {
+    let handles: Vec<_> = headers
+        .iter()
+        .cloned()
+        .map(|pvh| thread::spawn(move || pvh.read_metadata().ok()))
+        .collect();
+
+    handles
+        .into_iter()
+        .filter_map(|handle| handle.join().ok())
+        .filter_map(|map| map)
+        .filter_map(|map| vg_seqno(&map).map(|seqno| (seqno, map)))
+        .max_by_key(|&(seqno, _)| seqno)
+        .map(|(_, map)| map)
+        .ok_or(Error::Io(io::Error::new(
+            Other,
+            "No metadata could be read from any PV",
+        )))
+}
+
+/// Scan a list of directories for PVs, and return the UUIDs of all the
+/// VGs they belong to.
+///
+/// PVs that are not in a VG, or whose metadata cannot be read, are
+/// skipped.
+pub fn vg_uuids(dirs: &[&Path]) -> Result<BTreeSet<String>> {
+    Ok(list_vgs(dirs)?.keys().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+    use crate::parser::buf_to_textmap;
+    use crate::testutil::{can_use_loop, LoopDevice, TestDir, TestImage};
+    use crate::VG;
+
+    fn read_header_sector(pvh: &PvHeader) -> Vec<u8> {
+        let mut f = File::open(&pvh.dev_path).unwrap();
+        let mut buf = vec![0; MDA_HEADER_SIZE];
+        f.seek(SeekFrom::Start(pvh.metadata_areas[0].offset))
+            .unwrap();
+        f.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_header_sector(pvh: &PvHeader, buf: &[u8]) {
+        let mut f = OpenOptions::new().write(true).open(&pvh.dev_path).unwrap();
+        f.seek(SeekFrom::Start(pvh.metadata_areas[0].offset))
+            .unwrap();
+        f.write_all(buf).unwrap();
+    }
+
+    // If a crash leaves the MDA header pointing at the previous copy of
+    // the metadata, that copy must still be intact, including when one
+    // or the other copy wraps around the end of the ring.
+    #[test]
+    fn old_metadata_survives_interrupted_write() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        pvh.metadata_areas.truncate(1);
+
+        let filler = "x".repeat(300 << 10);
+        let maps: Vec<LvmTextMap> = (1..12)
+            .map(|seqno| {
+                let text = format!(
+                    "vg0 {{\nseqno = {}\ndescription = \"{}\"\n}}\n",
+                    seqno,
+                    &filler[..(seqno * 97_001) % filler.len()]
+                );
+                buf_to_textmap(text.as_bytes()).unwrap()
+            })
+            .collect();
+
+        let opts = WriteOptions::default();
+        pvh.write_metadata_with_options(&maps[0], &opts).unwrap();
+        for pair in maps.windows(2) {
+            let old_header = read_header_sector(&pvh);
+            pvh.write_metadata_with_options(&pair[1], &opts).unwrap();
+            let new_header = read_header_sector(&pvh);
+
+            write_header_sector(&pvh, &old_header);
+            assert_eq!(pvh.read_metadata().unwrap(), pair[0]);
+
+            write_header_sector(&pvh, &new_header);
+            assert_eq!(pvh.read_metadata().unwrap(), pair[1]);
+        }
+    }
+
+    #[test]
+    fn metadata_too_large_to_keep_previous_copy() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(16 << 20);
+        let mut pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let opts = WriteOptions::default();
+        let small = buf_to_textmap(b"vg0 {\nseqno = 1\n}\n").unwrap();
+        pvh.write_metadata_with_options(&small, &opts).unwrap();
+
+        // Fits in the MDA on its own, but not alongside the first copy
+        let ring_size = pvh.metadata_areas[0].size as usize - MDA_HEADER_SIZE;
+        let text = format!(
+            "vg0 {{\nseqno = 2\ndescription = \"{}\"\n}}\n",
+            "x".repeat(ring_size - 400)
+        );
+        let large = buf_to_textmap(text.as_bytes()).unwrap();
+        assert!(pvh.write_metadata_with_options(&large, &opts).is_err());
+        assert_eq!(pvh.read_metadata().unwrap(), small);
+    }
+
+    #[test]
+    fn write_metadata_advances_seqno() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        let mut map = buf_to_textmap(b"vg0 {\nid = \"x\"\n}\n").unwrap();
+
+        assert_eq!(pvh.write_metadata(&mut map).unwrap(), 1);
+        assert_eq!(vg_seqno(&pvh.read_metadata().unwrap()), Some(1));
+
+        assert_eq!(pvh.write_metadata(&mut map).unwrap(), 2);
+        assert_eq!(vg_seqno(&pvh.read_metadata().unwrap()), Some(2));
+    }
+
+    #[test]
+    fn pvheader_to_json() {
+        let image = TestImage::new(16 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+
+        let json = pvh.to_json();
+        let obj = json.as_object().unwrap();
+        let keys: Vec<&str> = obj.keys().map(|k| &k[..]).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "bootloader_areas",
+                "data_areas",
+                "dev_path",
+                "ext_version",
+                "label",
+                "metadata_areas",
+                "sector_size",
+                "size",
+                "uuid",
+            ]
+        );
+
+        assert_eq!(obj["uuid"].as_string(), Some(&pvh.uuid[..]));
+        assert_eq!(obj["size"].as_u64(), Some(pvh.size));
+        assert_eq!(
+            obj["dev_path"].as_string(),
+            Some(&*image.path().to_string_lossy())
+        );
+
+        let mdas = obj["metadata_areas"].as_array().unwrap();
+        assert_eq!(mdas.len(), pvh.metadata_areas.len());
+        let mda = mdas[0].as_object().unwrap();
+        assert_eq!(mda["offset"].as_u64(), Some(pvh.metadata_areas[0].offset));
+        assert_eq!(mda["size"].as_u64(), Some(pvh.metadata_areas[0].size));
+    }
+
+    #[test]
+    fn reserved_ranges_use_sector_size() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+
+        let ranges = pvh.reserved_sector_ranges();
+        assert_eq!(ranges[0], (0, 4));
+        let mda = pvh.metadata_areas[0];
+        assert!(ranges.contains(&(mda.offset / 512, mda.size / 512)));
+
+        pvh.sector_size = 4096;
+        let ranges = pvh.reserved_sector_ranges();
+        assert_eq!(ranges[0], (0, 1));
+        assert!(ranges.contains(&(mda.offset / 4096, mda.size / 4096)));
+    }
+
+    // Where the data area ends: at the start of a metadata area after
+    // it, or at the end of the PV.
+    fn data_end(pvh: &PvHeader) -> u64 {
+        let da = pvh.data_areas[0];
+        if da.size != 0 {
+            return da.offset + da.size;
+        }
+        pvh.metadata_areas
+            .iter()
+            .map(|a| a.offset)
+            .filter(|&offset| offset > da.offset)
+            .min()
+            .unwrap_or(pvh.size)
+    }
+
+    #[test]
+    fn resize_grows_data_area() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        let old_end = data_end(&pvh);
+
+        image.set_len(32 << 20);
+        pvh.resize().unwrap();
+        assert_eq!(pvh.size, 32 << 20);
+        assert_eq!(data_end(&pvh), old_end + (16 << 20));
+        assert_eq!(PvHeader::find_in_dev(image.path()).unwrap().size, 32 << 20);
+
+        // Shrinking would cut off data, so it's refused
+        image.set_len(24 << 20);
+        assert!(pvh.resize().is_err());
+        assert_eq!(pvh.size, 32 << 20);
+    }
+
+    // A PV on image with a 64KiB bootloader area.
+    fn bootloader_pv(image: &TestImage) -> PvHeader {
+        let opts = PvCreateOptions {
+            bootloader_area_size: 64 << 10,
+            ..Default::default()
+        };
+        PvHeader::initialize_with_options(image.path(), &opts).unwrap()
+    }
+
+    #[test]
+    fn read_bootloader_area() {
+        let image = TestImage::new(16 << 20);
+        let pvh = bootloader_pv(&image);
+        let area = pvh.bootloader_areas[0];
+
+        let pattern: Vec<u8> = (0..area.size).map(|x| x as u8).collect();
+        let mut f = OpenOptions::new().write(true).open(image.path()).unwrap();
+        f.seek(SeekFrom::Start(area.offset)).unwrap();
+        f.write_all(&pattern).unwrap();
+
+        assert_eq!(pvh.read_bootloader_area(0).unwrap(), pattern);
+        assert!(pvh.read_bootloader_area(1).is_err());
+
+        let image = TestImage::new(16 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+        assert!(pvh.read_bootloader_area(0).is_err());
+    }
+
+    #[test]
+    fn write_bootloader_area() {
+        let image = TestImage::new(16 << 20);
+        let pvh = bootloader_pv(&image);
+        let size = pvh.bootloader_areas[0].size as usize;
+
+        pvh.write_bootloader_area(0, b"boot code").unwrap();
+        let data = pvh.read_bootloader_area(0).unwrap();
+        assert_eq!(&data[..9], b"boot code");
+        assert!(data[9..].iter().all(|&x| x == 0));
+
+        assert!(pvh.write_bootloader_area(0, &vec![1; size + 1]).is_err());
+        assert!(pvh.write_bootloader_area(1, b"boot code").is_err());
+        assert_eq!(pvh.read_bootloader_area(0).unwrap(), data);
+
+        // The data area starts after the bootloader area
+        let area = pvh.bootloader_areas[0];
+        assert!(pvh.data_areas[0].offset >= area.offset + area.size);
+    }
+
+    #[test]
+    fn wipe_removes_label() {
+        let image = TestImage::new(16 << 20);
+        PvHeader::initialize(image.path()).unwrap();
+        PvHeader::wipe(image.path(), false).unwrap();
+        assert!(PvHeader::find_in_dev(image.path()).is_err());
+
+        // A PV with metadata is in a VG, so needs force
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        let mut map = buf_to_textmap(b"vg0 {\nid = \"x\"\n}\n").unwrap();
+        pvh.write_metadata(&mut map).unwrap();
+
+        assert!(PvHeader::wipe(image.path(), false).is_err());
+        assert!(PvHeader::find_in_dev(image.path()).is_ok());
+
+        PvHeader::wipe(image.path(), true).unwrap();
+        assert!(PvHeader::find_in_dev(image.path()).is_err());
+    }
+
+    #[test]
+    fn device_size_of_image() {
+        let image = TestImage::new(16 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+        assert_eq!(pvh.device_size().unwrap(), 16 << 20);
+
+        image.set_len(20 << 20);
+        assert_eq!(pvh.device_size().unwrap(), 20 << 20);
+        assert_eq!(pvh.size, 16 << 20);
+    }
+
+    // A PV on image with metadata for vg0 written to both its metadata
+    // areas.
+    fn pv_with_metadata(image: &TestImage) -> (PvHeader, LvmTextMap) {
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        let mut map = buf_to_textmap(b"vg0 {\nid = \"x\"\n}\n").unwrap();
+        pvh.write_metadata(&mut map).unwrap();
+        (pvh, map)
+    }
+
+    // Break the checksum of the first metadata area's header.
+    fn corrupt_first_mda_header(pvh: &PvHeader) {
+        let mut header = read_header_sector(pvh);
+        header[0] ^= 0xff;
+        write_header_sector(pvh, &header);
+    }
+
+    #[test]
+    fn read_metadata_checked_reports_bad_mda() {
+        let image = TestImage::new(16 << 20);
+        let (pvh, map) = pv_with_metadata(&image);
+        assert_eq!(pvh.metadata_areas.len(), 2);
+
+        corrupt_first_mda_header(&pvh);
+
+        let (found, failed) = pvh.read_metadata_checked().unwrap();
+        assert_eq!(found, map);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, pvh.metadata_areas[0]);
+    }
+
+    #[test]
+    fn read_metadata_salvage_recovers() {
+        let image = TestImage::new(16 << 20);
+        let (mut pvh, map) = pv_with_metadata(&image);
+        // Leave only the damaged copy
+        pvh.metadata_areas.truncate(1);
+
+        corrupt_first_mda_header(&pvh);
+
+        assert!(pvh.read_metadata().is_err());
+        assert_eq!(pvh.read_metadata_salvage().unwrap(), map);
+    }
+
+    #[test]
+    fn metadata_on_4k_device() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::with_sector_size(64 << 20, 4096);
+        let mut pvh = PvHeader::initialize(loop0.path()).unwrap();
+        assert_eq!(pvh.sector_size, 4096);
+        for area in pvh.metadata_areas.iter().chain(pvh.data_areas.iter()) {
+            assert_eq!(area.offset % 4096, 0);
+        }
+
+        // Enough writes for the text to wrap around the ring
+        let filler = "x".repeat(200 << 10);
+        for len in &[1000, 150_000, 3, 199_999, 77_777, 12] {
+            let text = format!("vg0 {{\ndescription = \"{}\"\n}}\n", &filler[..*len]);
+            let mut map = buf_to_textmap(text.as_bytes()).unwrap();
+            pvh.write_metadata(&mut map).unwrap();
+
+            let pvh = PvHeader::find_in_dev(loop0.path()).unwrap();
+            assert_eq!(pvh.sector_size, 4096);
+            assert_eq!(pvh.read_metadata().unwrap(), map);
+        }
+    }
+
+    #[test]
+    fn no_wrap_refuses_to_wrap() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        pvh.metadata_areas.truncate(1);
+
+        // Two copies fit in the ring one after the other, but a third
+        // must wrap around to the start
+        let filler = "x".repeat((pvh.metadata_areas[0].size * 2 / 5) as usize);
+        let maps: Vec<LvmTextMap> = (1..4)
+            .map(|seqno| {
+                let text = format!(
+                    "vg0 {{\nseqno = {}\ndescription = \"{}\"\n}}\n",
+                    seqno, filler
+                );
+                buf_to_textmap(text.as_bytes()).unwrap()
+            })
+            .collect();
+
+        let no_wrap = WriteOptions { allow_wrap: false };
+        pvh.write_metadata_with_options(&maps[0], &no_wrap).unwrap();
+        pvh.write_metadata_with_options(&maps[1], &no_wrap).unwrap();
+        assert!(pvh.write_metadata_with_options(&maps[2], &no_wrap).is_err());
+        assert_eq!(pvh.read_metadata().unwrap(), maps[1]);
+
+        pvh.write_metadata_with_options(&maps[2], &WriteOptions::default())
+            .unwrap();
+        assert_eq!(pvh.read_metadata().unwrap(), maps[2]);
+    }
+
+    #[test]
+    fn vg_uuids_of_two_vgs() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let dir = TestDir::new();
+        let loops = [LoopDevice::new(64 << 20), LoopDevice::new(64 << 20)];
+        let mut ids = BTreeSet::new();
+        for (i, loop_dev) in loops.iter().enumerate() {
+            PvHeader::initialize(loop_dev.path()).unwrap();
+            let vg = VG::create(&format!("melvin-test-uuids{}", i), vec![loop_dev.path()]).unwrap();
+            ids.insert(vg.id().to_string());
+            std::os::unix::fs::symlink(loop_dev.path(), dir.path().join(i.to_string())).unwrap();
+        }
+
+        assert_eq!(vg_uuids(&[dir.path()]).unwrap(), ids);
+    }
+
+    #[test]
+    fn list_vgs_from_one_pv() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let dir = TestDir::new();
+        let loops: Vec<LoopDevice> = (0..4).map(|_| LoopDevice::new(64 << 20)).collect();
+        for (i, loop_dev) in loops.iter().enumerate() {
+            PvHeader::initialize(loop_dev.path()).unwrap();
+            std::os::unix::fs::symlink(loop_dev.path(), dir.path().join(i.to_string())).unwrap();
+        }
+        let paths: Vec<&Path> = loops.iter().map(|x| x.path()).collect();
+        let vg = VG::create("melvin-test-listvgs", paths).unwrap();
+
+        // One PV's metadata is enough to find the VG and all its PVs
+        for loop_dev in &loops[1..] {
+            let pvh = PvHeader::find_in_dev(loop_dev.path()).unwrap();
+            let mut f = OpenOptions::new()
+                .write(true)
+                .open(loop_dev.path())
+                .unwrap();
+            for mda in &pvh.metadata_areas {
+                f.seek(SeekFrom::Start(mda.offset)).unwrap();
+                f.write_all(&[0; MDA_HEADER_SIZE]).unwrap();
+            }
+            assert!(pvh.read_metadata().is_err());
+        }
+
+        let vgs = list_vgs(&[dir.path()]).unwrap();
+        assert_eq!(vgs.len(), 1);
+        let (ref name, ref map) = vgs[vg.id()];
+        assert_eq!(name, "melvin-test-listvgs");
+        assert_eq!(
+            map.textmap_from_textmap("physical_volumes").unwrap().len(),
+            4
+        );
+    }
+
+    #[test]
+    fn read_vg_metadata_picks_highest_seqno() {
+        let images: Vec<TestImage> = (0..3).map(|_| TestImage::new(16 << 20)).collect();
+        let mut headers = Vec::new();
+        for (image, seqno) in images.iter().zip(&[3, 9, 5]) {
+            let mut pvh = PvHeader::initialize(image.path()).unwrap();
+            let text = format!("vg0 {{\nseqno = {}\n}}\n", seqno);
+            let map = buf_to_textmap(text.as_bytes()).unwrap();
+            pvh.write_metadata_with_options(&map, &WriteOptions::default())
+                .unwrap();
+            headers.push(pvh);
+        }
+        // A PV with no metadata is skipped
+        let empty = TestImage::new(16 << 20);
+        headers.push(PvHeader::initialize(empty.path()).unwrap());
+
+        assert_eq!(vg_seqno(&read_vg_metadata(&headers).unwrap()), Some(9));
+        assert!(read_vg_metadata(&headers[3..]).is_err());
+    }
+
+    #[test]
+    fn metadata_past_4g() {
+        // Sparse, so it takes next to no space
+        let image = TestImage::new(6 << 30);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        assert!(pvh.metadata_areas[1].offset > u32::max_value() as u64);
+
+        // Only use the metadata area at the end of the device
+        pvh.metadata_areas.remove(0);
+        let mut map = buf_to_textmap(b"vg0 {\nid = \"x\"\n}\n").unwrap();
+        pvh.write_metadata(&mut map).unwrap();
+        pvh.write_metadata(&mut map).unwrap();
+        assert_eq!(pvh.read_metadata().unwrap(), map);
+        assert_eq!(vg_seqno(&map), Some(2));
+    }
+
+    #[test]
+    fn label_type_round_trips() {
+        let image = TestImage::new(16 << 20);
+        PvHeader::initialize(image.path()).unwrap();
+        assert_eq!(
+            PvHeader::find_in_dev(image.path()).unwrap().label,
+            DEFAULT_LABEL_TYPE
+        );
+
+        let image = TestImage::new(16 << 20);
+        let mut opts = PvCreateOptions {
+            label: "MELVIN01".to_string(),
+            ..Default::default()
+        };
+        PvHeader::initialize_with_options(image.path(), &opts).unwrap();
+        assert_eq!(
+            PvHeader::find_in_dev(image.path()).unwrap().label,
+            "MELVIN01"
+        );
+
+        let image = TestImage::new(16 << 20);
+        opts.label = "MELVIN001".to_string();
+        assert!(PvHeader::initialize_with_options(image.path(), &opts).is_err());
+        assert!(PvHeader::find_in_dev(image.path()).is_err());
+    }
+
+    #[test]
+    fn initialize_then_find() {
+        let image = TestImage::new(64 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+
+        let found = PvHeader::find_in_dev(image.path()).unwrap();
+        assert_eq!(found.uuid, pvh.uuid);
+        assert_eq!(found.size, 64 << 20);
+        assert_eq!(found.data_areas, pvh.data_areas);
+        assert_eq!(found.metadata_areas, pvh.metadata_areas);
+        assert_eq!(found.metadata_areas.len(), 2);
+        assert!(found.bootloader_areas.is_empty());
+        assert_eq!(found.label, DEFAULT_LABEL_TYPE);
+
+        match PvHeader::initialize(image.path()) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::AlreadyExists),
+            x => panic!("expected AlreadyExists, got {:?}", x.map(|pvh| pvh.uuid)),
+        }
+        assert_eq!(PvHeader::find_in_dev(image.path()).unwrap().uuid, pvh.uuid);
+
+        let opts = PvCreateOptions {
+            force: true,
+            ..Default::default()
+        };
+        let forced = PvHeader::initialize_with_options(image.path(), &opts).unwrap();
+        assert_ne!(forced.uuid, pvh.uuid);
+        assert_eq!(
+            PvHeader::find_in_dev(image.path()).unwrap().uuid,
+            forced.uuid
+        );
+    }
+
+    #[test]
+    fn initialize_many_is_all_or_nothing() {
+        let image0 = TestImage::new(16 << 20);
+        let image1 = TestImage::new(16 << 20);
+        let missing = image1.path().with_extension("missing");
+        let opts = PvCreateOptions::default();
+
+        assert!(initialize_many(&[image0.path(), &missing], &opts).is_err());
+        assert!(PvHeader::find_in_dev(image0.path()).is_err());
+
+        assert!(initialize_many(&[image0.path(), image0.path()], &opts).is_err());
+        let force = PvCreateOptions {
+            force: true,
+            ..PvCreateOptions::default()
+        };
+        assert!(initialize_many(&[image0.path()], &force).is_err());
+
+        let pvhs = initialize_many(&[image0.path(), image1.path()], &opts).unwrap();
+        assert_eq!(pvhs.len(), 2);
+        assert!(PvHeader::find_in_dev(image1.path()).is_ok());
+    }
+
+    #[test]
+    fn scan_files_finds_images() {
+        let dir = TestDir::new();
+        let a = TestImage::new_at(dir.path().join("a"), 16 << 20);
+        let b = TestImage::new_at(dir.path().join("b"), 16 << 20);
+        let _empty = TestImage::new_at(dir.path().join("empty"), 16 << 20);
+        PvHeader::initialize(a.path()).unwrap();
+        PvHeader::initialize(b.path()).unwrap();
+
+        let mut found = pvheader_scan_files(&[dir.path()]).unwrap();
+        found.sort();
+        assert_eq!(found, vec![a.path().to_path_buf(), b.path().to_path_buf()]);
+
+        // Only block devices are scanned by default
+        assert!(pvheader_scan(&[dir.path()]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scan_by_uuid_finds_duplicates_once_each() {
+        let dir = TestDir::new();
+        let a = TestImage::new_at(dir.path().join("a"), 16 << 20);
+        let pvh = PvHeader::initialize(a.path()).unwrap();
+
+        // b is a copy of a, as if cloned with dd; c is a itself
+        let b = dir.path().join("b");
+        std::fs::copy(a.path(), &b).unwrap();
+        std::os::unix::fs::symlink(a.path(), dir.path().join("c")).unwrap();
+
+        let by_uuid = pvheader_scan_files_by_uuid(&[dir.path()]).unwrap();
+        assert_eq!(by_uuid.len(), 1);
+        assert_eq!(by_uuid[&pvh.uuid], vec![a.path().to_path_buf(), b]);
+    }
+
+    // A label in sector x, with its offset field set to offset and a
+    // valid CRC.
+    fn label_buf(x: usize, offset: u32) -> Vec<u8> {
+        let mut buf = vec![0; LABEL_SCAN_SECTORS * SECTOR_SIZE];
+        let mut sec_buf = [0; SECTOR_SIZE];
+        LabelHeader::initialize(&mut sec_buf, "LVM2 001");
+        LittleEndian::write_u64(&mut sec_buf[8..16], x as u64);
+        LittleEndian::write_u32(&mut sec_buf[20..24], offset);
+        let crc_val = crc32_calc(&sec_buf[20..]);
+        LittleEndian::write_u32(&mut sec_buf[16..20], crc_val);
+        buf[x * SECTOR_SIZE..(x + 1) * SECTOR_SIZE].copy_from_slice(&sec_buf);
+        buf
+    }
+
+    #[test]
+    fn pvheader_without_terminator() {
+        let path = Path::new("/dev/null");
+        let mut buf = vec![b'x'; ID_LEN + 8];
+        for _ in 0..3 {
+            let mut area = [0; 16];
+            LittleEndian::write_u64(&mut area[..8], 4096);
+            LittleEndian::write_u64(&mut area[8..], 4096);
+            buf.extend_from_slice(&area);
+        }
+
+        assert!(PvHeader::from_buf(&buf, DEFAULT_LABEL_TYPE, path).is_err());
+        assert!(PvHeader::from_buf(&buf[..ID_LEN], DEFAULT_LABEL_TYPE, path).is_err());
+
+        // With terminators for both lists, it parses
+        buf.extend_from_slice(&[0; 32]);
+        let pvh = PvHeader::from_buf(&buf, DEFAULT_LABEL_TYPE, path).unwrap();
+        assert_eq!(pvh.data_areas.len(), 3);
+        assert!(pvh.metadata_areas.is_empty());
+    }
+
+    // A pvheader with one data area and one metadata area, followed by
+    // an extension header of the given version and one bootloader area.
+    fn pvheader_buf(ext_version: u32) -> Vec<u8> {
+        let mut buf = vec![b'x'; ID_LEN + 8];
+        for &(offset, size) in &[(1 << 20, 0), (0, 0), (4096, 1 << 20), (0, 0)] {
+            let mut area = [0; 16];
+            LittleEndian::write_u64(&mut area[..8], offset);
+            LittleEndian::write_u64(&mut area[8..], size);
+            buf.extend_from_slice(&area);
+        }
+        let mut ext = [0; 8];
+        LittleEndian::write_u32(&mut ext[..4], ext_version);
+        buf.extend_from_slice(&ext);
+        let mut area = [0; 32];
+        LittleEndian::write_u64(&mut area[..8], 2 << 20);
+        LittleEndian::write_u64(&mut area[8..16], 4096);
+        buf.extend_from_slice(&area);
+        buf
+    }
+
+    #[test]
+    fn unknown_extension_version() {
+        let path = Path::new("/dev/null");
+
+        let pvh = PvHeader::from_buf(&pvheader_buf(1), DEFAULT_LABEL_TYPE, path).unwrap();
+        assert_eq!(pvh.ext_version, 1);
+        assert_eq!(pvh.bootloader_areas.len(), 1);
+
+        let pvh = PvHeader::from_buf(&pvheader_buf(2), DEFAULT_LABEL_TYPE, path).unwrap();
+        assert_eq!(pvh.ext_version, 2);
+        assert!(pvh.bootloader_areas.is_empty());
+        assert_eq!(pvh.data_areas.len(), 1);
+        assert_eq!(pvh.metadata_areas.len(), 1);
+    }
+
+    #[test]
+    fn label_header_checks_crc() {
+        let buf = label_buf(1, 32);
+        let lh = LabelHeader::from_buf(&buf, true).unwrap();
+        assert_eq!(lh.offset, SECTOR_SIZE as u32 + 32);
+        assert_eq!(lh.label, "LVM2 001");
+
+        // Every byte from the offset field on is covered by the CRC
+        for i in SECTOR_SIZE + 20..2 * SECTOR_SIZE {
+            let mut bad = buf.clone();
+            bad[i] ^= 0xff;
+            match LabelHeader::from_buf(&bad, true) {
+                Err(Error::ChecksumMismatch) => {}
+                res => panic!("byte {} flipped: {:?}", i, res),
+            }
+            assert!(LabelHeader::from_buf(&bad, false).is_ok());
+        }
+    }
+
+    #[test]
+    fn label_header_offset_overflow() {
+        assert!(LabelHeader::from_buf(&label_buf(3, u32::max_value()), true).is_err());
+    }
+
+    #[test]
+    fn layout() {
+        let layout = compute_layout(1 << 30, 1 << 20, 1 << 20, 512).unwrap();
+        assert_eq!(layout.label_offset, 512);
+        assert_eq!(
+            layout.mda0,
+            PvArea {
+                offset: 4096,
+                size: (1 << 20) - 4096
+            }
+        );
+        assert_eq!(
+            layout.data,
+            PvArea {
+                offset: 1 << 20,
+                size: (1 << 30) - (2 << 20)
+            }
+        );
+        assert_eq!(
+            layout.mda1,
+            PvArea {
+                offset: (1 << 30) - (1 << 20),
+                size: 1 << 20
+            }
+        );
+
+        // Sectors bigger than 4KiB push mda0 along; data rounds up to
+        // the alignment
+        let layout = compute_layout(1 << 30, 1 << 20, 3 << 20, 8192).unwrap();
+        assert_eq!(layout.mda0.offset, 8192);
+        assert_eq!(layout.data.offset, 3 << 20);
+
+        // A device size that isn't a multiple of the sector size
+        let layout = compute_layout((1 << 30) + 100, 1 << 20, 1 << 20, 4096).unwrap();
+        assert_eq!(layout.mda1.offset, (1 << 30) - (1 << 20));
+
+        assert!(compute_layout(1 << 30, 1 << 20, 0, 512).is_err());
+        assert!(compute_layout(1 << 30, 1 << 20, 1000, 512).is_err());
+        assert!(compute_layout(1 << 30, 1000, 1 << 20, 512).is_err());
+        assert!(compute_layout(1 << 30, 4608, 1 << 20, 512).is_err());
+        assert!(compute_layout(2 << 20, 1 << 20, 1 << 20, 512).is_err());
+    }
+
+    #[test]
+    fn layout_validation() {
+        let image = TestImage::new(16 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+        pvh.validate_layout().unwrap();
+        let mda0 = pvh.metadata_areas[0];
+
+        let mut bad = pvh.clone();
+        bad.metadata_areas[0].size = 17 << 20;
+        assert!(bad.validate_layout().is_err());
+
+        let mut bad = pvh.clone();
+        bad.bootloader_areas.push(PvArea {
+            offset: mda0.offset + mda0.size - 512,
+            size: 4096,
+        });
+        assert!(bad.validate_layout().is_err());
+
+        // A data area of size 0 reaches the end of the device
+        let mut bad = pvh.clone();
+        bad.data_areas[0].size = 0;
+        bad.bootloader_areas.push(PvArea {
+            offset: 8 << 20,
+            size: 4096,
+        });
+        assert!(bad.validate_layout().is_err());
+    }
+}
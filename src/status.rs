@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Typed status values for VGs and LVs.
+
+use std::collections::BTreeSet;
+
+/// A status value that has a textual representation in LVM metadata.
+pub trait StatusFlag: Ord + Copy {
+    /// The text used for this value in metadata.
+    fn as_str(&self) -> &'static str;
+    /// Parse a value from its text in metadata, if known.
+    fn from_status_str(s: &str) -> Option<Self>;
+}
+
+/// The status values a VG may have.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum VgStatus {
+    /// LVs may be read.
+    Read,
+    /// Metadata and LVs may be written.
+    Write,
+    /// PVs may be added or removed.
+    Resizeable,
+    /// The VG has been exported, and may not be used until imported.
+    Exported,
+    /// The VG is shared by a cluster.
+    Clustered,
+    /// The VG is shared by multiple hosts.
+    Shared,
+}
+
+impl StatusFlag for VgStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            VgStatus::Read => "READ",
+            VgStatus::Write => "WRITE",
+            VgStatus::Resizeable => "RESIZEABLE",
+            VgStatus::Exported => "EXPORTED",
+            VgStatus::Clustered => "CLUSTERED",
+            VgStatus::Shared => "SHARED",
+        }
+    }
+
+    fn from_status_str(s: &str) -> Option<VgStatus> {
+        match s {
+            "READ" => Some(VgStatus::Read),
+            "WRITE" => Some(VgStatus::Write),
+            "RESIZEABLE" => Some(VgStatus::Resizeable),
+            "EXPORTED" => Some(VgStatus::Exported),
+            "CLUSTERED" => Some(VgStatus::Clustered),
+            "SHARED" => Some(VgStatus::Shared),
+            _ => None,
+        }
+    }
+}
+
+/// The status values an LV may have.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum LvStatus {
+    /// The LV may be read.
+    Read,
+    /// The LV may be written.
+    Write,
+    /// The LV is visible to the user, as opposed to an internal LV such
+    /// as a thin pool's metadata.
+    Visible,
+    /// The LV's minor number is fixed.
+    FixedMinor,
+    /// The LV is locked, e.g. while a pvmove is in progress.
+    Locked,
+    /// The LV is a temporary LV used by pvmove.
+    Pvmove,
+}
+
+impl StatusFlag for LvStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            LvStatus::Read => "READ",
+            LvStatus::Write => "WRITE",
+            LvStatus::Visible => "VISIBLE",
+            LvStatus::FixedMinor => "FIXED_MINOR",
+            LvStatus::Locked => "LOCKED",
+            LvStatus::Pvmove => "PVMOVE",
+        }
+    }
+
+    fn from_status_str(s: &str) -> Option<LvStatus> {
+        match s {
+            "READ" => Some(LvStatus::Read),
+            "WRITE" => Some(LvStatus::Write),
+            "VISIBLE" => Some(LvStatus::Visible),
+            "FIXED_MINOR" => Some(LvStatus::FixedMinor),
+            "LOCKED" => Some(LvStatus::Locked),
+            "PVMOVE" => Some(LvStatus::Pvmove),
+            _ => None,
+        }
+    }
+}
+
+/// A set of status values. Values that are not understood are kept as
+/// strings, so they are not lost when the status is written back out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatusSet<T: StatusFlag> {
+    /// The recognized values.
+    pub known: BTreeSet<T>,
+    /// Any other values, in their original order.
+    pub unknown: Vec<String>,
+}
+
+impl<T: StatusFlag> StatusSet<T> {
+    /// Parse a status list from metadata.
+    pub fn from_strings(strings: &[String]) -> StatusSet<T> {
+        let mut known = BTreeSet::new();
+        let mut unknown = Vec::new();
+
+        for s in strings {
+            match T::from_status_str(s) {
+                Some(x) => {
+                    known.insert(x);
+                }
+                None => unknown.push(s.clone()),
+            }
+        }
+
+        StatusSet {
+            known: known,
+            unknown: unknown,
+        }
+    }
+
+    /// Convert back to a status list for metadata: recognized values
+    /// first, then any unknown ones.
+    pub fn to_strings(&self) -> Vec<String> {
+        self.known
+            .iter()
+            .map(|x| x.as_str().to_string())
+            .chain(self.unknown.iter().cloned())
+            .collect()
+    }
+
+    /// Whether the set contains the given value.
+    pub fn contains(&self, flag: T) -> bool {
+        self.known.contains(&flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn unknown_values_are_kept() {
+        let set: StatusSet<LvStatus> =
+            StatusSet::from_strings(&strings(&["NEW_FLAG", "WRITE", "READ", "OTHER"]));
+
+        assert!(set.contains(LvStatus::Read));
+        assert!(set.contains(LvStatus::Write));
+        assert!(!set.contains(LvStatus::Visible));
+        assert_eq!(set.unknown, strings(&["NEW_FLAG", "OTHER"]));
+        assert_eq!(
+            set.to_strings(),
+            strings(&["READ", "WRITE", "NEW_FLAG", "OTHER"])
+        );
+        assert_eq!(StatusSet::from_strings(&set.to_strings()), set);
+    }
+
+    #[test]
+    fn every_value_round_trips() {
+        let vg = [
+            VgStatus::Read,
+            VgStatus::Write,
+            VgStatus::Resizeable,
+            VgStatus::Exported,
+            VgStatus::Clustered,
+            VgStatus::Shared,
+        ];
+        for x in &vg {
+            assert_eq!(VgStatus::from_status_str(x.as_str()), Some(*x));
+        }
+
+        let lv = [
+            LvStatus::Read,
+            LvStatus::Write,
+            LvStatus::Visible,
+            LvStatus::FixedMinor,
+            LvStatus::Locked,
+            LvStatus::Pvmove,
+        ];
+        for x in &lv {
+            assert_eq!(LvStatus::from_status_str(x.as_str()), Some(*x));
+        }
+
+        // LV-only values aren't VG status
+        assert_eq!(VgStatus::from_status_str("VISIBLE"), None);
+    }
+}
@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Helpers shared by the unit tests.
+
+use std::env::temp_dir;
+use std::fs::{create_dir, remove_dir_all, remove_file, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_IMAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// A sparse file standing in for a device, removed when dropped.
+pub struct TestImage {
+    path: PathBuf,
+}
+
+// A path in the temp directory, unique to this test run.
+fn temp_path(suffix: &str) -> PathBuf {
+    temp_dir().join(format!(
+        "melvin-test-{}-{}{}",
+        process::id(),
+        NEXT_IMAGE.fetch_add(1, Ordering::SeqCst),
+        suffix
+    ))
+}
+
+impl TestImage {
+    /// Create a zeroed image of size bytes.
+    pub fn new(size: u64) -> TestImage {
+        TestImage::new_at(temp_path(".img"), size)
+    }
+
+    /// Create a zeroed image of size bytes at the given path.
+    pub fn new_at(path: PathBuf, size: u64) -> TestImage {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .expect("create test image");
+        f.set_len(size).expect("size test image");
+
+        TestImage { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Grow or shrink the image to size bytes.
+    pub fn set_len(&self, size: u64) {
+        let f = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .expect("open test image");
+        f.set_len(size).expect("size test image");
+    }
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// An empty directory, removed with its contents when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        let path = temp_path("");
+        create_dir(&path).expect("create test directory");
+        TestDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.path);
+    }
+}
+
+/// Whether tests that need root and device-mapper, to set up loop
+/// devices and activate LVs, can run. Those tests pass trivially if not.
+pub fn can_use_dm() -> bool {
+    let root = unsafe { libc::geteuid() } == 0;
+    root && Path::new("/dev/mapper/control").exists()
+}
+
+/// Whether tests that need root to set up loop devices, but not
+/// device-mapper, can run. Those tests pass trivially if not.
+pub fn can_use_loop() -> bool {
+    let root = unsafe { libc::geteuid() } == 0;
+    root && Path::new("/dev/loop-control").exists()
+}
+
+/// A loop device over a new TestImage, detached when dropped.
+pub struct LoopDevice {
+    path: PathBuf,
+    image: TestImage,
+}
+
+impl LoopDevice {
+    pub fn new(size: u64) -> LoopDevice {
+        LoopDevice::with_sector_size(size, 512)
+    }
+
+    /// Create a loop device with the given logical sector size, e.g.
+    /// 4096 to stand in for a 4K-native disk.
+    pub fn with_sector_size(size: u64, sector_size: u64) -> LoopDevice {
+        let image = TestImage::new(size);
+        let output = Command::new("losetup")
+            .arg("--find")
+            .arg("--show")
+            .arg("--sector-size")
+            .arg(sector_size.to_string())
+            .arg(image.path())
+            .output()
+            .expect("run losetup");
+        assert!(output.status.success(), "losetup failed");
+
+        LoopDevice {
+            path: PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()),
+            image: image,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Grow or shrink the backing image to size bytes, and have the
+    /// loop device pick up the new size.
+    pub fn set_len(&self, size: u64) {
+        self.image.set_len(size);
+        let status = Command::new("losetup")
+            .arg("--set-capacity")
+            .arg(&self.path)
+            .status()
+            .expect("run losetup");
+        assert!(status.success(), "losetup --set-capacity failed");
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup").arg("-d").arg(&self.path).status();
+    }
+}
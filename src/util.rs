@@ -2,13 +2,30 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::io;
+use std::io::ErrorKind::Other;
+
 use crc::crc32;
+use nix::sys::utsname::uname;
 use uuid::Uuid;
 
+use crate::{Error, Result};
+
 const INITIAL_CRC: u32 = 0xf597a6cf;
 const CRC_SEED: u32 = 0xedb88320;
+const MAX_NAME_LEN: usize = 127;
+const MAX_TAG_LEN: usize = 1024;
+
+// LVM uses these in the names of the hidden LVs that make up e.g. thin
+// pools and RAID LVs, so user-created LVs may not.
+const RESERVED_LV_PREFIXES: &'static [&'static str] = &["pvmove", "snapshot"];
+const RESERVED_LV_SUBSTRINGS: &'static [&'static str] = &[
+    "_cdata", "_cmeta", "_corig", "_mimage", "_mlog", "_pmspare", "_rimage", "_rmeta", "_tdata",
+    "_tmeta", "_vorigin",
+];
 
-pub fn align_to(num: usize, align_to: usize) -> usize {
+// u64 so that device offsets are not truncated on 32-bit targets
+pub fn align_to(num: u64, align_to: u64) -> u64 {
     let agn = align_to - 1;
 
     (num + agn) & !agn
@@ -42,3 +59,211 @@ pub fn hyphenate_uuid(uuid: &[u8]) -> String {
         String::from_utf8_lossy(&uuid[26..32])
     )
 }
+
+// Whether s is in the form of a hyphenated LVM uuid, as opposed to,
+// say, a VG name.
+pub fn is_lvm_uuid(s: &str) -> bool {
+    let hyphens = [6, 11, 16, 21, 26, 31];
+
+    s.len() == 38
+        && s.bytes().enumerate().all(|(i, c)| {
+            if hyphens.contains(&i) {
+                c == b'-'
+            } else {
+                c.is_ascii_alphanumeric()
+            }
+        })
+}
+
+const SIZE_UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// Format a size in bytes for people to read, in the largest binary
+/// unit it has at least one of, with two decimal places, e.g.
+/// "4.00 GiB" or "512.00 MiB". Sizes under 1KiB are given exactly, e.g.
+/// "512 B".
+pub fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut unit = 0;
+    let mut value = bytes as f64;
+    while value >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    // e.g. 1023.999 MiB would print as "1024.00 MiB"
+    if (value * 100.0).round() >= 102400.0 && unit < SIZE_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.2} {}", value, SIZE_UNITS[unit])
+}
+
+/// Parse a size such as "4G", "4GiB", "1.5t" or "512", returning it in
+/// bytes. Units are binary, as in LVM: K, M, G, T, P and E, in either
+/// case and optionally followed by "iB". A size with no unit, or with
+/// "B", is in bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let err = || Error::Parse(format!("Invalid size \"{}\"", s));
+
+    let s = s.trim();
+    let num_len = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (num, suffix) = s.split_at(num_len);
+    let suffix = suffix.trim_start().to_ascii_lowercase();
+
+    let value: f64 = num.parse().map_err(|_| err())?;
+
+    let unit = match suffix.as_str() {
+        "" | "b" => 0,
+        _ => {
+            let mut chars = suffix.chars();
+            let letter = chars.next().map(|c| c.to_ascii_uppercase());
+            let rest = chars.as_str();
+            if rest != "" && rest != "ib" {
+                return Err(err());
+            }
+            SIZE_UNITS
+                .iter()
+                .skip(1)
+                .position(|u| u.chars().next() == letter)
+                .map(|x| x + 1)
+                .ok_or_else(err)?
+        }
+    };
+
+    let bytes = (value * 1024f64.powi(unit as i32)).round();
+    if bytes >= u64::max_value() as f64 {
+        return Err(err());
+    }
+
+    Ok(bytes as u64)
+}
+
+// The name of this host, or None if it could not be determined.
+pub fn hostname() -> Option<String> {
+    let name = uname().nodename().trim().to_string();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// The kinds of things LVM names, which have slightly different rules.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NameKind {
+    /// A Volume Group.
+    Vg,
+    /// A Logical Volume.
+    Lv,
+    /// A tag on a VG, LV, or PV.
+    Tag,
+}
+
+/// Check that a name follows LVM's rules for the given kind of name:
+/// only certain characters, not too long, not starting with a hyphen,
+/// and for LVs, not a name LVM reserves for its own use.
+pub fn is_valid_lvm_name(name: &str, kind: NameKind) -> Result<()> {
+    let err = |msg: String| Err(Error::Io(io::Error::new(Other, msg)));
+
+    let max_len = match kind {
+        NameKind::Tag => MAX_TAG_LEN,
+        NameKind::Vg | NameKind::Lv => MAX_NAME_LEN,
+    };
+
+    if name.is_empty() {
+        return err("Name may not be empty".to_string());
+    }
+
+    if name.len() > max_len {
+        return err(format!("Name may be at most {} characters", max_len));
+    }
+
+    if name.starts_with('-') {
+        return err(format!("Name {} may not start with a hyphen", name));
+    }
+
+    if kind != NameKind::Tag && (name == "." || name == "..") {
+        return err(format!("Name {} is not allowed", name));
+    }
+
+    let valid_char = |c: char| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '+' | '_' | '.' | '-' => true,
+        '/' | '=' | '!' | ':' | '&' | '#' => kind == NameKind::Tag,
+        _ => false,
+    };
+    if let Some(c) = name.chars().find(|c| !valid_char(*c)) {
+        return err(format!("Name {} may not contain {:?}", name, c));
+    }
+
+    if kind == NameKind::Lv {
+        if let Some(prefix) = RESERVED_LV_PREFIXES.iter().find(|x| name.starts_with(*x)) {
+            return err(format!("LV name {} may not start with {}", name, prefix));
+        }
+        if let Some(sub) = RESERVED_LV_SUBSTRINGS.iter().find(|x| name.contains(*x)) {
+            return err(format!("LV name {} may not contain {}", name, sub));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lvm_names() {
+        assert!(is_valid_lvm_name("vg0", NameKind::Vg).is_ok());
+        assert!(is_valid_lvm_name("my-lv_1.+", NameKind::Lv).is_ok());
+        assert!(is_valid_lvm_name(&"a".repeat(MAX_NAME_LEN), NameKind::Vg).is_ok());
+
+        assert!(is_valid_lvm_name("", NameKind::Vg).is_err());
+        assert!(is_valid_lvm_name(&"a".repeat(MAX_NAME_LEN + 1), NameKind::Vg).is_err());
+        assert!(is_valid_lvm_name("-vg", NameKind::Vg).is_err());
+        assert!(is_valid_lvm_name("..", NameKind::Lv).is_err());
+        assert!(is_valid_lvm_name("vg 0", NameKind::Vg).is_err());
+        assert!(is_valid_lvm_name("a/b", NameKind::Vg).is_err());
+
+        // Reserved for LVM's hidden LVs, but fine for VGs
+        assert!(is_valid_lvm_name("snapshot1", NameKind::Lv).is_err());
+        assert!(is_valid_lvm_name("pool_tmeta", NameKind::Lv).is_err());
+        assert!(is_valid_lvm_name("pool_tmeta", NameKind::Vg).is_ok());
+
+        // Tags allow more characters, and longer names
+        assert!(is_valid_lvm_name("site=a/b:c", NameKind::Tag).is_ok());
+        assert!(is_valid_lvm_name("..", NameKind::Tag).is_ok());
+        assert!(is_valid_lvm_name(&"a".repeat(MAX_NAME_LEN + 1), NameKind::Tag).is_ok());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.00 KiB");
+        assert_eq!(format_size(1536 << 20), "1.50 GiB");
+        assert_eq!(format_size((1 << 20) - 1), "1.00 MiB");
+        assert_eq!(format_size(u64::max_value()), "16.00 EiB");
+
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("8B").unwrap(), 8);
+        assert_eq!(parse_size("4G").unwrap(), 4 << 30);
+        assert_eq!(parse_size(" 2 MiB ").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1.5t").unwrap(), 3 << 39);
+        assert_eq!(parse_size("15E").unwrap(), 15 << 60);
+
+        for bad in &["", "G", "-1", "1.2.3", "4X", "4Gb", "16E"] {
+            assert!(parse_size(bad).is_err(), "{}", bad);
+        }
+
+        for &size in &[0, 512, 4 << 30, 1536 << 20, 5 << 50] {
+            assert_eq!(parse_size(&format_size(size)).unwrap(), size);
+        }
+    }
+}
@@ -5,35 +5,145 @@
 //! Volume Groups
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_dir, File, OpenOptions};
 use std::io;
 use std::io::ErrorKind::Other;
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem::replace;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use devicemapper::{
-    DevId, Device, DmFlags, DmName, DmOptions, LinearDev, LinearDevTargetParams,
-    LinearTargetParams, Sectors, TargetLine, DM,
-};
-use nix::sys::utsname::uname;
+use devicemapper::{DevId, Device, DmName, DmOptions, DM};
+use rustc_serialize::json::{Json, ToJson};
 use time::now;
 
+use crate::dm;
 use crate::lv;
 use crate::lv::segment;
+use crate::lv::segment::SegmentType;
 use crate::lv::LV;
-use crate::parser::{status_from_textmap, Entry, LvmTextMap, TextMapOps};
+use crate::lvmetad;
+use crate::parser::{
+    buf_to_textmap, diff_textmaps, status_from_textmap, strings_from_textmap, textmap_to_buf,
+    Entry, LvmTextMap, MapDiff, TextMapOps,
+};
 use crate::pv;
 use crate::pv::PV;
-use crate::pvlabel::{PvHeader, SECTOR_SIZE};
-use crate::util::{align_to, make_uuid};
+use crate::pvlabel::{vg_seqno, PvHeader, WriteOptions, SECTOR_SIZE};
+use crate::status::{LvStatus, StatusSet, VgStatus};
+use crate::util::{align_to, hostname, is_valid_lvm_name, make_uuid, NameKind};
 use crate::{Error, Result};
 
 const DEFAULT_EXTENT_SIZE: u64 = 8192; // 4MiB
 const DEFAULT_THINPOOL_CHUNK_SIZE: u64 = 128; // 64KiB
+const DEFAULT_CREATION_HOST: &'static str = "unknown";
+const METADATA_FORMAT: &'static str = "lvm2";
+
+/// An overview of a VG's size and contents.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VgSummary {
+    /// The number of PVs in the VG.
+    pub pv_count: usize,
+    /// The number of LVs in the VG.
+    pub lv_count: usize,
+    /// How many 512-byte sectors make up each extent.
+    pub extent_size: u64,
+    /// The total number of extents.
+    pub extents: u64,
+    /// The number of extents not used by any LV.
+    pub extents_free: u64,
+}
+
+/// A PV whose extent count in the VG metadata does not match the
+/// capacity of its underlying device.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SizeDiscrepancy {
+    /// The device the PV is on.
+    pub device: Device,
+    /// The number of extents recorded in the metadata.
+    pub recorded_pe_count: u64,
+    /// The number of extents that fit on the device as it is now.
+    pub actual_pe_count: u64,
+}
+
+impl SizeDiscrepancy {
+    /// Whether the device is now too small to hold all the extents
+    /// recorded in the metadata.
+    pub fn shrunk(&self) -> bool {
+        self.actual_pe_count < self.recorded_pe_count
+    }
+}
+
+/// How to choose free extents for new LVs, as LVM's allocation
+/// policies do.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AllocPolicy {
+    /// Use a single free area, or fail. An LV being extended must grow
+    /// into the extents directly after its last one.
+    Contiguous,
+    /// Place new extents only on PVs the LV already uses. A new LV uses
+    /// no PVs yet, so this is the same as Normal for it.
+    Cling,
+    /// Prefer a single free area, on the PVs an LV already uses if it is
+    /// being extended, but use several, on any PV, if need be. Parallel
+    /// stripes are kept on different PVs.
+    Normal,
+    /// Use free areas in order, however fragmented, even placing
+    /// parallel stripes on the same PV.
+    Anywhere,
+}
+
+impl Default for AllocPolicy {
+    fn default() -> AllocPolicy {
+        AllocPolicy::Normal
+    }
+}
+
+impl FromStr for AllocPolicy {
+    type Err = Error;
+
+    /// Parse an "allocation_policy" value from LVM metadata. Tags aren't
+    /// considered, so "cling_by_tags" is the same as "cling".
+    fn from_str(s: &str) -> Result<AllocPolicy> {
+        match s {
+            "contiguous" => Ok(AllocPolicy::Contiguous),
+            "cling" | "cling_by_tags" => Ok(AllocPolicy::Cling),
+            "normal" => Ok(AllocPolicy::Normal),
+            "anywhere" => Ok(AllocPolicy::Anywhere),
+            _ => Err(Error::Parse(format!("unknown allocation policy \"{}\"", s))),
+        }
+    }
+}
+
+/// Restrictions on where new LVs may be allocated.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct AllocConstraints {
+    /// PVs that must not be used, e.g. because they are being evacuated.
+    pub exclude_pvs: Vec<Device>,
+    /// How to choose among the free areas. If None, the LV's allocation
+    /// policy is used, or else the VG's, or else Normal.
+    pub policy: Option<AllocPolicy>,
+}
+
+/// How many of a VG's PVs are present.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VgAvailability {
+    /// All of the VG's PVs are present.
+    Complete,
+    /// Some, but not all, of the VG's PVs are present. Only LVs entirely
+    /// on the present PVs can be activated.
+    Partial,
+    /// None of the VG's PVs are present.
+    Unavailable,
+}
 
 /// A Volume Group allows multiple Physical Volumes to be treated as a
 /// storage pool that can then be used to allocate Logical Volumes.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct VG {
     /// Name.
     name: String,
@@ -47,6 +157,8 @@ pub struct VG {
     status: Vec<String>,
     /// Flags.
     flags: Vec<String>,
+    /// Tags.
+    tags: Vec<String>,
     /// Size of each extent, in 512-byte sectors.
     extent_size: u64,
     /// Maximum number of LVs, 0 means no limit.
@@ -55,15 +167,111 @@ pub struct VG {
     max_pv: u64,
     /// How many metadata copies (?)
     metadata_copies: u64,
+    /// The allocation policy for new LVs, if not the default.
+    alloc_policy: Option<String>,
     /// Physical Volumes within this volume group.
     pvs: BTreeMap<Device, PV>,
     /// Logical Volumes within this volume group.
     lvs: BTreeMap<String, LV>,
+    /// Extents held by outstanding Reservations.
+    reserved: ReservedAreas,
+    /// Whether to wipe signatures from newly created LVs.
+    wipe_signatures: bool,
+    /// The host name to record in metadata, instead of looking it up.
+    creation_host: Option<String>,
+    /// Where to archive the previous metadata before each commit.
+    archive_dir: Option<PathBuf>,
+    /// Whether a Transaction is in progress, so commits are deferred.
+    in_transaction: bool,
+    /// LVs created during a Transaction, to be activated once it is
+    /// committed.
+    pending_activations: Vec<String>,
+    /// Why lvmetad could not be told about the last commit, if it could
+    /// not.
+    lvmetad_error: Option<String>,
+}
+
+// Areas set aside by Reservations, in the format: {Device: {start: len} }
+//
+// This is shared with each Reservation so it can release its area when
+// dropped. It only exists in memory, so it is not considered when
+// comparing VGs.
+#[derive(Debug, Clone, Default)]
+struct ReservedAreas(Arc<Mutex<BTreeMap<Device, BTreeMap<u64, u64>>>>);
+
+impl PartialEq for ReservedAreas {
+    fn eq(&self, _other: &ReservedAreas) -> bool {
+        true
+    }
+}
+
+/// A contiguous range of extents set aside for a new LV.
+///
+/// While a Reservation exists, its extents are not considered free by
+/// the VG it came from, so they will not be handed out again. Pass it to
+/// `VG::lv_create_reserved()` to create an LV on the extents, or drop it
+/// to release them.
+#[derive(Debug)]
+pub struct Reservation {
+    reserved: ReservedAreas,
+    device: Device,
+    start: u64,
+    extents: u64,
+}
+
+impl Reservation {
+    /// The PV the reserved extents are on.
+    pub fn device(&self) -> Device {
+        self.device
+    }
+
+    /// The first reserved extent on the PV.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// How many extents are reserved.
+    pub fn extents(&self) -> u64 {
+        self.extents
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Ok(mut reserved) = (self.reserved.0).lock() {
+            let now_empty = match reserved.get_mut(&self.device) {
+                Some(areas) => {
+                    areas.remove(&self.start);
+                    areas.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                reserved.remove(&self.device);
+            }
+        }
+    }
 }
 
 impl VG {
     /// Create a Volume Group from one or more PVs.
     pub fn create(name: &str, pv_paths: Vec<&Path>) -> Result<VG> {
+        VG::create_check(name, &pv_paths)?;
+
+        let mut vg = VgBuilder::new(name).build();
+
+        for path in &pv_paths {
+            vg.pv_add(path)?;
+        }
+
+        Ok(vg)
+    }
+
+    /// Check whether `create()` would succeed with the given PVs, without
+    /// writing anything.
+    pub fn create_check(name: &str, pv_paths: &[&Path]) -> Result<()> {
+        is_valid_lvm_name(name, NameKind::Vg)?;
+
         if pv_paths.len() == 0 {
             return Err(Error::Io(io::Error::new(
                 Other,
@@ -73,7 +281,7 @@ impl VG {
 
         let pvhs = {
             let mut v = Vec::new();
-            for path in &pv_paths {
+            for path in pv_paths {
                 v.push(PvHeader::find_in_dev(path)?);
             }
             v
@@ -87,30 +295,31 @@ impl VG {
             )));
         }
 
-        let mut vg = VG {
-            name: name.to_string(),
-            id: make_uuid(),
-            seqno: 0,
-            format: "lvm2".to_string(),
-            status: vec![
-                "READ".to_string(),
-                "WRITE".to_string(),
-                "RESIZEABLE".to_string(),
-            ],
-            flags: Vec::new(),
-            extent_size: DEFAULT_EXTENT_SIZE,
-            max_lv: 0,
-            max_pv: 0,
-            metadata_copies: 0,
-            pvs: BTreeMap::new(),
-            lvs: BTreeMap::new(),
-        };
+        // Lay out each PV in a VG that only exists in memory
+        let vg = VgBuilder::new(name).build();
+        let mut devs = BTreeSet::new();
 
-        for path in &pv_paths {
-            vg.pv_add(path)?;
+        for (path, pvh) in pv_paths.iter().zip(&pvhs) {
+            check_pv_unused(pvh)?;
+
+            let dev = Device::from_str(&path.to_string_lossy())?;
+            if !devs.insert(dev) {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("{} given more than once", path.display()),
+                )));
+            }
+
+            let (_, pe_count) = vg.extent_layout(pvh, pvh.size)?;
+            if pe_count == 0 {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("{} is too small to hold any extents", path.display()),
+                )));
+            }
         }
 
-        Ok(vg)
+        Ok(())
     }
 
     /// Construct a `VG` from its name and an `LvmTextMap`.
@@ -120,6 +329,13 @@ impl VG {
         let id = map.string_from_textmap("id").ok_or(err())?;
         let seqno = map.i64_from_textmap("seqno").ok_or(err())?;
         let format = map.string_from_textmap("format").ok_or(err())?;
+        // Other formats, e.g. "lvm1", are laid out differently on disk
+        if format != METADATA_FORMAT {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("Unsupported metadata format {}", format),
+            )));
+        }
         let extent_size = map.i64_from_textmap("extent_size").ok_or(err())?;
         let max_lv = map.i64_from_textmap("max_lv").ok_or(err())?;
         let max_pv = map.i64_from_textmap("max_pv").ok_or(err())?;
@@ -137,6 +353,8 @@ impl VG {
             })
             .collect();
 
+        let tags = strings_from_textmap(map, "tags");
+
         // While the textmap uses "pv0"-style names to link physical
         // volume definitions with LV segment stripes, we do not want to
         // use these internally, because what if "pv0" is unused and is
@@ -200,12 +418,23 @@ impl VG {
             format: format.to_string(),
             status: status,
             flags: flags,
+            tags: tags,
             extent_size: extent_size as u64,
             max_lv: max_lv as u64,
             max_pv: max_pv as u64,
             metadata_copies: metadata_copies as u64,
+            alloc_policy: map
+                .string_from_textmap("allocation_policy")
+                .map(|x| x.to_string()),
             pvs: pvs,
             lvs: lvs,
+            reserved: ReservedAreas::default(),
+            wipe_signatures: false,
+            creation_host: None,
+            archive_dir: None,
+            in_transaction: false,
+            pending_activations: Vec::new(),
+            lvmetad_error: None,
         };
 
         // let dm_devices = {
@@ -227,6 +456,222 @@ impl VG {
         Ok(vg)
     }
 
+    /// Construct a `VG` from JSON, as produced by its `ToJson` impl.
+    ///
+    /// This allows a VG to be defined declaratively and then written to
+    /// its PVs with `commit`. Byte sizes, if present, must agree with
+    /// the extent size, and every segment must fit on its PVs.
+    pub fn from_json(json: &str) -> Result<VG> {
+        let err = |msg: &str| Error::Parse(format!("VG JSON: {}", msg));
+
+        let json = Json::from_str(json).map_err(|e| Error::Parse(e.to_string()))?;
+
+        let u64_field = |json: &Json, name: &str| {
+            json.find(name)
+                .and_then(|x| x.as_u64())
+                .ok_or_else(|| err(&format!("missing or invalid {}", name)))
+        };
+        let string_field = |json: &Json, name: &str| {
+            json.find(name)
+                .and_then(|x| x.as_string())
+                .map(|x| x.to_string())
+                .ok_or_else(|| err(&format!("missing or invalid {}", name)))
+        };
+        let strings_field = |json: &Json, name: &str| -> Result<Vec<String>> {
+            match json.find(name) {
+                Some(&Json::Array(ref items)) => items
+                    .iter()
+                    .map(|x| {
+                        x.as_string()
+                            .map(|x| x.to_string())
+                            .ok_or_else(|| err(&format!("invalid {}", name)))
+                    })
+                    .collect(),
+                None => Ok(Vec::new()),
+                _ => Err(err(&format!("invalid {}", name))),
+            }
+        };
+        let array_field = |json: &Json, name: &str| -> Result<Vec<Json>> {
+            match json.find(name) {
+                Some(&Json::Array(ref items)) => Ok(items.clone()),
+                None => Ok(Vec::new()),
+                _ => Err(err(&format!("invalid {}", name))),
+            }
+        };
+
+        let extent_size = u64_field(&json, "extent_size")?;
+        if extent_size == 0 {
+            return Err(err("extent_size must not be 0"));
+        }
+        let extent_bytes = extent_size * SECTOR_SIZE as u64;
+        if let Some(bytes) = json.find("extent_size_bytes").and_then(|x| x.as_u64()) {
+            if bytes != extent_bytes {
+                return Err(err("extent_size_bytes does not match extent_size"));
+            }
+        }
+
+        let mut pvs = BTreeMap::new();
+        for pv_json in array_field(&json, "pvs")? {
+            let device = string_field(&pv_json, "device")?;
+            let device = Device::from_str(&device)
+                .map_err(|_| err(&format!("invalid PV device {}", device)))?;
+
+            let pv = PV {
+                id: string_field(&pv_json, "id")?,
+                device: device,
+                status: strings_field(&pv_json, "status")?,
+                flags: strings_field(&pv_json, "flags")?,
+                tags: strings_field(&pv_json, "tags")?,
+                dev_size: u64_field(&pv_json, "dev_size")?,
+                pe_start: u64_field(&pv_json, "pe_start")?,
+                pe_count: u64_field(&pv_json, "pe_count")?,
+            };
+
+            if let Some(bytes) = pv_json.find("pe_count_bytes").and_then(|x| x.as_u64()) {
+                if bytes != pv.pe_count * extent_bytes {
+                    return Err(err(&format!(
+                        "PV {} pe_count_bytes does not match extent_size",
+                        pv.id
+                    )));
+                }
+            }
+
+            if pvs.insert(device, pv).is_some() {
+                return Err(err(&format!("PV device {} listed twice", device)));
+            }
+        }
+
+        let mut lvs = BTreeMap::new();
+        for lv_json in array_field(&json, "lvs")? {
+            let name = string_field(&lv_json, "name")?;
+
+            let mut segments = Vec::new();
+            for seg_json in array_field(&lv_json, "segments")? {
+                let seg = segment::from_json(&seg_json)?;
+                if let Some(bytes) = seg_json.find("extent_count_bytes").and_then(|x| x.as_u64()) {
+                    if bytes != seg.extent_count() * extent_bytes {
+                        return Err(err(&format!(
+                            "LV {} segment extent_count_bytes does not match extent_size",
+                            name
+                        )));
+                    }
+                }
+                segments.push(seg);
+            }
+
+            let lv = LV {
+                name: name.clone(),
+                id: string_field(&lv_json, "id")?,
+                status: strings_field(&lv_json, "status")?,
+                flags: strings_field(&lv_json, "flags")?,
+                tags: strings_field(&lv_json, "tags")?,
+                creation_host: string_field(&lv_json, "creation_host")?,
+                creation_time: lv_json
+                    .find("creation_time")
+                    .and_then(|x| x.as_i64())
+                    .ok_or_else(|| err("missing or invalid creation_time"))?,
+                alloc_policy: lv_json
+                    .find("allocation_policy")
+                    .and_then(|x| x.as_string())
+                    .map(|x| x.to_string()),
+                segments: segments,
+                device: None,
+            };
+
+            if let Some(bytes) = lv_json.find("extent_count_bytes").and_then(|x| x.as_u64()) {
+                if bytes != lv.used_extents() * extent_bytes {
+                    return Err(err(&format!(
+                        "LV {} extent_count_bytes does not match extent_size",
+                        name
+                    )));
+                }
+            }
+
+            if lvs.insert(name.clone(), lv).is_some() {
+                return Err(err(&format!("LV {} listed twice", name)));
+            }
+        }
+
+        let format = string_field(&json, "format")?;
+        if format != METADATA_FORMAT {
+            return Err(err(&format!("unsupported metadata format {}", format)));
+        }
+
+        let vg = VG {
+            name: string_field(&json, "name")?,
+            id: string_field(&json, "id")?,
+            seqno: u64_field(&json, "seqno")?,
+            format: format,
+            status: strings_field(&json, "status")?,
+            flags: strings_field(&json, "flags")?,
+            tags: strings_field(&json, "tags")?,
+            extent_size: extent_size,
+            max_lv: u64_field(&json, "max_lv")?,
+            max_pv: u64_field(&json, "max_pv")?,
+            metadata_copies: u64_field(&json, "metadata_copies")?,
+            alloc_policy: json
+                .find("allocation_policy")
+                .and_then(|x| x.as_string())
+                .map(|x| x.to_string()),
+            pvs: pvs,
+            lvs: lvs,
+            reserved: ReservedAreas::default(),
+            wipe_signatures: false,
+            creation_host: None,
+            archive_dir: None,
+            in_transaction: false,
+            pending_activations: Vec::new(),
+            lvmetad_error: None,
+        };
+
+        vg.validate_allocation()?;
+
+        Ok(vg)
+    }
+
+    /// Restore a VG from an archived metadata file, such as one written
+    /// before a commit when an archive directory is set, by writing it to
+    /// the VG's PVs. The restored metadata's seqno is set past that of
+    /// any metadata on the PVs, so it supersedes them.
+    ///
+    /// Each header must be for a PV in the archived VG.
+    pub fn restore(archive_path: &Path, headers: &mut [PvHeader]) -> Result<VG> {
+        let mut vg = VG::restore_from_file(archive_path)?;
+
+        vg.normalize_seqno(headers)?;
+
+        Ok(vg)
+    }
+
+    /// Read a VG from a metadata backup or archive file, such as one
+    /// written by `backup_to_file` or LVM's vgcfgbackup, without writing
+    /// anything to disk. The file's areas are checked to be on the VG's
+    /// PVs and not to overlap.
+    ///
+    /// To make it the VG's metadata on disk, pass it and the headers of
+    /// its PVs to `normalize_seqno`, which writes it with a seqno past
+    /// that of any metadata already there.
+    pub fn restore_from_file(path: &Path) -> Result<VG> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let disk_map = buf_to_textmap(&buf)?;
+
+        // The VG's textmap is the only textmap in the metadata
+        let (name, map) = disk_map
+            .iter()
+            .filter_map(|(key, value)| match value {
+                &Entry::TextMap(ref map) => Some((key, map)),
+                _ => None,
+            })
+            .next()
+            .ok_or_else(|| Error::Io(io::Error::new(Other, "No VG found in metadata file")))?;
+
+        let vg = VG::from_textmap(name, map)?;
+        vg.validate_allocation()?;
+
+        Ok(vg)
+    }
+
     /// Add a non-affiliated PV to this VG.
     pub fn pv_add(&mut self, path: &Path) -> Result<()> {
         let pvh = PvHeader::find_in_dev(path)?;
@@ -244,46 +689,17 @@ impl VG {
         // }
 
         // check pv is not already in the VG or another VG
-        // Does it have text metadata??
-        if let Ok(metadata) = pvh.read_metadata() {
-            // Find the textmap for the vg, among all the other stuff.
-            // (It's the only textmap.)
-            let mut vg_name = Cow::Borrowed("<unknown>");
-            for (key, value) in metadata {
-                match value {
-                    Entry::TextMap(_) => {
-                        vg_name = Cow::Owned(key);
-                        break;
-                    }
-                    _ => {}
-                }
-            }
+        check_pv_unused(&pvh)?;
 
+        let dev_size_sectors = pvh.size / SECTOR_SIZE as u64;
+        let (pe_start_sectors, pe_count) = self.extent_layout(&pvh, pvh.size)?;
+        if pe_count == 0 {
             return Err(Error::Io(io::Error::new(
                 Other,
-                format!("PV already in VG {}", vg_name),
+                "PV is too small to hold an extent",
             )));
         }
 
-        let da = pvh.data_areas.get(0).ok_or(Error::Io(io::Error::new(
-            Other,
-            "Could not find data area in PV",
-        )))?;
-
-        // figure out how many extents fit in the PV's data area
-        // pe_start aligned to extent size
-        let dev_size_sectors = pvh.size / SECTOR_SIZE as u64;
-        let pe_start_sectors = align_to(
-            (da.offset / SECTOR_SIZE as u64) as usize,
-            self.extent_size as usize,
-        ) as u64;
-        let mda1_size_sectors = match pvh.metadata_areas.get(1) {
-            Some(pvarea) => pvarea.size / SECTOR_SIZE as u64,
-            None => 0,
-        };
-        let area_size_sectors = dev_size_sectors - pe_start_sectors - mda1_size_sectors;
-        let pe_count = area_size_sectors / self.extent_size;
-
         // if added PV had no MDAs then we could get this far and then fail
         if self.pvs.contains_key(&dev) {
             Err(Error::Io(io::Error::new(Other, "PV already in VG")))
@@ -295,20 +711,106 @@ impl VG {
                     device: dev,
                     status: vec!["ALLOCATABLE".to_string()],
                     flags: Vec::new(),
+                    tags: Vec::new(),
                     dev_size: dev_size_sectors,
                     pe_start: pe_start_sectors,
                     pe_count: pe_count,
                 },
             );
 
-            self.commit()
+            self.write_changes()
+        }
+    }
+
+    // Figure out where the first extent starts and how many extents fit
+    // in the PV's data area, for a device of dev_size bytes. Returns
+    // (pe_start, pe_count), with pe_start in sectors.
+    fn extent_layout(&self, pvh: &PvHeader, dev_size: u64) -> Result<(u64, u64)> {
+        let da = pvh.data_areas.get(0).ok_or(Error::Io(io::Error::new(
+            Other,
+            "Could not find data area in PV",
+        )))?;
+
+        // pe_start aligned to extent size
+        let dev_size_sectors = dev_size / SECTOR_SIZE as u64;
+        let pe_start_sectors = align_to(da.offset / SECTOR_SIZE as u64, self.extent_size);
+        let mda1_size_sectors = match pvh.metadata_areas.get(1) {
+            Some(pvarea) => pvarea.size / SECTOR_SIZE as u64,
+            None => 0,
+        };
+        let area_size_sectors = dev_size_sectors
+            .saturating_sub(pe_start_sectors)
+            .saturating_sub(mda1_size_sectors);
+
+        Ok((pe_start_sectors, area_size_sectors / self.extent_size))
+    }
+
+    /// Grow a PV in the VG to the current size of its device, and add
+    /// the extents that now fit to the VG, like pvresize.
+    pub fn pv_resize(&mut self, pvh: &mut PvHeader) -> Result<()> {
+        let dev = self
+            .pvs
+            .values()
+            .find(|pv| pv.id == pvh.uuid)
+            .map(|pv| pv.device)
+            .ok_or_else(|| Error::NotFound(format!("PV {} in VG {}", pvh.uuid, self.name)))?;
+
+        pvh.resize()?;
+
+        let (_, pe_count) = self.extent_layout(pvh, pvh.size)?;
+        let pv = self.pvs.get_mut(&dev).expect("found above");
+        if pe_count <= pv.pe_count {
+            return Ok(());
+        }
+        pv.dev_size = pvh.size / SECTOR_SIZE as u64;
+        pv.pe_count = pe_count;
+
+        self.write_changes()
+    }
+
+    /// Compare each member PV's extent count against the current size
+    /// of its device, and report any that no longer match, e.g. because
+    /// the underlying storage was resized.
+    ///
+    /// PvHeaders are matched to PVs by UUID. Headers for PVs that are
+    /// not in this VG, or whose device size can't be read, are ignored.
+    pub fn audit_sizes(&self, headers: &[PvHeader]) -> Vec<SizeDiscrepancy> {
+        let mut discrepancies = Vec::new();
+
+        for pvh in headers {
+            let pv = match self.pvs.values().find(|pv| pv.id == pvh.uuid) {
+                Some(pv) => pv,
+                None => continue,
+            };
+
+            let actual_pe_count = match pvh
+                .device_size()
+                .and_then(|size| self.extent_layout(pvh, size))
+            {
+                Ok((_, pe_count)) => pe_count,
+                Err(_) => continue,
+            };
+
+            if actual_pe_count != pv.pe_count {
+                discrepancies.push(SizeDiscrepancy {
+                    device: pv.device,
+                    recorded_pe_count: pv.pe_count,
+                    actual_pe_count: actual_pe_count,
+                });
+            }
         }
+
+        discrepancies
     }
 
     /// Remove a PV. It must be unused by any LVs.
     pub fn pv_remove(&mut self, pvh: &PvHeader) -> Result<()> {
         let dev = Device::from_str(&pvh.dev_path.to_string_lossy())?;
 
+        if self.pv_has_active_lvs(dev)? {
+            return Err(Error::Io(io::Error::from_raw_os_error(libc::EBUSY)));
+        }
+
         for (lvname, lv) in &self.lvs {
             for seg in &lv.segments {
                 for seg_dev in seg.pv_dependencies() {
@@ -322,89 +824,706 @@ impl VG {
             }
         }
 
+        let reserved = (self.reserved.0)
+            .lock()
+            .expect("reservation lock poisoned")
+            .get(&dev)
+            .map_or(false, |areas| !areas.is_empty());
+        if reserved {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "PV has extents held by a Reservation",
+            )));
+        }
+
         self.pvs
             .remove(&dev)
             .ok_or(Error::Io(io::Error::new(Other, "Could not remove PV")))?;
 
-        self.commit()
+        self.write_changes()
     }
 
-    /// Create a new linear logical volume in the volume group.
-    pub fn lv_create_linear(&mut self, name: &str, extent_size: u64) -> Result<()> {
-        if self.lvs.contains_key(name) {
-            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+    /// Whether any LV using the PV on the given device is active, per
+    /// device-mapper. Such a PV must not be removed or wiped, since it
+    /// may be backing e.g. a mounted filesystem.
+    pub fn pv_has_active_lvs(&self, dev: Device) -> Result<bool> {
+        let dm_names = active_dm_names()?;
+
+        Ok(self.lvs.values().any(|lv| {
+            lv.segments
+                .iter()
+                .any(|seg| seg.pv_dependencies().contains(&dev))
+                && dm_names.contains(&lv.dm_name(self))
+        }))
+    }
+
+    /// Make sure the first metadata area on a PV is at least
+    /// `needed_bytes` long, growing it into the start of the data area if
+    /// not. Any LV extents in the way are first moved to free space on
+    /// other PVs, so the LVs using them must be inactive.
+    pub fn ensure_mda_capacity(&mut self, pvh: &mut PvHeader, needed_bytes: u64) -> Result<()> {
+        // Moving data can't be undone by dropping a Transaction
+        if self.in_transaction {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Cannot grow a metadata area during a transaction",
+            )));
         }
 
-        let (dev, area_start, len) = {
-            let mut contig_area = None;
-            for (dev, areas) in self.free_areas() {
-                for (start, len) in areas {
-                    if len >= extent_size {
-                        contig_area = Some((dev, start, len));
-                        break;
-                    }
-                }
-            }
-            if contig_area.is_none() {
+        let dev = Device::from_str(&pvh.dev_path.to_string_lossy())?;
+
+        let mda0 = pvh
+            .metadata_areas
+            .get(0)
+            .ok_or(Error::Io(io::Error::new(Other, "PV has no metadata area")))?
+            .clone();
+        if mda0.size >= needed_bytes {
+            return Ok(());
+        }
+
+        let (pe_start, pe_count) = {
+            let pv = self
+                .pvs
+                .get(&dev)
+                .ok_or(Error::Io(io::Error::new(Other, "PV not found in VG")))?;
+            (pv.pe_start, pv.pe_count)
+        };
+
+        // Keep the data area aligned to the extent size
+        let new_pe_start = align_to(
+            align_to(mda0.offset + needed_bytes, SECTOR_SIZE as u64) / SECTOR_SIZE as u64,
+            self.extent_size,
+        );
+        let shift = (max(new_pe_start, pe_start) - pe_start) / self.extent_size;
+        if shift >= pe_count {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "PV too small for the requested metadata area",
+            )));
+        }
+
+        if shift > 0 {
+            let reserved = (self.reserved.0)
+                .lock()
+                .expect("reservation lock poisoned")
+                .get(&dev)
+                .map_or(false, |areas| areas.keys().any(|start| *start < shift));
+            if reserved {
                 return Err(Error::Io(io::Error::new(
                     Other,
-                    "no contiguous area for new LV",
+                    "Extents needed for the metadata area are reserved",
                 )));
-            } else {
-                contig_area.unwrap()
             }
-        };
 
-        let segment = Box::new(segment::StripedSegment {
-            start_extent: 0,
-            extent_count: extent_size,
-            stripes: vec![(dev, area_start)],
-            stripe_size: None,
-        });
+            self.relocate_extents(dev, shift)?;
+        }
 
-        let params = LinearTargetParams::new(Device::from(u64::from(dev)), Sectors(area_start));
-        let table = vec![TargetLine::new(
-            Sectors(0),
-            Sectors(len),
-            LinearDevTargetParams::Linear(params),
-        )];
+        // The VG as it is now on disk, to go back to if growing the MDA
+        // fails
+        let saved_pvs = self.pvs.clone();
+        let saved_lvs = self.lvs.clone();
 
-        let lv = LV {
-            name: name.to_string(),
-            id: make_uuid(),
-            status: vec![
-                "READ".to_string(),
-                "WRITE".to_string(),
-                "VISIBLE".to_string(),
-            ],
-            flags: Vec::new(),
-            creation_host: uname().nodename().to_string(),
-            creation_time: now().to_timespec().sec,
-            segments: vec![segment],
-            device: None,
-        };
+        let res = self.shift_data_area(pvh, dev, shift, mda0.offset);
+        if res.is_err() {
+            self.pvs = saved_pvs;
+            self.lvs = saved_lvs;
+        }
 
-        let lv_name = format!(
-            "{}-{}",
-            self.name.replace("-", "--"),
-            lv.name.replace("-", "--")
-        );
+        res
+    }
+
+    // Move the start of the data area of the PV on dev shift extents
+    // later, growing its first MDA, which starts at mda0_offset, to
+    // fill the space, and commit. The extents must already be unused.
+    fn shift_data_area(
+        &mut self,
+        pvh: &mut PvHeader,
+        dev: Device,
+        shift: u64,
+        mda0_offset: u64,
+    ) -> Result<()> {
+        if shift > 0 {
+            // Extents are numbered from the new start of the data area
+            for lv in self.lvs.values_mut() {
+                for seg in &mut lv.segments {
+                    seg.remap_areas(&|seg_dev, start| {
+                        if seg_dev == dev {
+                            (seg_dev, start - shift)
+                        } else {
+                            (seg_dev, start)
+                        }
+                    });
+                }
+            }
+
+            let pv = self.pvs.get_mut(&dev).expect("PV checked above");
+            pv.pe_start += shift * self.extent_size;
+            pv.pe_count -= shift;
+        }
+
+        let new_pe_start = self.pvs[&dev].pe_start;
+        pvh.resize_first_mda(new_pe_start * SECTOR_SIZE as u64 - mda0_offset)?;
+
+        self.write_changes()
+    }
+
+    // Move the contents of any LV areas within the first count extents
+    // of the PV on dev to free space on other PVs, and commit. On error,
+    // the LVs are left as they were.
+    fn relocate_extents(&mut self, dev: Device, count: u64) -> Result<()> {
+        let saved_lvs = self.lvs.clone();
+
+        let res = self
+            .move_extents_off(dev, count)
+            .and_then(|_| self.write_changes());
+        if res.is_err() {
+            self.lvs = saved_lvs;
+        }
+
+        res
+    }
+
+    // Copy LV areas within the first count extents of the PV on dev to
+    // free space on other PVs, and point the LVs at the copies.
+    fn move_extents_off(&mut self, dev: Device, count: u64) -> Result<()> {
+        let dm_names = active_dm_names()?;
+        let mut constraints = AllocConstraints::default();
+        constraints.exclude_pvs.push(dev);
+
+        let lvnames: Vec<String> = self.lvs.keys().cloned().collect();
+        for lvname in lvnames {
+            for idx in 0..self.lvs[&lvname].segments.len() {
+                let areas = self.lvs[&lvname].segments[idx].used_areas();
+
+                for (area_dev, area_start, len) in areas {
+                    if area_dev != dev || area_start >= count {
+                        continue;
+                    }
+
+                    if dm_names.contains(&self.lvs[&lvname].dm_name(self)) {
+                        return Err(Error::Io(io::Error::from_raw_os_error(libc::EBUSY)));
+                    }
+
+                    let (new_dev, new_start) =
+                        find_contiguous_area(&self.free_areas(), len, &constraints)?;
+                    self.copy_extents(dev, area_start, new_dev, new_start, len)?;
+
+                    let lv = self.lvs.get_mut(&lvname).expect("listed above");
+                    lv.segments[idx].remap_areas(&|seg_dev, start| {
+                        if seg_dev == dev && start == area_start {
+                            (new_dev, new_start)
+                        } else {
+                            (seg_dev, start)
+                        }
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy len extents of data from one PV to another.
+    fn copy_extents(
+        &self,
+        src_dev: Device,
+        src_start: u64,
+        dst_dev: Device,
+        dst_start: u64,
+        len: u64,
+    ) -> Result<()> {
+        let offset = |dev: Device, start: u64| -> Result<(PathBuf, u64)> {
+            let pv = self
+                .pvs
+                .get(&dev)
+                .ok_or(Error::Io(io::Error::new(Other, "PV not found in VG")))?;
+            let path = pv.path().ok_or(Error::Io(io::Error::new(
+                Other,
+                "Could not find PV's device",
+            )))?;
+            Ok((
+                path,
+                (pv.pe_start + start * self.extent_size) * SECTOR_SIZE as u64,
+            ))
+        };
+        let (src_path, src_offset) = offset(src_dev, src_start)?;
+        let (dst_path, dst_offset) = offset(dst_dev, dst_start)?;
+
+        let mut src = File::open(&src_path)?;
+        let mut dst = OpenOptions::new().write(true).open(&dst_path)?;
+        src.seek(SeekFrom::Start(src_offset))?;
+        dst.seek(SeekFrom::Start(dst_offset))?;
+
+        let mut buf = vec![0u8; (self.extent_size * SECTOR_SIZE as u64) as usize];
+        for _ in 0..len {
+            src.read_exact(&mut buf)?;
+            dst.write_all(&buf)?;
+        }
+        dst.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Rename the VG, along with the device-mapper devices of any of its
+    /// active LVs, whose names include the VG's.
+    pub fn rename(&mut self, new_name: &str) -> Result<()> {
+        is_valid_lvm_name(new_name, NameKind::Vg)?;
+
+        if new_name == self.name {
+            return Ok(());
+        }
+
+        let dm_names = active_dm_names()?;
+
+        // (old, new) names of the active LVs' devices, all checked
+        // before any device is renamed
+        let renames: Vec<(String, String)> = self
+            .lvs
+            .keys()
+            .map(|lv_name| {
+                (
+                    lv::dm_name_for(&self.name, lv_name),
+                    lv::dm_name_for(new_name, lv_name),
+                )
+            })
+            .filter(|(old, _)| dm_names.contains(old))
+            .collect();
+        for (old, new) in &renames {
+            DmName::new(old)?;
+            DmName::new(new)?;
+        }
+        if !renames.is_empty() {
+            self.check_dm_change_allowed()?;
+        }
 
-        // poke dm and tell it about a new device
         let dm = DM::new()?;
-        let _new_linear = {
-            LinearDev::setup(
-                &dm,
-                DmName::new(&lv_name).expect("valid format"),
-                None,
-                table,
+        let rename_device = |from: &str, to: &str| {
+            dm.device_rename(
+                DmName::new(from).expect("checked above"),
+                &DevId::Name(DmName::new(to).expect("checked above")),
             )
-            .unwrap()
+        };
+        // Put back the devices renamed so far, most recent first
+        let undo = |done: &[(String, String)]| {
+            for (old, new) in done.iter().rev() {
+                let _ = rename_device(new, old);
+            }
+        };
+
+        for (idx, (old, new)) in renames.iter().enumerate() {
+            if let Err(e) = rename_device(old, new) {
+                undo(&renames[..idx]);
+                return Err(e.into());
+            }
+        }
+
+        let old_name = replace(&mut self.name, new_name.to_string());
+        if let Err(e) = self.write_changes() {
+            self.name = old_name;
+            undo(&renames);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Create a new linear logical volume in the volume group.
+    pub fn lv_create_linear(&mut self, name: &str, extent_size: u64) -> Result<()> {
+        self.lv_create_linear_constrained(name, extent_size, &AllocConstraints::default())
+    }
+
+    /// Create a new linear logical volume in the volume group, placing
+    /// it according to the given constraints.
+    pub fn lv_create_linear_constrained(
+        &mut self,
+        name: &str,
+        extent_size: u64,
+        constraints: &AllocConstraints,
+    ) -> Result<()> {
+        if self.lvs.contains_key(name) {
+            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+        }
+
+        let areas = self.allocate(extent_size, constraints, None)?;
+
+        self.lv_create_linear_areas(name, areas)
+    }
+
+    // The allocation policy for lv, or for a new LV if None: that given
+    // in the constraints, or else the LV's, or else the VG's. Policies
+    // that aren't understood, such as "inherit", are passed over.
+    fn alloc_policy_for(&self, constraints: &AllocConstraints, lv: Option<&LV>) -> AllocPolicy {
+        let parse = |policy: &Option<String>| policy.as_ref().and_then(|x| x.parse().ok());
+
+        constraints
+            .policy
+            .or_else(|| lv.and_then(|lv| parse(&lv.alloc_policy)))
+            .or_else(|| parse(&self.alloc_policy))
+            .unwrap_or_default()
+    }
+
+    // Returns free areas, in the format (Device, start, len), to hold
+    // extent_count more extents for lv, or for a new LV if None, chosen
+    // according to the constraints and the allocation policy.
+    fn allocate(
+        &self,
+        extent_count: u64,
+        constraints: &AllocConstraints,
+        lv: Option<&LV>,
+    ) -> Result<Vec<(Device, u64, u64)>> {
+        self.allocate_from(&self.free_areas(), extent_count, constraints, lv)
+    }
+
+    // As allocate, but choosing from the given free areas, in the format
+    // returned by free_areas().
+    fn allocate_from(
+        &self,
+        free: &BTreeMap<Device, BTreeMap<u64, u64>>,
+        extent_count: u64,
+        constraints: &AllocConstraints,
+        lv: Option<&LV>,
+    ) -> Result<Vec<(Device, u64, u64)>> {
+        let lv_areas: Vec<(Device, u64, u64)> = lv
+            .map(|lv| {
+                lv.segments
+                    .iter()
+                    .flat_map(|seg| seg.used_areas())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // The same constraints, but keeping to the PVs the LV is on
+        let mut cling = constraints.clone();
+        cling.exclude_pvs.extend(
+            self.pvs
+                .keys()
+                .filter(|dev| !lv_areas.iter().any(|area| area.0 == **dev))
+                .cloned(),
+        );
+
+        // Prefer one area, but fall back to piecing the extents together
+        // from several if need be.
+        let one_or_more = |constraints: &AllocConstraints| match find_contiguous_area(
+            free,
+            extent_count,
+            constraints,
+        ) {
+            Ok((dev, start)) => Ok(vec![(dev, start, extent_count)]),
+            Err(_) => find_areas(free, extent_count, constraints),
+        };
+
+        match self.alloc_policy_for(constraints, lv) {
+            AllocPolicy::Contiguous => match lv_areas.last() {
+                // Only the extents directly after the LV's last one will do
+                Some(&(dev, start, len)) => {
+                    let next = start + len;
+                    match free.get(&dev).and_then(|areas| areas.get(&next)) {
+                        Some(&free_len)
+                            if free_len >= extent_count
+                                && !constraints.exclude_pvs.contains(&dev) =>
+                        {
+                            Ok(vec![(dev, next, extent_count)])
+                        }
+                        _ => Err(Error::NoSpace),
+                    }
+                }
+                None => {
+                    let (dev, start) = find_contiguous_area(free, extent_count, constraints)?;
+                    Ok(vec![(dev, start, extent_count)])
+                }
+            },
+            AllocPolicy::Cling if !lv_areas.is_empty() => one_or_more(&cling),
+            AllocPolicy::Cling | AllocPolicy::Normal => {
+                if lv_areas.is_empty() {
+                    one_or_more(constraints)
+                } else {
+                    one_or_more(&cling).or_else(|_| one_or_more(constraints))
+                }
+            }
+            AllocPolicy::Anywhere => find_areas(free, extent_count, constraints),
+        }
+    }
+
+    /// Create a new striped logical volume in the volume group. The
+    /// extents are split evenly across `stripes` different PVs, with
+    /// `stripe_size` 512-byte sectors written to each in turn.
+    pub fn lv_create_striped(
+        &mut self,
+        name: &str,
+        extents: u64,
+        stripes: usize,
+        stripe_size: u64,
+    ) -> Result<()> {
+        is_valid_lvm_name(name, NameKind::Lv)?;
+
+        if self.lvs.contains_key(name) {
+            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+        }
+
+        if stripes == 0 || extents % stripes as u64 != 0 {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Extents must be a multiple of the number of stripes",
+            )));
+        }
+
+        if !stripe_size.is_power_of_two() || stripe_size > self.extent_size {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Stripe size must be a power of two no larger than the extent size",
+            )));
+        }
+
+        let areas = self.allocate_stripes(stripes, extents / stripes as u64)?;
+
+        let segment = Box::new(segment::StripedSegment {
+            start_extent: 0,
+            extent_count: extents,
+            stripes: areas,
+            stripe_size: Some(stripe_size),
+        });
+
+        let lv = LV {
+            name: name.to_string(),
+            id: make_uuid(),
+            status: vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "VISIBLE".to_string(),
+            ],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: self.creation_host(),
+            creation_time: now().to_timespec().sec,
+            alloc_policy: None,
+            segments: vec![segment],
+            device: None,
         };
 
+        self.activate_or_defer(&lv)?;
+
         self.lvs.insert(name.to_string(), lv);
 
-        self.commit()
+        self.write_changes()
+    }
+
+    // Find a contiguous area of stripe_len extents for each of stripes
+    // stripes, each on a different PV unless the policy is Anywhere.
+    // Returns the device and starting extent of each.
+    fn allocate_stripes(&self, stripes: usize, stripe_len: u64) -> Result<Vec<(Device, u64)>> {
+        let policy = self.alloc_policy_for(&AllocConstraints::default(), None);
+        let mut constraints = AllocConstraints {
+            policy: Some(AllocPolicy::Contiguous),
+            ..Default::default()
+        };
+        let mut free = self.free_areas();
+        let mut areas: Vec<(Device, u64)> = Vec::new();
+        while areas.len() < stripes {
+            let (dev, start, _) = match self.allocate_from(&free, stripe_len, &constraints, None) {
+                Ok(found) => found[0],
+                Err(_) => {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        format!(
+                            "Only found space for {} stripes, {} needed",
+                            areas.len(),
+                            stripes
+                        ),
+                    )))
+                }
+            };
+            take_free_area(&mut free, dev, start, stripe_len);
+            if policy != AllocPolicy::Anywhere {
+                constraints.exclude_pvs.push(dev);
+            }
+            areas.push((dev, start));
+        }
+
+        Ok(areas)
+    }
+
+    /// Create a new linear logical volume using all the free space in the
+    /// volume group, except for `reserve_extents` extents which are left
+    /// free as slack. Extents held by a Reservation are not counted as
+    /// free.
+    pub fn lv_create_linear_all(&mut self, name: &str, reserve_extents: u64) -> Result<()> {
+        let free: u64 = self
+            .free_areas()
+            .values()
+            .flat_map(|areas| areas.values())
+            .sum();
+
+        if free <= reserve_extents {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!(
+                    "{} free extents, cannot reserve {} and create an LV",
+                    free, reserve_extents
+                ),
+            )));
+        }
+
+        self.lv_create_linear(name, free - reserve_extents)
+    }
+
+    /// Set aside a contiguous range of extents for an LV that will be
+    /// created later. The extents are not written to disk as used, but
+    /// will not be allocated by this VG until the Reservation is dropped.
+    pub fn reserve(&mut self, extents: u64) -> Result<Reservation> {
+        let (dev, start) =
+            find_contiguous_area(&self.free_areas(), extents, &AllocConstraints::default())?;
+
+        (self.reserved.0)
+            .lock()
+            .expect("reservation lock poisoned")
+            .entry(dev)
+            .or_insert(BTreeMap::new())
+            .insert(start, extents);
+
+        Ok(Reservation {
+            reserved: self.reserved.clone(),
+            device: dev,
+            start: start,
+            extents: extents,
+        })
+    }
+
+    /// Create a new linear logical volume on previously reserved extents.
+    pub fn lv_create_reserved(&mut self, name: &str, reservation: Reservation) -> Result<()> {
+        if !Arc::ptr_eq(&self.reserved.0, &reservation.reserved.0) {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Reservation is from a different VG",
+            )));
+        }
+
+        if self.lvs.contains_key(name) {
+            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+        }
+
+        // The LV now holds the extents, so releasing the reservation
+        // when it goes out of scope does not make them free.
+        self.lv_create_linear_areas(
+            name,
+            vec![(reservation.device, reservation.start, reservation.extents)],
+        )
+    }
+
+    // Create a linear LV mapping each of areas, in the format
+    // (Device, start, len), in turn.
+    fn lv_create_linear_areas(&mut self, name: &str, areas: Vec<(Device, u64, u64)>) -> Result<()> {
+        is_valid_lvm_name(name, NameKind::Lv)?;
+
+        let mut segments: Vec<Box<dyn segment::Segment>> = Vec::new();
+        let mut lv_extent = 0;
+
+        for (dev, area_start, extent_count) in areas {
+            if !self.pvs.contains_key(&dev) {
+                return Err(Error::Io(io::Error::new(Other, "PV not found in VG")));
+            }
+
+            segments.push(Box::new(segment::StripedSegment {
+                start_extent: lv_extent,
+                extent_count: extent_count,
+                stripes: vec![(dev, area_start)],
+                stripe_size: None,
+            }));
+
+            lv_extent += extent_count;
+        }
+
+        let lv = LV {
+            name: name.to_string(),
+            id: make_uuid(),
+            status: vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "VISIBLE".to_string(),
+            ],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: self.creation_host(),
+            creation_time: now().to_timespec().sec,
+            alloc_policy: None,
+            segments: segments,
+            device: None,
+        };
+
+        // If the LV can't be activated, it is not added, so its extents
+        // stay free, as do those of a Reservation it was to be created on
+        // once that is dropped.
+        self.activate_or_defer(&lv)?;
+
+        self.lvs.insert(name.to_string(), lv);
+
+        self.write_changes()
+    }
+
+    /// Set whether new linear LVs have any existing signatures wiped
+    /// after they are activated. Defaults to false.
+    pub fn set_wipe_signatures(&mut self, wipe: bool) {
+        self.wipe_signatures = wipe;
+    }
+
+    /// Set a directory in which to archive the VG's metadata as it was
+    /// on disk before each commit, giving a trail of configurations to
+    /// roll back to. If None (the default), nothing is archived.
+    pub fn set_archive_dir(&mut self, dir: Option<&Path>) {
+        self.archive_dir = dir.map(|x| x.to_owned());
+    }
+
+    /// Set the host name recorded in metadata as having created LVs and
+    /// written the VG. If None (the default), this host's name is used,
+    /// or "unknown" if it cannot be determined.
+    pub fn set_creation_host(&mut self, host: Option<&str>) {
+        self.creation_host = host.map(|x| x.to_string());
+    }
+
+    // The host name to record in metadata.
+    fn creation_host(&self) -> String {
+        self.creation_host
+            .clone()
+            .or_else(hostname)
+            .unwrap_or_else(|| DEFAULT_CREATION_HOST.to_string())
+    }
+
+    // Activate a newly created LV, or during a Transaction, note it to be
+    // activated once the Transaction is committed.
+    fn activate_or_defer(&mut self, lv: &LV) -> Result<()> {
+        if self.in_transaction {
+            self.pending_activations.push(lv.name.clone());
+            return Ok(());
+        }
+
+        self.activate_new_lv(lv)
+    }
+
+    // Activate a newly created LV, wiping any signatures on it if set to.
+    fn activate_new_lv(&self, lv: &LV) -> Result<()> {
+        dm::activate(self, lv)?;
+
+        if self.wipe_signatures {
+            if let Err(e) = lv.wipe_signatures(self) {
+                // Don't leave a device behind for an LV that wasn't made
+                dm::deactivate(self, lv)?;
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fail if a Transaction is in progress, since dropping it can't undo
+    // changes to device-mapper devices.
+    fn check_dm_change_allowed(&self) -> Result<()> {
+        if self.in_transaction {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Cannot change device-mapper devices during a transaction",
+            )));
+        }
+
+        Ok(())
     }
 
     /// Create a thin pool from existing metadata and data volumes.
@@ -421,6 +1540,9 @@ impl VG {
         thin_meta: &str,
         thin_data: &str,
     ) -> Result<()> {
+        is_valid_lvm_name(name, NameKind::Lv)?;
+        self.check_dm_change_allowed()?;
+
         let dm = DM::new()?;
 
         let extent_count = {
@@ -464,8 +1586,10 @@ impl VG {
                 "VISIBLE".to_string(),
             ],
             flags: Vec::new(),
-            creation_host: uname().nodename().to_string(),
+            tags: Vec::new(),
+            creation_host: self.creation_host(),
             creation_time: now().to_timespec().sec,
+            alloc_policy: None,
             segments: vec![segment],
             device: None,
         };
@@ -477,252 +1601,3487 @@ impl VG {
 
         self.lvs.insert(name.to_string(), lv);
 
-        self.commit()
+        self.write_changes()
     }
 
-    /// Destroy a logical volume.
-    pub fn lv_remove(&mut self, name: &str) -> Result<()> {
-        match self.lvs.remove(name) {
-            None => Err(Error::Io(io::Error::new(Other, "LV not found in VG"))),
-            Some(lv) => {
-                let dm = DM::new()?;
-                let name = DmName::new(&lv.name)?;
-                dm.device_suspend(
-                    &DevId::Name(name),
-                    &DmOptions::new().set_flags(DmFlags::DM_SUSPEND),
-                )?;
-                dm.device_remove(&DevId::Name(name), &DmOptions::new())?;
+    /// Create a thin snapshot of a thin LV. Unlike a classic snapshot,
+    /// no space is set aside for it: it shares the origin's blocks
+    /// within the thin pool until either is written. The pool must be
+    /// active, since the snapshot is created in its own metadata as
+    /// well as the VG's. The snapshot is not activated.
+    pub fn lv_create_thin_snapshot(&mut self, pool: &str, origin: &str, name: &str) -> Result<()> {
+        is_valid_lvm_name(name, NameKind::Lv)?;
 
-                self.commit()
-            }
+        if self.lvs.contains_key(name) {
+            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
         }
-    }
-
-    /// The total number of extents in use in the volume group.
-    pub fn extents_in_use(&self) -> u64 {
-        self.lvs.values().map(|x| x.used_extents()).sum()
-    }
 
-    /// The total number of free extents in the volume group.
-    pub fn extents_free(&self) -> u64 {
-        self.extents() - self.extents_in_use()
-    }
+        // Thin and thin pool LVs have a single segment
+        let first_seg = |lvname: &str| self.lvs.get(lvname).and_then(|lv| lv.segments.get(0));
+        let thin_seg = |lvname: &str| {
+            first_seg(lvname)
+                .filter(|seg| seg.segment_type() == SegmentType::Thin)
+                .and_then(|seg| seg.as_any().downcast_ref::<segment::ThinSegment>())
+        };
 
-    /// The total number of extents in the volume group.
-    pub fn extents(&self) -> u64 {
-        self.pvs.values().map(|x| x.pe_count).sum()
-    }
+        let transaction_id = match first_seg(pool)
+            .filter(|seg| seg.segment_type() == SegmentType::ThinPool)
+            .and_then(|seg| seg.as_any().downcast_ref::<segment::ThinpoolSegment>())
+        {
+            Some(seg) => seg.transaction_id,
+            None => {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("LV {} is not a thin pool", pool),
+                )))
+            }
+        };
 
-    fn commit(&mut self) -> Result<()> {
-        self.seqno += 1;
+        let (origin_id, extent_count, external_origin) = match thin_seg(origin) {
+            Some(seg) if seg.thin_pool == pool => {
+                (seg.device_id, seg.extent_count, seg.external_origin.clone())
+            }
+            _ => {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("LV {} is not a thin LV in pool {}", origin, pool),
+                )))
+            }
+        };
 
-        let map: LvmTextMap = to_textmap(self);
+        // Allocate the next device ID in the pool
+        let device_id = self
+            .lvs
+            .keys()
+            .filter_map(|lvname| thin_seg(lvname))
+            .filter(|seg| seg.thin_pool == pool)
+            .map(|seg| seg.device_id)
+            .max()
+            .map_or(1, |x| x + 1);
+
+        let dm_names = active_dm_names()?;
+        if !dm_names.contains(&lv::dm_name_for(&self.name, pool)) {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!("Thin pool {} must be active to create a snapshot", pool),
+            )));
+        }
+        let origin_active = dm_names.contains(&lv::dm_name_for(&self.name, origin));
+        self.check_dm_change_allowed()?;
+
+        self.pool_create_snap(
+            pool,
+            origin,
+            origin_active,
+            device_id,
+            origin_id,
+            transaction_id,
+        )?;
+
+        self.set_pool_transaction_id(pool, transaction_id + 1);
+
+        let segment = Box::new(segment::ThinSegment {
+            start_extent: 0,
+            extent_count: extent_count,
+            thin_pool: pool.to_string(),
+            transaction_id: transaction_id + 1,
+            device_id: device_id,
+            origin: Some(origin.to_string()),
+            // Blocks the origin hasn't written still come from there
+            external_origin: external_origin,
+        });
+
+        let lv = LV {
+            name: name.to_string(),
+            id: make_uuid(),
+            status: vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "VISIBLE".to_string(),
+            ],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: self.creation_host(),
+            creation_time: now().to_timespec().sec,
+            alloc_policy: None,
+            segments: vec![segment],
+            device: None,
+        };
+
+        self.lvs.insert(name.to_string(), lv);
+
+        if let Err(e) = self.write_changes() {
+            self.lvs.remove(name);
+            self.set_pool_transaction_id(pool, transaction_id);
+
+            // Take the snapshot back out of the pool too
+            let pool_lv = &self.lvs[pool];
+            dm::pool_message(self, pool_lv, &format!("delete {}", device_id))?;
+            dm::pool_message(
+                self,
+                pool_lv,
+                &format!(
+                    "set_transaction_id {} {}",
+                    transaction_id + 1,
+                    transaction_id
+                ),
+            )?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Create thin device device_id in an active pool as a snapshot of
+    // thin device origin_id, and move the pool's transaction ID on from
+    // transaction_id. An active origin is suspended meanwhile, so that
+    // its mappings don't change while they are copied.
+    fn pool_create_snap(
+        &self,
+        pool: &str,
+        origin: &str,
+        origin_active: bool,
+        device_id: u64,
+        origin_id: u64,
+        transaction_id: u64,
+    ) -> Result<()> {
+        let pool_lv = &self.lvs[pool];
+        let origin_lv = &self.lvs[origin];
+
+        if origin_active {
+            dm::suspend(self, origin_lv)?;
+        }
+        let res = dm::pool_message(
+            self,
+            pool_lv,
+            &format!("create_snap {} {}", device_id, origin_id),
+        );
+        if origin_active {
+            dm::resume(self, origin_lv)?;
+        }
+        res?;
+
+        if let Err(e) = dm::pool_message(
+            self,
+            pool_lv,
+            &format!(
+                "set_transaction_id {} {}",
+                transaction_id,
+                transaction_id + 1
+            ),
+        ) {
+            dm::pool_message(self, pool_lv, &format!("delete {}", device_id))?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Set the transaction ID recorded in a thin pool LV's segment.
+    fn set_pool_transaction_id(&mut self, pool: &str, transaction_id: u64) {
+        if let Some(seg) = self
+            .lvs
+            .get_mut(pool)
+            .and_then(|lv| lv.segments.get_mut(0))
+            .and_then(|seg| seg.as_any_mut().downcast_mut::<segment::ThinpoolSegment>())
+        {
+            seg.transaction_id = transaction_id;
+        }
+    }
+
+    /// Destroy a logical volume. If it is active, it is deactivated
+    /// first, which fails if it is in use.
+    pub fn lv_remove(&mut self, name: &str) -> Result<()> {
+        self.deactivate_lv(name)?;
+
+        // Its extents are now free
+        self.lvs.remove(name);
+
+        self.write_changes()
+    }
+
+    /// Add a tag to the VG. Adding a tag it already has does nothing.
+    pub fn add_vg_tag(&mut self, tag: &str) -> Result<()> {
+        is_valid_lvm_name(tag, NameKind::Tag)?;
+
+        if self.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        self.tags.push(tag.to_string());
+
+        self.write_changes()
+    }
+
+    /// Remove a tag from the VG. Removing a tag it does not have does
+    /// nothing.
+    pub fn remove_vg_tag(&mut self, tag: &str) -> Result<()> {
+        if !self.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        self.tags.retain(|x| x != tag);
+
+        self.write_changes()
+    }
+
+    /// Add a tag to a PV in the VG. Adding a tag it already has does
+    /// nothing.
+    pub fn add_pv_tag(&mut self, dev: Device, tag: &str) -> Result<()> {
+        is_valid_lvm_name(tag, NameKind::Tag)?;
+
+        let pv = self
+            .pvs
+            .get_mut(&dev)
+            .ok_or_else(|| Error::NotFound(format!("PV {}:{}", dev.major, dev.minor)))?;
+        if pv.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        pv.tags.push(tag.to_string());
+
+        self.write_changes()
+    }
+
+    /// Remove a tag from a PV in the VG. Removing a tag it does not have
+    /// does nothing.
+    pub fn remove_pv_tag(&mut self, dev: Device, tag: &str) -> Result<()> {
+        let pv = self
+            .pvs
+            .get_mut(&dev)
+            .ok_or_else(|| Error::NotFound(format!("PV {}:{}", dev.major, dev.minor)))?;
+        if !pv.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        pv.tags.retain(|x| x != tag);
+
+        self.write_changes()
+    }
+
+    /// Add a tag to an LV. Adding a tag it already has does nothing.
+    pub fn add_lv_tag(&mut self, name: &str, tag: &str) -> Result<()> {
+        is_valid_lvm_name(tag, NameKind::Tag)?;
+
+        let lv = self
+            .lvs
+            .get_mut(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+        if lv.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        lv.tags.push(tag.to_string());
+
+        self.write_changes()
+    }
+
+    /// Remove a tag from an LV. Removing a tag it does not have does
+    /// nothing.
+    pub fn remove_lv_tag(&mut self, name: &str, tag: &str) -> Result<()> {
+        let lv = self
+            .lvs
+            .get_mut(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+        if !lv.tags.iter().any(|x| x == tag) {
+            return Ok(());
+        }
+        lv.tags.retain(|x| x != tag);
+
+        self.write_changes()
+    }
+
+    /// Rename an LV, updating any references to it from other LVs, and
+    /// its device-mapper device if it is active.
+    pub fn lv_rename(&mut self, old: &str, new: &str) -> Result<()> {
+        is_valid_lvm_name(new, NameKind::Lv)?;
+
+        if !self.lvs.contains_key(old) {
+            return Err(Error::NotFound(format!("LV {}", old)));
+        }
+
+        if self.lvs.contains_key(new) {
+            return Err(Error::Io(io::Error::new(Other, "LV already exists")));
+        }
+
+        // Rename the device before touching self.lvs, so that an error
+        // leaves the LV as it was
+        let old_dm_name = lv::dm_name_for(&self.name, old);
+        let new_dm_name = lv::dm_name_for(&self.name, new);
+        let active = active_dm_names()?.contains(&old_dm_name);
+        if active {
+            self.check_dm_change_allowed()?;
+            rename_dm_device(&old_dm_name, &new_dm_name)?;
+        }
+
+        self.rename_lv_entries(old, new);
+
+        // If the new name can't be written, put everything back
+        if let Err(e) = self.write_changes() {
+            self.rename_lv_entries(new, old);
+            if active {
+                let _ = rename_dm_device(&new_dm_name, &old_dm_name);
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    // Re-key an LV under a new name, and update references to it from
+    // other LVs, and from the LVs to be activated when a Transaction is
+    // committed.
+    fn rename_lv_entries(&mut self, old: &str, new: &str) {
+        if let Some(mut lv) = self.lvs.remove(old) {
+            lv.name = new.to_string();
+            self.lvs.insert(new.to_string(), lv);
+        }
+        for lv in self.lvs.values_mut() {
+            for seg in &mut lv.segments {
+                seg.rename_lv_refs(old, new);
+            }
+        }
+        for pending in &mut self.pending_activations {
+            if pending == old {
+                *pending = new.to_string();
+            }
+        }
+    }
+
+    /// Change the size of a linear LV to new_extents. Growing it
+    /// appends newly allocated segments. Shrinking it drops its last
+    /// extents, so any filesystem or other data on the LV must already
+    /// fit in the new size: this cannot be checked here, and data past
+    /// the new end is lost.
+    ///
+    /// If the LV is active, its device is reloaded to the new size. When
+    /// shrinking an active LV, shrink its filesystem first.
+    pub fn lv_resize(&mut self, name: &str, new_extents: u64) -> Result<()> {
+        let (old_extents, linear) = match self.lvs.get(name) {
+            None => return Err(Error::NotFound(format!("LV {}", name))),
+            Some(lv) => (
+                lv.used_extents(),
+                lv.segments.iter().all(|seg| {
+                    seg.segment_type() == SegmentType::Striped && seg.used_areas().len() == 1
+                }),
+            ),
+        };
+
+        if new_extents == old_extents {
+            return Ok(());
+        }
+
+        if !linear {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Only linear LVs can be resized",
+            )));
+        }
+
+        if new_extents == 0 {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "LV cannot be resized to 0 extents",
+            )));
+        }
+
+        // (Device, start, len) for each segment, in LV order
+        let mut areas: Vec<(Device, u64, u64)> = Vec::new();
+        for seg in &self.lvs[name].segments {
+            areas.extend(seg.used_areas());
+        }
+
+        if new_extents > old_extents {
+            let extra = new_extents - old_extents;
+            let new_areas =
+                self.allocate(extra, &AllocConstraints::default(), Some(&self.lvs[name]))?;
+            for (dev, start, len) in new_areas {
+                // Grow the last area if the new one directly follows it
+                match areas.last_mut() {
+                    Some(last) if last.0 == dev && last.1 + last.2 == start => last.2 += len,
+                    _ => areas.push((dev, start, len)),
+                }
+            }
+        } else {
+            let mut remaining = new_extents;
+            for area in &mut areas {
+                area.2 = min(area.2, remaining);
+                remaining -= area.2;
+            }
+            areas.retain(|area| area.2 != 0);
+        }
+
+        let mut segments: Vec<Box<dyn segment::Segment>> = Vec::new();
+        let mut lv_extent = 0;
+        for (dev, start, len) in areas {
+            segments.push(Box::new(segment::StripedSegment {
+                start_extent: lv_extent,
+                extent_count: len,
+                stripes: vec![(dev, start)],
+                stripe_size: None,
+            }));
+            lv_extent += len;
+        }
+
+        let active = self.is_active(name)?;
+        if active {
+            self.check_dm_change_allowed()?;
+        }
+        let old_segments = replace(
+            &mut self.lvs.get_mut(name).expect("checked above").segments,
+            segments,
+        );
+
+        // Load the new mapping if the LV is active. If that or writing
+        // the metadata fails, the LV keeps its old segments.
+        if active {
+            if let Err(e) = self.reload_lv_table(name) {
+                self.lvs.get_mut(name).expect("checked above").segments = old_segments;
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.write_changes() {
+            self.lvs.get_mut(name).expect("checked above").segments = old_segments;
+            // The device must not go on mapping extents that, as far as
+            // the metadata on disk is concerned, aren't the LV's
+            if active {
+                self.reload_lv_table(name)?;
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Make an LV read-only, or read-write. If the LV is active, its
+    /// device's table is reloaded so the change takes effect at once.
+    pub fn set_lv_permission(&mut self, name: &str, writable: bool) -> Result<()> {
+        let active = self.is_active(name)?;
+        if active {
+            self.check_dm_change_allowed()?;
+        }
+
+        let old_status = {
+            let lv = self.lvs.get_mut(name).expect("checked above");
+
+            let mut status = lv.status_set();
+            status.known.insert(LvStatus::Read);
+            if writable {
+                status.known.insert(LvStatus::Write);
+            } else {
+                status.known.remove(&LvStatus::Write);
+            }
+            replace(&mut lv.status, status.to_strings())
+        };
+
+        // Load the table with the new permission if the LV is active. If
+        // that or writing the metadata fails, the LV keeps its old status.
+        if active {
+            if let Err(e) = self.reload_lv_table(name) {
+                self.lvs.get_mut(name).expect("checked above").status = old_status;
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.write_changes() {
+            self.lvs.get_mut(name).expect("checked above").status = old_status;
+            if active {
+                self.reload_lv_table(name)?;
+            }
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Activate an LV, creating its device-mapper device,
+    /// /dev/mapper/<vg>-<lv>. Activating an active LV does nothing.
+    pub fn activate_lv(&self, name: &str) -> Result<()> {
+        let lv = self
+            .lvs
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+
+        if active_dm_names()?.contains(&lv.dm_name(self)) {
+            return Ok(());
+        }
+
+        self.check_dm_change_allowed()?;
+        dm::activate(self, lv)
+    }
+
+    /// Deactivate an LV, removing its device-mapper device. This fails
+    /// with EBUSY if the device is open, e.g. by a mounted filesystem.
+    /// Deactivating an inactive LV does nothing.
+    pub fn deactivate_lv(&self, name: &str) -> Result<()> {
+        let lv = self
+            .lvs
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+
+        if !active_dm_names()?.contains(&lv.dm_name(self)) {
+            return Ok(());
+        }
+
+        self.check_dm_change_allowed()?;
+        dm::deactivate(self, lv)
+    }
+
+    /// Reload the device-mapper table of an active LV from its current
+    /// segments, so the kernel sees changes such as a new size.
+    ///
+    /// The new table takes effect immediately. When an LV shrinks, any
+    /// filesystem on it must be shrunk before this is called, or the
+    /// filesystem will find its end missing.
+    pub fn reload_lv_table(&self, name: &str) -> Result<()> {
+        let lv = self
+            .lvs
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+
+        self.check_dm_change_allowed()?;
+        dm::reload(self, lv)
+    }
+
+    /// Whether the named LV is active, i.e. whether device-mapper has a
+    /// device for it. This asks the kernel, so it is accurate even if
+    /// another process activated or deactivated the LV.
+    pub fn is_active(&self, name: &str) -> Result<bool> {
+        let lv = self
+            .lvs
+            .get(name)
+            .ok_or_else(|| Error::NotFound(format!("LV {}", name)))?;
+
+        Ok(active_dm_names()?.contains(&lv.dm_name(self)))
+    }
+
+    /// The names of the LVs in the VG that are active.
+    pub fn active_lvs(&self) -> Result<Vec<&str>> {
+        let dm_names = active_dm_names()?;
+
+        Ok(self
+            .lvs
+            .values()
+            .filter(|lv| dm_names.contains(&lv.dm_name(self)))
+            .map(|lv| &lv.name[..])
+            .collect())
+    }
+
+    /// The total number of extents in use in the volume group.
+    pub fn extents_in_use(&self) -> u64 {
+        self.lvs.values().map(|x| x.used_extents()).sum()
+    }
+
+    /// The total number of free extents in the volume group.
+    pub fn extents_free(&self) -> u64 {
+        self.extents() - self.extents_in_use()
+    }
+
+    /// The number of free extents on each PV. Every PV is listed, both
+    /// those with no LVs on them and those with no space left.
+    pub fn pv_free_extents(&self) -> BTreeMap<Device, u64> {
+        let free = self.free_areas();
+
+        self.pvs
+            .keys()
+            .map(|dev| {
+                let count = free.get(dev).map_or(0, |areas| areas.values().sum());
+                (*dev, count)
+            })
+            .collect()
+    }
+
+    /// The total number of extents in the volume group.
+    pub fn extents(&self) -> u64 {
+        self.pvs.values().map(|x| x.pe_count).sum()
+    }
+
+    /// Check the VG for inconsistencies, e.g. after reading it from a
+    /// possibly damaged disk: every area used by an LV must be on a PV in
+    /// the VG and within that PV's extents, no two areas may overlap,
+    /// each LV's segments must cover its extents in order from 0 without
+    /// gaps, and no more extents may be allocated than the VG has.
+    ///
+    /// Returns an error describing the first problem found.
+    pub fn validate(&self) -> Result<()> {
+        let err = |msg: String| Err(Error::Io(io::Error::new(Other, msg)));
+
+        self.validate_allocation()?;
+
+        for (lvname, lv) in &self.lvs {
+            let mut next = 0;
+            for seg in &lv.segments {
+                if seg.start_extent() != next {
+                    return err(format!(
+                        "LV {} has a segment at extent {}, expected {}",
+                        lvname,
+                        seg.start_extent(),
+                        next
+                    ));
+                }
+                next += seg.extent_count();
+            }
+        }
+
+        // Thin LVs' extents are virtual, so count only those on PVs
+        let allocated: u64 = self
+            .lvs
+            .values()
+            .flat_map(|lv| lv::used_areas(lv))
+            .map(|(_, _, len)| len)
+            .sum();
+        if allocated > self.extents() {
+            return err(format!(
+                "{} extents are allocated but the VG has only {}",
+                allocated,
+                self.extents()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that every area used by an LV is on a PV in the VG and
+    /// within that PV's extents, and that no two areas overlap.
+    pub fn validate_allocation(&self) -> Result<()> {
+        // {Device: [(start, len, lvname)] }
+        let mut areas = BTreeMap::new();
+
+        for (lvname, lv) in &self.lvs {
+            for (dev, start, len) in lv::used_areas(lv) {
+                let pv = self.pvs.get(&dev).ok_or_else(|| {
+                    Error::Io(io::Error::new(
+                        Other,
+                        format!("LV {} uses a PV not in the VG", lvname),
+                    ))
+                })?;
+
+                if start + len > pv.pe_count {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        format!(
+                            "LV {} uses extents {}-{} but its PV has only {}",
+                            lvname,
+                            start,
+                            start + len - 1,
+                            pv.pe_count
+                        ),
+                    )));
+                }
+
+                areas
+                    .entry(dev)
+                    .or_insert(Vec::new())
+                    .push((start, len, lvname));
+            }
+        }
+
+        for dev_areas in areas.values_mut() {
+            dev_areas.sort();
+            for pair in dev_areas.windows(2) {
+                let (start, len, lvname) = pair[0];
+                let (next_start, _, next_lvname) = pair[1];
+                if start + len > next_start {
+                    return Err(Error::Io(io::Error::new(
+                        Other,
+                        format!("LVs {} and {} overlap", lvname, next_lvname),
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start a Transaction, to make several changes to the VG and write
+    /// them to disk together.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        let original = self.clone();
+        self.in_transaction = true;
+
+        Transaction {
+            vg: self,
+            original: Some(original),
+        }
+    }
+
+    /// Write the VG's metadata to all its PVs, incrementing the seqno.
+    ///
+    /// Methods that modify the VG commit on their own, so this is only
+    /// needed for a VG that was assembled in memory, e.g. by `VgBuilder`.
+    /// During a Transaction this fails; commit the Transaction instead.
+    pub fn commit(&mut self) -> Result<()> {
+        self.check_no_transaction()?;
+
+        let seqno = self.seqno + 1;
+        self.commit_with_seqno(seqno)
+    }
+
+    // Commit a change made by one of the VG's methods. During a
+    // Transaction, the change is only checked, and is written when the
+    // Transaction is committed.
+    fn write_changes(&mut self) -> Result<()> {
+        if self.in_transaction {
+            return self.validate_allocation();
+        }
+
+        self.commit()
+    }
+
+    // Fail if a Transaction is in progress, since metadata must not be
+    // written until it is committed.
+    fn check_no_transaction(&self) -> Result<()> {
+        if self.in_transaction {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Cannot write metadata during a transaction",
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Write the VG's metadata to all its PVs with the given seqno,
+    /// rather than the next one. This is for tools that replicate
+    /// metadata exactly between hosts; normally use `commit()`.
+    ///
+    /// The seqno must be greater than the VG's current one. Once the
+    /// metadata is written, the VG takes the new seqno, so this needs
+    /// `&mut self`; if writing fails, the VG keeps its old seqno.
+    pub fn commit_with_seqno(&mut self, seqno: u64) -> Result<()> {
+        self.check_no_transaction()?;
+        self.validate_allocation()?;
+
+        if seqno <= self.seqno {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                format!(
+                    "seqno {} is not greater than VG {}'s seqno {}",
+                    seqno, self.name, self.seqno
+                ),
+            )));
+        }
+
+        if let Some(ref dir) = self.archive_dir {
+            self.archive(dir)?;
+        }
+
+        let old_seqno = replace(&mut self.seqno, seqno);
+        if let Err(e) = self.write_to_pvs() {
+            self.seqno = old_seqno;
+            return Err(e);
+        }
+
+        // The metadata on disk is now correct, so don't fail if only
+        // lvmetad's cached copy couldn't be updated.
+        self.lvmetad_error = lvmetad::vg_update(self).err().map(|e| e.to_string());
+
+        Ok(())
+    }
+
+    // Write the VG's metadata, as it is, to all its PVs.
+    fn write_to_pvs(&self) -> Result<()> {
+        let disk_map = self.disk_textmap();
+
+        // TODO: atomicity of updating pvs, metad, dm
+        for pv in self.pvs.values() {
+            if let Some(path) = pv.path() {
+                let mut pvheader = PvHeader::find_in_dev(&path)?;
+
+                pvheader.write_metadata_with_options(&disk_map, &WriteOptions::default())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Copy the metadata currently on disk to a new file in dir, named
+    // like LVM's archives: "<vg>_<number>.vg", with the number one more
+    // than that of any existing archive for the VG.
+    fn archive(&self, dir: &Path) -> Result<()> {
+        let disk_map = match self
+            .pvs
+            .values()
+            .filter_map(|pv| pv.path())
+            .filter_map(|path| PvHeader::find_in_dev(&path).ok())
+            .filter_map(|pvh| pvh.read_metadata().ok())
+            .next()
+        {
+            Some(x) => x,
+            // Nothing written yet, so nothing to archive
+            None => return Ok(()),
+        };
+
+        let prefix = format!("{}_", self.name);
+        let mut next = 0;
+        for entry in read_dir(dir)? {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.starts_with(&prefix) && file_name.ends_with(".vg") {
+                let num = &file_name[prefix.len()..file_name.len() - 3];
+                if let Ok(x) = num.parse::<u64>() {
+                    next = max(next, x + 1);
+                }
+            }
+        }
+
+        let path = dir.join(format!("{}{:05}.vg", prefix, next));
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        f.write_all(&textmap_to_buf(&disk_map))?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Write this VG's metadata to a file at path, like vgcfgbackup.
+    /// The file is in the same format as LVM's /etc/lvm/backup files,
+    /// so either tool may restore from it. An existing file is replaced.
+    pub fn backup_to_file(&self, path: &Path) -> Result<()> {
+        let tm = now();
+        let host = self.creation_host();
+
+        let mut disk_map = self.disk_textmap();
+        disk_map.insert(
+            "contents".to_string(),
+            Entry::String("Text Format Volume Group".to_string()),
+        );
+        disk_map.insert(
+            "description".to_string(),
+            Entry::String("Created *after* executing 'backup_to_file'".to_string()),
+        );
+        disk_map.insert(
+            "creation_time".to_string(),
+            Entry::Number(tm.to_timespec().sec),
+        );
+
+        let mut buf = format!(
+            "# Generated by melvin {}: {}\n# Host: {}\n\n",
+            env!("CARGO_PKG_VERSION"),
+            tm.ctime(),
+            host
+        )
+        .into_bytes();
+        buf.extend(textmap_to_buf(&disk_map));
+
+        let mut f = File::create(path)?;
+        f.write_all(&buf)?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Repair PVs whose metadata has diverging seqnos, e.g. after a
+    /// manual recovery, by writing this VG's metadata to all of them
+    /// with a single seqno greater than any of theirs. Every header must
+    /// be of one of the VG's PVs.
+    pub fn normalize_seqno(&mut self, headers: &mut [PvHeader]) -> Result<()> {
+        self.check_no_transaction()?;
+
+        for header in headers.iter() {
+            if !self.pvs.values().any(|pv| pv.id == header.uuid) {
+                return Err(Error::Io(io::Error::new(
+                    Other,
+                    format!("PV {} is not in VG {}", header.uuid, self.name),
+                )));
+            }
+        }
+
+        let max_seqno = headers
+            .iter()
+            .filter_map(|h| h.read_metadata().ok())
+            .filter_map(|map| vg_seqno(&map))
+            .map(|x| x as u64)
+            .fold(self.seqno, max);
+
+        self.seqno = max_seqno + 1;
+
+        let disk_map = self.disk_textmap();
+
+        for header in headers {
+            header.write_metadata_with_options(&disk_map, &WriteOptions::default())?;
+        }
+
+        Ok(())
+    }
+
+    // The complete metadata to write to disk, with the VG's textmap and
+    // a header describing when and where it was written.
+    fn disk_textmap(&self) -> LvmTextMap {
+        let map: LvmTextMap = to_textmap(self);
 
         let mut disk_map = LvmTextMap::new();
         disk_map.insert(
             "contents".to_string(),
             Entry::String("Melvin Text Format Volume Group".to_string()),
         );
-        disk_map.insert("version".to_string(), Entry::Number(1));
-        disk_map.insert("description".to_string(), Entry::String("".to_string()));
-        disk_map.insert(
-            "creation_host".to_string(),
-            Entry::String(uname().nodename().to_string()),
+        disk_map.insert("version".to_string(), Entry::Number(1));
+        disk_map.insert("description".to_string(), Entry::String("".to_string()));
+        disk_map.insert(
+            "creation_host".to_string(),
+            Entry::String(self.creation_host()),
+        );
+        disk_map.insert(
+            "creation_time".to_string(),
+            Entry::Number(now().to_timespec().sec),
+        );
+        disk_map.insert(self.name.clone(), Entry::TextMap(Box::new(map)));
+
+        disk_map
+    }
+
+    /// Compare the configuration of two VGs: their PVs, LVs, and segment
+    /// layouts. Unlike `==`, this ignores the seqno and when and where
+    /// LVs were created, which may differ between copies of metadata
+    /// that describe the same configuration.
+    pub fn structurally_eq(&self, other: &VG) -> bool {
+        self.name == other.name && structural_textmap(self) == structural_textmap(other)
+    }
+
+    /// Compare this VG against the metadata currently on a member PV,
+    /// returning what committing it would change.
+    pub fn pending_changes(&self, header: &PvHeader) -> Result<Vec<MapDiff>> {
+        let disk_map = header.read_metadata()?;
+        let on_disk =
+            disk_map
+                .textmap_from_textmap(&self.name)
+                .ok_or(Error::Io(io::Error::new(
+                    Other,
+                    format!("No metadata for VG {} on PV", self.name),
+                )))?;
+
+        Ok(diff_textmaps(on_disk, &to_textmap(self)))
+    }
+
+    // Returns used areas in the format: {Device: {start: len} }
+    //
+    // e.g. with {<Device 3:1>: {0: 45, 47: 100, 147: 200} }
+    // extents 0-44 (inclusive) are used, 45 and 46 are not, 47-146
+    // are used, then 147-346 are used.
+    //
+    // Adjacent used areas are not merged.
+    //
+    // PVs with no used areas are not in the outer map at all.
+    //
+    // If corrupt metadata has two areas starting at the same extent, the
+    // longer is kept so its extents are not thought free.
+    // validate_allocation() reports such overlaps.
+    //
+    fn used_areas(&self) -> BTreeMap<Device, BTreeMap<u64, u64>> {
+        let mut used_map = BTreeMap::new();
+
+        for lv in self.lvs.values() {
+            for (device, start, len) in lv::used_areas(lv) {
+                let area_len = used_map
+                    .entry(device)
+                    .or_insert(BTreeMap::new())
+                    .entry(start)
+                    .or_insert(0);
+                *area_len = max(*area_len, len);
+            }
+        }
+
+        used_map
+    }
+
+    // Returns unused areas in the format: {Device: {start: len} }
+    //
+    // e.g. assuming the same <Device 3:1> as above and it has 1000
+    // extents, calling free_areas would result in:
+    // {<Device 3:1>: {45: 2, 347: 653} }
+    //
+    fn free_areas(&self) -> BTreeMap<Device, BTreeMap<u64, u64>> {
+        let mut free_map = BTreeMap::new();
+
+        // Reserved areas are not free either
+        let mut used_map = self.used_areas();
+        for (dev, areas) in (self.reserved.0)
+            .lock()
+            .expect("reservation lock poisoned")
+            .iter()
+        {
+            used_map
+                .entry(*dev)
+                .or_insert(BTreeMap::new())
+                .extend(areas.iter().map(|(start, len)| (*start, *len)));
+        }
+
+        // Every PV, whether or not any LVs are on it
+        for (dev, pv) in &self.pvs {
+            let mut area_map = used_map.remove(dev).unwrap_or_default();
+
+            // Insert an entry to mark the end of the PV so the fold works
+            // correctly
+            area_map.insert(pv.pe_count, 0);
+
+            // An area inside an earlier one must not move prev_end back
+            let mut free: BTreeMap<u64, u64> = BTreeMap::new();
+            area_map.iter().fold(0, |prev_end, (start, len)| {
+                if prev_end < *start {
+                    free.insert(prev_end, start - prev_end);
+                }
+                max(prev_end, start + len)
+            });
+
+            // Merge free areas that abut, so they are seen as one
+            let mut merged: BTreeMap<u64, u64> = BTreeMap::new();
+            for (start, len) in free {
+                match merged.iter_mut().next_back() {
+                    Some((prev_start, prev_len)) if prev_start + *prev_len == start => {
+                        *prev_len += len;
+                    }
+                    _ => {
+                        merged.insert(start, len);
+                    }
+                }
+            }
+
+            if !merged.is_empty() {
+                free_map.insert(*dev, merged);
+            }
+        }
+
+        free_map
+    }
+
+    /// Find what occupies a physical extent on a PV. Returns the name of
+    /// the LV and the segment within it that use the extent, or None if
+    /// the extent is free.
+    ///
+    /// Useful for mapping e.g. a media error back to the affected LV.
+    pub fn what_is_at(&self, dev: Device, extent: u64) -> Option<(&str, &dyn segment::Segment)> {
+        for (lvname, lv) in &self.lvs {
+            for seg in &lv.segments {
+                for (seg_dev, start, len) in seg.used_areas() {
+                    if seg_dev == dev && extent >= start && extent < start + len {
+                        return Some((lvname, &**seg));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Read the contents of an extent directly from the device a PV is
+    /// on, e.g. to checksum it. `pv_devices` gives the path to use for
+    /// each PV's device.
+    pub fn read_extent(
+        &self,
+        pv_devices: &BTreeMap<Device, PathBuf>,
+        dev: Device,
+        extent: u64,
+    ) -> Result<Vec<u8>> {
+        let pv = self
+            .pvs
+            .get(&dev)
+            .ok_or_else(|| Error::Io(io::Error::new(Other, "PV not found in VG")))?;
+
+        if extent >= pv.pe_count {
+            return Err(Error::Io(io::Error::new(
+                Other,
+                "Extent out of range for PV",
+            )));
+        }
+
+        let path = pv_devices
+            .get(&dev)
+            .ok_or_else(|| Error::Io(io::Error::new(Other, "No path given for PV")))?;
+
+        let offset = (pv.pe_start + extent * self.extent_size) * SECTOR_SIZE as u64;
+        let mut buf = vec![0u8; (self.extent_size * SECTOR_SIZE as u64) as usize];
+
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Returns how many free areas there are of each size, in extents.
+    /// Many small areas suggest the VG's free space is fragmented.
+    pub fn free_size_histogram(&self) -> BTreeMap<u64, usize> {
+        let mut histogram = BTreeMap::new();
+
+        for areas in self.free_areas().values() {
+            for len in areas.values() {
+                *histogram.entry(*len).or_insert(0) += 1;
+            }
+        }
+
+        histogram
+    }
+
+    /// Returns how many copies of the VG's metadata there are: the
+    /// number of active metadata areas on the member PVs among headers.
+    /// With only one copy, losing it would lose the VG's configuration.
+    pub fn mda_copy_count(&self, headers: &[PvHeader]) -> usize {
+        headers
+            .iter()
+            .filter(|pvh| self.pvs.values().any(|pv| pv.id == pvh.uuid))
+            .map(|pvh| pvh.active_metadata_areas().map_or(0, |areas| areas.len()))
+            .sum()
+    }
+
+    /// Returns the number of PVs in the VG.
+    pub fn pv_count(&self) -> usize {
+        self.pvs.len()
+    }
+
+    /// Returns the number of LVs in the VG.
+    pub fn lv_count(&self) -> usize {
+        self.lvs.len()
+    }
+
+    /// Returns the name and extent count of each LV, largest first.
+    pub fn lvs_by_size(&self) -> Vec<(&str, u64)> {
+        let mut lvs: Vec<_> = self
+            .lvs
+            .values()
+            .map(|lv| (&lv.name[..], lv.used_extents()))
+            .collect();
+
+        lvs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        lvs
+    }
+
+    /// Returns an overview of the VG's size and contents.
+    pub fn summary(&self) -> VgSummary {
+        VgSummary {
+            pv_count: self.pv_count(),
+            lv_count: self.lv_count(),
+            extent_size: self.extent_size,
+            extents: self.extents(),
+            extents_free: self.extents_free(),
+        }
+    }
+
+    /// Returns a Graphviz DOT graph of the VG, with a node for each PV
+    /// and LV, and an edge from each LV to each PV it uses, labeled with
+    /// how many of the PV's extents it uses.
+    pub fn to_dot(&self) -> String {
+        let mut dot = format!("digraph \"{}\" {{\n", self.name);
+
+        for (dev, pv) in &self.pvs {
+            dot.push_str(&format!(
+                "    \"pv {}:{}\" [shape=box, label=\"{}:{}\\n{} extents\"];\n",
+                dev.major, dev.minor, dev.major, dev.minor, pv.pe_count
+            ));
+        }
+
+        for (lvname, lv) in &self.lvs {
+            dot.push_str(&format!("    \"lv {}\" [label=\"{}\"];\n", lvname, lvname));
+
+            let mut extents_per_pv = BTreeMap::new();
+            for (dev, _, len) in lv::used_areas(lv) {
+                *extents_per_pv.entry(dev).or_insert(0) += len;
+            }
+            for (dev, count) in extents_per_pv {
+                dot.push_str(&format!(
+                    "    \"lv {}\" -> \"pv {}:{}\" [label=\"{}\"];\n",
+                    lvname, dev.major, dev.minor, count
+                ));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Returns the largest number of stripes an LV of the given size
+    /// could be striped across, or 0 if there is not enough space for it
+    /// at all.
+    ///
+    /// Each stripe needs its own PV with a contiguous free area large
+    /// enough to hold its share of the extents.
+    pub fn suggest_stripe_count(&self, extents: u64) -> usize {
+        let largest_free: Vec<u64> = self
+            .free_areas()
+            .values()
+            .map(|areas| areas.values().cloned().max().unwrap_or(0))
+            .collect();
+
+        for stripes in (1..largest_free.len() + 1).rev() {
+            let per_stripe = (extents + stripes as u64 - 1) / stripes as u64;
+            let capable = largest_free.iter().filter(|&&x| x >= per_stripe).count();
+            if capable >= stripes {
+                return stripes;
+            }
+        }
+
+        0
+    }
+
+    /// Returns a list of PV Devices that make up the VG.
+    pub fn pv_list(&self) -> Vec<Device> {
+        self.pvs.keys().map(|key| *key).collect()
+    }
+
+    /// Returns a reference to the PV matching the Device.
+    pub fn pv_get(&self, dev: Device) -> Option<&PV> {
+        self.pvs.get(&dev)
+    }
+
+    /// Returns a list of the names of LVs in the VG.
+    pub fn lv_list(&self) -> Vec<String> {
+        self.lvs.keys().map(|key| key.clone()).collect()
+    }
+
+    /// Returns a reference to the LV matching the name.
+    pub fn lv_get(&self, name: &str) -> Option<&LV> {
+        self.lvs.get(name)
+    }
+
+    /// Returns the name of the VG.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the UUID of the VG.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the VG's tags.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns the VG's allocation policy, if one other than the default
+    /// has been set. It applies to LVs without a policy of their own.
+    pub fn alloc_policy(&self) -> Option<&str> {
+        self.alloc_policy.as_ref().map(|x| &x[..])
+    }
+
+    /// Returns how many 512-byte sectors make up each extent in the VG.
+    pub fn extent_size(&self) -> u64 {
+        self.extent_size
+    }
+
+    /// Given the UUIDs of the PVs that are present, determine whether
+    /// the VG is completely, partially, or not at all available.
+    pub fn availability(&self, present_pvs: &BTreeSet<String>) -> VgAvailability {
+        let present = self
+            .pvs
+            .values()
+            .filter(|pv| present_pvs.contains(&pv.id))
+            .count();
+
+        if present == self.pvs.len() {
+            VgAvailability::Complete
+        } else if present == 0 {
+            VgAvailability::Unavailable
+        } else {
+            VgAvailability::Partial
+        }
+    }
+
+    /// Returns the VG's status, parsed into known values.
+    pub fn status_set(&self) -> StatusSet<VgStatus> {
+        StatusSet::from_strings(&self.status)
+    }
+
+    /// Whether the VG's metadata may be written.
+    pub fn is_writable(&self) -> bool {
+        self.status_set().contains(VgStatus::Write)
+    }
+
+    /// Whether PVs may be added to or removed from the VG.
+    pub fn is_resizeable(&self) -> bool {
+        self.status_set().contains(VgStatus::Resizeable)
+    }
+
+    /// Whether the VG has been exported.
+    pub fn is_exported(&self) -> bool {
+        self.status_set().contains(VgStatus::Exported)
+    }
+
+    /// Why lvmetad could not be told about the last commit, if it could
+    /// not. The metadata on disk is correct either way, but until lvmetad
+    /// is updated, LVM's tools may see its old copy.
+    pub fn lvmetad_error(&self) -> Option<&str> {
+        self.lvmetad_error.as_ref().map(|x| x.as_str())
+    }
+}
+
+// Check that a PV is not in a VG, i.e. that it has no text metadata.
+fn check_pv_unused(pvh: &PvHeader) -> Result<()> {
+    if let Ok(metadata) = pvh.read_metadata() {
+        // Find the textmap for the vg, among all the other stuff.
+        // (It's the only textmap.)
+        let mut vg_name = Cow::Borrowed("<unknown>");
+        for (key, value) in metadata {
+            match value {
+                Entry::TextMap(_) => {
+                    vg_name = Cow::Owned(key);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        return Err(Error::Io(io::Error::new(
+            Other,
+            format!("PV already in VG {}", vg_name),
+        )));
+    }
+
+    Ok(())
+}
+
+// The names of all device-mapper devices.
+fn active_dm_names() -> Result<BTreeSet<String>> {
+    let dm = DM::new()?;
+
+    Ok(dm
+        .list_devices()?
+        .into_iter()
+        .map(|(name, _, _)| String::from_utf8_lossy(name.as_bytes()).into_owned())
+        .collect())
+}
+
+// Rename a device-mapper device.
+fn rename_dm_device(old: &str, new: &str) -> Result<()> {
+    let dm = DM::new()?;
+    dm.device_rename(DmName::new(old)?, &DevId::Name(DmName::new(new)?))?;
+
+    Ok(())
+}
+
+// Returns the device and starting extent of the first area in free, in
+// the format returned by VG::free_areas(), with at least extent_count
+// extents that the constraints allow.
+fn find_contiguous_area(
+    free: &BTreeMap<Device, BTreeMap<u64, u64>>,
+    extent_count: u64,
+    constraints: &AllocConstraints,
+) -> Result<(Device, u64)> {
+    for (dev, areas) in free {
+        if constraints.exclude_pvs.contains(dev) {
+            continue;
+        }
+        for (&start, &len) in areas {
+            if len >= extent_count {
+                return Ok((*dev, start));
+            }
+        }
+    }
+
+    Err(Error::NoSpace)
+}
+
+// Returns areas from free, in the format (Device, start, len), that
+// together hold extent_count extents, taking the first that the
+// constraints allow.
+fn find_areas(
+    free: &BTreeMap<Device, BTreeMap<u64, u64>>,
+    extent_count: u64,
+    constraints: &AllocConstraints,
+) -> Result<Vec<(Device, u64, u64)>> {
+    let mut areas = Vec::new();
+    let mut needed = extent_count;
+
+    for (dev, dev_free) in free {
+        if constraints.exclude_pvs.contains(dev) {
+            continue;
+        }
+        for (&start, &len) in dev_free {
+            if needed == 0 {
+                return Ok(areas);
+            }
+            let len = min(len, needed);
+            areas.push((*dev, start, len));
+            needed -= len;
+        }
+    }
+
+    if needed != 0 {
+        return Err(Error::NoSpace);
+    }
+
+    Ok(areas)
+}
+
+// Remove len extents, starting at start on dev, from free, in the
+// format returned by VG::free_areas(). They must all be free.
+fn take_free_area(
+    free: &mut BTreeMap<Device, BTreeMap<u64, u64>>,
+    dev: Device,
+    start: u64,
+    len: u64,
+) {
+    let areas = free.get_mut(&dev).expect("area must be free");
+    let (area_start, area_len) = areas
+        .range(..=start)
+        .next_back()
+        .map(|(&start, &len)| (start, len))
+        .expect("area must be free");
+
+    areas.remove(&area_start);
+    if start > area_start {
+        areas.insert(area_start, start - area_start);
+    }
+    if area_start + area_len > start + len {
+        areas.insert(start + len, area_start + area_len - (start + len));
+    }
+}
+
+/// A set of changes to a VG that are written to disk together, with a
+/// single seqno increment, when the Transaction is committed. Until then,
+/// the VG's methods, which are all available on the Transaction, only
+/// change the VG in memory.
+///
+/// If the Transaction is dropped without being committed, or committing
+/// fails, the VG reverts to how it was before the Transaction began.
+///
+/// Changes to device-mapper devices could not be reverted that way. LVs
+/// created during a Transaction are activated only once it has been
+/// committed, and methods that would change an existing device fail
+/// instead: e.g. activating or deactivating an LV, or removing, resizing,
+/// or renaming an active one, or creating a thin pool or snapshot. So do
+/// the methods that write to disk directly: `VG::commit`,
+/// `commit_with_seqno`, `normalize_seqno`, and `ensure_mda_capacity`.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    vg: &'a mut VG,
+    original: Option<VG>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Write all the changes made in the Transaction to disk, with a
+    /// single seqno increment, then activate the LVs created in it.
+    ///
+    /// If an LV can't be activated, the error is returned, but the
+    /// metadata has been written by then, so the VG is not reverted: the
+    /// LV and any others not yet activated are left inactive, and can be
+    /// activated with `activate_lv`.
+    pub fn commit(mut self) -> Result<()> {
+        self.vg.in_transaction = false;
+        self.vg.commit()?;
+        self.original = None;
+
+        for name in replace(&mut self.vg.pending_activations, Vec::new()) {
+            // It may have been created and then removed again
+            if let Some(lv) = self.vg.lvs.get(&name) {
+                self.vg.activate_new_lv(lv)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = VG;
+
+    fn deref(&self) -> &VG {
+        self.vg
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    fn deref_mut(&mut self) -> &mut VG {
+        self.vg
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if let Some(original) = self.original.take() {
+            *self.vg = original;
+        }
+    }
+}
+
+/// Assembles a `VG` in memory, using reasonable defaults for anything
+/// not explicitly given.
+///
+/// Nothing is written to disk until the resulting VG is committed.
+#[derive(Debug)]
+pub struct VgBuilder {
+    name: String,
+    id: String,
+    extent_size: u64,
+    pvs: BTreeMap<Device, PV>,
+    lvs: BTreeMap<String, LV>,
+}
+
+impl VgBuilder {
+    /// Start building a VG with the given name, a new UUID, and the
+    /// default extent size.
+    pub fn new(name: &str) -> VgBuilder {
+        VgBuilder {
+            name: name.to_string(),
+            id: make_uuid(),
+            extent_size: DEFAULT_EXTENT_SIZE,
+            pvs: BTreeMap::new(),
+            lvs: BTreeMap::new(),
+        }
+    }
+
+    /// Use the given UUID instead of generating one.
+    pub fn id(mut self, id: &str) -> VgBuilder {
+        self.id = id.to_string();
+        self
+    }
+
+    /// Set the size of each extent, in 512-byte sectors.
+    pub fn extent_size(mut self, extent_size: u64) -> VgBuilder {
+        self.extent_size = extent_size;
+        self
+    }
+
+    /// Add a PV.
+    pub fn pv(mut self, pv: PV) -> VgBuilder {
+        self.pvs.insert(pv.device, pv);
+        self
+    }
+
+    /// Add an LV.
+    pub fn lv(mut self, lv: LV) -> VgBuilder {
+        self.lvs.insert(lv.name.clone(), lv);
+        self
+    }
+
+    /// Create the VG.
+    pub fn build(self) -> VG {
+        VG {
+            name: self.name,
+            id: self.id,
+            seqno: 0,
+            format: METADATA_FORMAT.to_string(),
+            status: vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "RESIZEABLE".to_string(),
+            ],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            extent_size: self.extent_size,
+            max_lv: 0,
+            max_pv: 0,
+            metadata_copies: 0,
+            alloc_policy: None,
+            pvs: self.pvs,
+            lvs: self.lvs,
+            reserved: ReservedAreas::default(),
+            wipe_signatures: false,
+            creation_host: None,
+            archive_dir: None,
+            in_transaction: false,
+            pending_activations: Vec::new(),
+            lvmetad_error: None,
+        }
+    }
+}
+
+/// The VG's metadata, as in its section of the on-disk metadata.
+pub fn to_textmap(vg: &VG) -> LvmTextMap {
+    let mut map = LvmTextMap::new();
+
+    map.insert("id".to_string(), Entry::String(vg.id.clone()));
+    map.insert("seqno".to_string(), Entry::Number(vg.seqno as i64));
+    map.insert("format".to_string(), Entry::String(vg.format.clone()));
+
+    map.insert("max_pv".to_string(), Entry::Number(0));
+    map.insert("max_lv".to_string(), Entry::Number(0));
+
+    map.insert(
+        "status".to_string(),
+        Entry::List(Box::new(
+            vg.status.iter().map(|x| Entry::String(x.clone())).collect(),
+        )),
+    );
+
+    map.insert(
+        "flags".to_string(),
+        Entry::List(Box::new(
+            vg.flags.iter().map(|x| Entry::String(x.clone())).collect(),
+        )),
+    );
+
+    if !vg.tags.is_empty() {
+        map.insert(
+            "tags".to_string(),
+            Entry::List(Box::new(
+                vg.tags.iter().map(|x| Entry::String(x.clone())).collect(),
+            )),
+        );
+    }
+
+    map.insert(
+        "extent_size".to_string(),
+        Entry::Number(vg.extent_size as i64),
+    );
+    map.insert(
+        "metadata_copies".to_string(),
+        Entry::Number(vg.metadata_copies as i64),
+    );
+
+    if let Some(ref policy) = vg.alloc_policy {
+        map.insert(
+            "allocation_policy".to_string(),
+            Entry::String(policy.clone()),
+        );
+    }
+
+    // See comment in from_textmap() - we need to assign ordinals to
+    // the PV map so the textmap can use "pv0"-style strings to link
+    // pvs with LV stripes.
+    let dev_to_idx: BTreeMap<Device, usize> = vg
+        .pvs
+        .values()
+        .enumerate()
+        .map(|(num, pv)| (pv.device, num))
+        .collect();
+
+    map.insert(
+        "physical_volumes".to_string(),
+        Entry::TextMap(Box::new(
+            vg.pvs
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        format!("pv{}", dev_to_idx.get(k).unwrap()),
+                        Entry::TextMap(Box::new(pv::to_textmap(v))),
+                    )
+                })
+                .collect(),
+        )),
+    );
+
+    if !vg.lvs.is_empty() {
+        map.insert(
+            "logical_volumes".to_string(),
+            Entry::TextMap(Box::new(
+                vg.lvs
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            k.clone(),
+                            Entry::TextMap(Box::new(lv::to_textmap(v, &dev_to_idx))),
+                        )
+                    })
+                    .collect(),
+            )),
+        );
+    }
+
+    map
+}
+
+// A JSON array of strings.
+fn strings_to_json(strings: &[String]) -> Json {
+    Json::Array(strings.iter().map(|x| x.to_json()).collect())
+}
+
+// The VG as JSON, for tools other than LVM. This is built directly from
+// the VG rather than from its textmap, so it stays the same if the
+// on-disk format changes. Sizes in extents are also given in bytes.
+impl ToJson for VG {
+    fn to_json(&self) -> Json {
+        let extent_bytes = self.extent_size * SECTOR_SIZE as u64;
+
+        let pvs = self
+            .pvs
+            .values()
+            .map(|pv| {
+                let mut obj = BTreeMap::new();
+                obj.insert("id".to_string(), pv.id.to_json());
+                obj.insert(
+                    "device".to_string(),
+                    format!("{}:{}", pv.device.major, pv.device.minor).to_json(),
+                );
+                obj.insert("status".to_string(), strings_to_json(&pv.status));
+                obj.insert("flags".to_string(), strings_to_json(&pv.flags));
+                obj.insert("tags".to_string(), strings_to_json(&pv.tags));
+                obj.insert("dev_size".to_string(), pv.dev_size.to_json());
+                obj.insert(
+                    "dev_size_bytes".to_string(),
+                    (pv.dev_size * SECTOR_SIZE as u64).to_json(),
+                );
+                obj.insert("pe_start".to_string(), pv.pe_start.to_json());
+                obj.insert("pe_count".to_string(), pv.pe_count.to_json());
+                obj.insert(
+                    "pe_count_bytes".to_string(),
+                    (pv.pe_count * extent_bytes).to_json(),
+                );
+                Json::Object(obj)
+            })
+            .collect();
+
+        let lvs = self
+            .lvs
+            .values()
+            .map(|lv| {
+                let segments = lv
+                    .segments
+                    .iter()
+                    .map(|seg| {
+                        let mut json = seg.to_json();
+                        if let Json::Object(ref mut obj) = json {
+                            obj.insert(
+                                "extent_count_bytes".to_string(),
+                                (seg.extent_count() * extent_bytes).to_json(),
+                            );
+                        }
+                        json
+                    })
+                    .collect();
+
+                let mut obj = BTreeMap::new();
+                obj.insert("name".to_string(), lv.name.to_json());
+                obj.insert("id".to_string(), lv.id.to_json());
+                obj.insert("status".to_string(), strings_to_json(&lv.status));
+                obj.insert("flags".to_string(), strings_to_json(&lv.flags));
+                obj.insert("tags".to_string(), strings_to_json(&lv.tags));
+                obj.insert("creation_host".to_string(), lv.creation_host.to_json());
+                obj.insert("creation_time".to_string(), lv.creation_time.to_json());
+                if let Some(ref policy) = lv.alloc_policy {
+                    obj.insert("allocation_policy".to_string(), policy.to_json());
+                }
+                obj.insert("extent_count".to_string(), lv.used_extents().to_json());
+                obj.insert(
+                    "extent_count_bytes".to_string(),
+                    (lv.used_extents() * extent_bytes).to_json(),
+                );
+                obj.insert("segments".to_string(), Json::Array(segments));
+                Json::Object(obj)
+            })
+            .collect();
+
+        let mut obj = BTreeMap::new();
+        obj.insert("name".to_string(), self.name.to_json());
+        obj.insert("id".to_string(), self.id.to_json());
+        obj.insert("seqno".to_string(), self.seqno.to_json());
+        obj.insert("format".to_string(), self.format.to_json());
+        obj.insert("status".to_string(), strings_to_json(&self.status));
+        obj.insert("flags".to_string(), strings_to_json(&self.flags));
+        obj.insert("tags".to_string(), strings_to_json(&self.tags));
+        obj.insert("extent_size".to_string(), self.extent_size.to_json());
+        obj.insert("extent_size_bytes".to_string(), extent_bytes.to_json());
+        obj.insert("max_lv".to_string(), self.max_lv.to_json());
+        obj.insert("max_pv".to_string(), self.max_pv.to_json());
+        obj.insert(
+            "metadata_copies".to_string(),
+            self.metadata_copies.to_json(),
+        );
+        if let Some(ref policy) = self.alloc_policy {
+            obj.insert("allocation_policy".to_string(), policy.to_json());
+        }
+        obj.insert("pvs".to_string(), Json::Array(pvs));
+        obj.insert("lvs".to_string(), Json::Array(lvs));
+        Json::Object(obj)
+    }
+}
+
+// A textmap of the VG with fields that don't affect its configuration
+// removed.
+fn structural_textmap(vg: &VG) -> LvmTextMap {
+    let mut map = to_textmap(vg);
+
+    map.remove("seqno");
+
+    if let Some(&mut Entry::TextMap(ref mut lvs)) = map.get_mut("logical_volumes") {
+        for lv in lvs.values_mut() {
+            if let &mut Entry::TextMap(ref mut lv_map) = lv {
+                lv_map.remove("creation_host");
+                lv_map.remove("creation_time");
+            }
+        }
+    }
+
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{can_use_dm, can_use_loop, LoopDevice, TestDir, TestImage};
+
+    fn test_pv(minor: u32, pe_count: u64) -> PV {
+        PV {
+            id: make_uuid(),
+            device: Device { major: 7, minor },
+            status: vec!["ALLOCATABLE".to_string()],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            dev_size: (pe_count + 1) * DEFAULT_EXTENT_SIZE,
+            pe_start: DEFAULT_EXTENT_SIZE,
+            pe_count,
+        }
+    }
+
+    // A linear LV mapping each of areas, in the format (minor, start,
+    // len), in turn.
+    fn test_lv(name: &str, policy: Option<&str>, areas: &[(u32, u64, u64)]) -> LV {
+        let mut lv_extent = 0;
+        let mut segments: Vec<Box<dyn segment::Segment>> = Vec::new();
+        for &(minor, start, len) in areas {
+            segments.push(Box::new(segment::StripedSegment {
+                start_extent: lv_extent,
+                extent_count: len,
+                stripes: vec![(Device { major: 7, minor }, start)],
+                stripe_size: None,
+            }));
+            lv_extent += len;
+        }
+
+        LV {
+            name: name.to_string(),
+            id: make_uuid(),
+            status: vec!["READ".to_string(), "WRITE".to_string()],
+            flags: Vec::new(),
+            tags: Vec::new(),
+            creation_host: "test".to_string(),
+            creation_time: 0,
+            alloc_policy: policy.map(|x| x.to_string()),
+            segments,
+            device: None,
+        }
+    }
+
+    fn dev(minor: u32) -> Device {
+        Device { major: 7, minor }
+    }
+
+    // LV's == only compares names, so tests compare this instead: all
+    // of the LV that is written to disk.
+    fn lv_json(lv: &LV) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert("name".to_string(), lv.name.to_json());
+        obj.insert("id".to_string(), lv.id.to_json());
+        obj.insert("status".to_string(), strings_to_json(&lv.status));
+        obj.insert("flags".to_string(), strings_to_json(&lv.flags));
+        obj.insert("tags".to_string(), strings_to_json(&lv.tags));
+        obj.insert("creation_host".to_string(), lv.creation_host.to_json());
+        obj.insert("creation_time".to_string(), lv.creation_time.to_json());
+        obj.insert("allocation_policy".to_string(), lv.alloc_policy.to_json());
+        obj.insert(
+            "segments".to_string(),
+            Json::Array(lv.segments.iter().map(|seg| seg.to_json()).collect()),
+        );
+        Json::Object(obj)
+    }
+
+    fn lvs_json(lvs: &BTreeMap<String, LV>) -> Vec<Json> {
+        lvs.values().map(lv_json).collect()
+    }
+
+    #[test]
+    fn reservations_hold_extents_until_dropped() {
+        let mut vg = VgBuilder::new("vg0").pv(test_pv(0, 10)).build();
+
+        let first = vg.reserve(4).unwrap();
+        let second = vg.reserve(4).unwrap();
+        assert_eq!((first.device(), first.start()), (dev(0), 0));
+        assert_eq!(second.start(), 4);
+        assert!(vg.reserve(4).is_err());
+
+        drop(first);
+        assert_eq!(vg.reserve(4).unwrap().start(), 0);
+
+        // A reservation can only be used in the VG it came from
+        let mut other = VgBuilder::new("vg1").pv(test_pv(0, 10)).build();
+        assert!(other.lv_create_reserved("lv0", second).is_err());
+        assert!(other.lv_list().is_empty());
+    }
+
+    #[test]
+    fn linear_all_needs_more_than_the_slack() {
+        let mut vg = VgBuilder::new("vg0").pv(test_pv(0, 10)).build();
+
+        assert!(vg.lv_create_linear_all("lv0", 10).is_err());
+        assert!(vg.lv_create_linear_all("lv0", 20).is_err());
+        assert!(vg.lv_list().is_empty());
+    }
+
+    #[test]
+    fn alloc_policy_strings() {
+        assert_eq!(
+            "contiguous".parse::<AllocPolicy>().unwrap(),
+            AllocPolicy::Contiguous
+        );
+        assert_eq!(
+            "cling_by_tags".parse::<AllocPolicy>().unwrap(),
+            AllocPolicy::Cling
+        );
+        assert_eq!(
+            "anywhere".parse::<AllocPolicy>().unwrap(),
+            AllocPolicy::Anywhere
+        );
+        assert!("inherit".parse::<AllocPolicy>().is_err());
+    }
+
+    #[test]
+    fn alloc_policy_inherited_from_vg() {
+        let mut vg = VgBuilder::new("vg0").pv(test_pv(0, 100)).build();
+        let none = AllocConstraints::default();
+        let lv = test_lv("lv0", Some("inherit"), &[]);
+
+        assert_eq!(vg.alloc_policy_for(&none, Some(&lv)), AllocPolicy::Normal);
+        vg.alloc_policy = Some("anywhere".to_string());
+        assert_eq!(vg.alloc_policy_for(&none, Some(&lv)), AllocPolicy::Anywhere);
+
+        let lv = test_lv("lv0", Some("cling"), &[]);
+        assert_eq!(vg.alloc_policy_for(&none, Some(&lv)), AllocPolicy::Cling);
+
+        let contiguous = AllocConstraints {
+            policy: Some(AllocPolicy::Contiguous),
+            ..Default::default()
+        };
+        assert_eq!(
+            vg.alloc_policy_for(&contiguous, Some(&lv)),
+            AllocPolicy::Contiguous
+        );
+    }
+
+    #[test]
+    fn contiguous_extends_after_last_extent() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", Some("contiguous"), &[(0, 0, 10)]))
+            .lv(test_lv("lv1", None, &[(0, 20, 10)]))
+            .build();
+        let none = AllocConstraints::default();
+
+        let lv0 = vg.lv_get("lv0").unwrap();
+        assert_eq!(
+            vg.allocate(10, &none, Some(lv0)).unwrap(),
+            vec![(dev(0), 10, 10)]
+        );
+        assert!(vg.allocate(11, &none, Some(lv0)).is_err());
+    }
+
+    #[test]
+    fn cling_and_normal_prefer_the_lvs_pvs() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 12))
+            .lv(test_lv("lv0", Some("cling"), &[(1, 0, 10)]))
+            .lv(test_lv("lv1", None, &[(1, 10, 1)]))
+            .build();
+        let none = AllocConstraints::default();
+        let normal = AllocConstraints {
+            policy: Some(AllocPolicy::Normal),
+            ..Default::default()
+        };
+
+        // A new LV goes in the first big enough area
+        assert_eq!(vg.allocate(1, &none, None).unwrap(), vec![(dev(0), 0, 1)]);
+
+        let lv0 = vg.lv_get("lv0").unwrap();
+        assert_eq!(
+            vg.allocate(1, &none, Some(lv0)).unwrap(),
+            vec![(dev(1), 11, 1)]
+        );
+        assert!(vg.allocate(2, &none, Some(lv0)).is_err());
+        assert_eq!(
+            vg.allocate(2, &normal, Some(lv0)).unwrap(),
+            vec![(dev(0), 0, 2)]
+        );
+    }
+
+    #[test]
+    fn free_areas_merge_and_skip_reservations() {
+        // lv0's empty area at 20 splits the free space in two
+        let mut vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 10))
+            .lv(test_lv("lv0", None, &[(0, 0, 10), (0, 20, 0)]))
+            .lv(test_lv("lv1", None, &[(0, 30, 10), (1, 0, 10)]))
+            .build();
+        let expected: BTreeMap<u64, u64> = vec![(10, 20), (40, 60)].into_iter().collect();
+        assert_eq!(vg.free_areas()[&dev(0)], expected);
+        assert!(!vg.free_areas().contains_key(&dev(1)));
+
+        let reservation = vg.reserve(5).unwrap();
+        let reserved: BTreeMap<u64, u64> = vec![(15, 15), (40, 60)].into_iter().collect();
+        assert_eq!(vg.free_areas()[&dev(0)], reserved);
+
+        drop(reservation);
+        assert_eq!(vg.free_areas()[&dev(0)], expected);
+    }
+
+    #[test]
+    fn areas_sharing_a_start() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 10)]))
+            .lv(test_lv("lv1", None, &[(0, 0, 20)]))
+            .build();
+
+        // The longer area's extents are not free
+        let expected: BTreeMap<u64, u64> = vec![(20, 80)].into_iter().collect();
+        assert_eq!(vg.free_areas()[&dev(0)], expected);
+        assert!(vg.validate_allocation().is_err());
+    }
+
+    #[test]
+    fn validate_finds_problems() {
+        let good = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 10), (0, 50, 10)]))
+            .lv(test_lv("lv1", None, &[(0, 10, 40)]))
+            .build();
+        good.validate().unwrap();
+
+        let overlap = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 10)]))
+            .lv(test_lv("lv1", None, &[(0, 5, 10)]))
+            .build();
+        assert!(overlap.validate().is_err());
+
+        let past_end = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 95, 10)]))
+            .build();
+        assert!(past_end.validate().is_err());
+
+        let missing_pv = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(1, 0, 10)]))
+            .build();
+        assert!(missing_pv.validate().is_err());
+
+        // A gap between segments
+        let mut lv = test_lv("lv0", None, &[(0, 0, 10)]);
+        lv.segments.push(Box::new(segment::StripedSegment {
+            start_extent: 20,
+            extent_count: 10,
+            stripes: vec![(dev(0), 10)],
+            stripe_size: None,
+        }));
+        let gap = VgBuilder::new("vg0").pv(test_pv(0, 100)).lv(lv).build();
+        gap.validate_allocation().unwrap();
+        assert!(gap.validate().is_err());
+    }
+
+    #[test]
+    fn take_free_area_splits() {
+        let mut free = BTreeMap::new();
+        free.insert(dev(0), vec![(0, 10), (20, 10)].into_iter().collect());
+
+        take_free_area(&mut free, dev(0), 22, 3);
+        take_free_area(&mut free, dev(0), 0, 10);
+
+        let expected: BTreeMap<u64, u64> = vec![(20, 2), (25, 5)].into_iter().collect();
+        assert_eq!(free[&dev(0)], expected);
+    }
+
+    // The seqno of the metadata on a PV.
+    fn disk_seqno(path: &Path) -> i64 {
+        let map = PvHeader::find_in_dev(path)
+            .unwrap()
+            .read_metadata()
+            .unwrap();
+        vg_seqno(&map).unwrap()
+    }
+
+    #[test]
+    fn commit_with_seqno_writes_given_seqno() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-seqno", vec![loop0.path()]).unwrap();
+        let seqno = disk_seqno(loop0.path());
+
+        vg.commit_with_seqno(seqno as u64 + 10).unwrap();
+        assert_eq!(disk_seqno(loop0.path()), seqno + 10);
+
+        // Going backwards, or writing the same seqno again, is refused
+        assert!(vg.commit_with_seqno(seqno as u64 + 5).is_err());
+        assert!(vg.commit_with_seqno(seqno as u64 + 10).is_err());
+        assert_eq!(disk_seqno(loop0.path()), seqno + 10);
+
+        vg.commit().unwrap();
+        assert_eq!(disk_seqno(loop0.path()), seqno + 11);
+    }
+
+    #[test]
+    fn set_lv_permission_updates_status() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-perm", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let lv = test_lv("lv0", None, &[(dev0.minor, 0, 1)]);
+        vg.lvs.insert("lv0".to_string(), lv);
+        vg.commit().unwrap();
+
+        let disk_status = || -> Vec<String> {
+            let map = pvh.read_metadata().unwrap();
+            let lv_map = map
+                .textmap_from_textmap("melvin-test-perm")
+                .and_then(|vg_map| vg_map.textmap_from_textmap("logical_volumes"))
+                .and_then(|lvs| lvs.textmap_from_textmap("lv0"))
+                .unwrap();
+            lv_map
+                .list_from_textmap("status")
+                .unwrap()
+                .iter()
+                .filter_map(|x| match x {
+                    &Entry::String(ref x) => Some(x.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        vg.set_lv_permission("lv0", false).unwrap();
+        let lv = vg.lv_get("lv0").unwrap();
+        assert!(!lv.is_writable());
+        assert!(!lv.status.iter().any(|x| x == "WRITE"));
+        assert!(lv.status.iter().any(|x| x == "READ"));
+        assert!(!disk_status().iter().any(|x| x == "WRITE"));
+
+        vg.set_lv_permission("lv0", true).unwrap();
+        assert!(vg.lv_get("lv0").unwrap().is_writable());
+        assert!(disk_status().iter().any(|x| x == "WRITE"));
+
+        assert!(vg.set_lv_permission("missing", false).is_err());
+    }
+
+    #[test]
+    fn audit_sizes_reports_shrunk_pv() {
+        let image = TestImage::new(16 << 20);
+        let pvh = PvHeader::initialize(image.path()).unwrap();
+        let mut missing = pvh.clone();
+        missing.dev_path = image.path().with_extension("missing");
+
+        // The metadata claims more extents than the 16MiB device holds
+        let mut pv = test_pv(0, 100);
+        pv.id = pvh.uuid.clone();
+        let vg = VgBuilder::new("vg0").pv(pv).build();
+
+        let found = vg.audit_sizes(&[pvh.clone(), missing]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].device, dev(0));
+        assert_eq!(found[0].recorded_pe_count, 100);
+        assert!(found[0].actual_pe_count < 100);
+        assert!(found[0].shrunk());
+
+        // A PV that's not in the VG is ignored
+        let other = VgBuilder::new("vg1").pv(test_pv(1, 100)).build();
+        assert!(other.audit_sizes(&[pvh]).is_empty());
+    }
+
+    #[test]
+    fn stripes_on_distinct_pvs() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 100))
+            .pv(test_pv(2, 100))
+            .build();
+
+        let areas = vg.allocate_stripes(3, 20).unwrap();
+        let mut devs: Vec<Device> = areas.iter().map(|&(dev, _)| dev).collect();
+        devs.sort();
+        devs.dedup();
+        assert_eq!(devs, vec![dev(0), dev(1), dev(2)]);
+
+        // Each PV has room for several stripes, but only one may go on each
+        assert!(vg.allocate_stripes(4, 20).is_err());
+    }
+
+    #[test]
+    fn pv_resize_adds_extents() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-pvresize", vec![loop0.path()]).unwrap();
+        let extents = vg.extents();
+
+        loop0.set_len(128 << 20);
+        let mut pvh = PvHeader::find_in_dev(loop0.path()).unwrap();
+        vg.pv_resize(&mut pvh).unwrap();
+        assert_eq!(pvh.size, 128 << 20);
+        assert_eq!(vg.extents(), extents + (64 << 20) / (vg.extent_size * 512));
+
+        loop0.set_len(96 << 20);
+        let mut pvh = PvHeader::find_in_dev(loop0.path()).unwrap();
+        assert!(vg.pv_resize(&mut pvh).is_err());
+        assert_eq!(vg.extents(), extents + (64 << 20) / (vg.extent_size * 512));
+    }
+
+    #[test]
+    fn to_json_shape() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 2), (0, 10, 3)]))
+            .build();
+        let extent_bytes = vg.extent_size * 512;
+
+        let json = vg.to_json();
+        let obj = json.as_object().unwrap();
+        for key in &[
+            "name",
+            "id",
+            "seqno",
+            "format",
+            "extent_size",
+            "extent_size_bytes",
+            "pvs",
+            "lvs",
+        ] {
+            assert!(obj.contains_key(*key), "missing {}", key);
+        }
+        assert_eq!(obj["name"].as_string(), Some("vg0"));
+        assert_eq!(obj["extent_size_bytes"].as_u64(), Some(extent_bytes));
+
+        let pvs = obj["pvs"].as_array().unwrap();
+        assert_eq!(pvs.len(), 1);
+        assert_eq!(pvs[0].find("device").unwrap().as_string(), Some("7:0"));
+        assert_eq!(
+            pvs[0].find("pe_count_bytes").unwrap().as_u64(),
+            Some(100 * extent_bytes)
+        );
+
+        let lvs = obj["lvs"].as_array().unwrap();
+        assert_eq!(lvs.len(), 1);
+        let lv = &lvs[0];
+        assert_eq!(lv.find("name").unwrap().as_string(), Some("lv0"));
+        assert_eq!(lv.find("extent_count").unwrap().as_u64(), Some(5));
+        assert_eq!(
+            lv.find("extent_count_bytes").unwrap().as_u64(),
+            Some(5 * extent_bytes)
+        );
+        let segments = lv.find("segments").unwrap().as_array().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(
+            segments[1].find("extent_count_bytes").unwrap().as_u64(),
+            Some(3 * extent_bytes)
+        );
+    }
+
+    #[test]
+    fn from_json_round_trip() {
+        let mut vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 100))
+            .lv(test_lv("lv0", Some("cling"), &[(0, 0, 2), (1, 10, 3)]))
+            .build();
+        vg.tags.push("tag0".to_string());
+
+        let parsed = VG::from_json(&vg.to_json().to_string()).unwrap();
+        assert_eq!(parsed, vg);
+        assert_eq!(lvs_json(&parsed.lvs), lvs_json(&vg.lvs));
+
+        assert!(VG::from_json("{").is_err());
+        assert!(VG::from_json("[]").is_err());
+
+        // Byte sizes that disagree with the extent size
+        let mut json = vg.to_json();
+        if let Json::Object(ref mut obj) = json {
+            obj.insert("extent_size_bytes".to_string(), Json::U64(1));
+        }
+        assert!(VG::from_json(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn backup_restore_round_trip() {
+        let mut vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 2), (1, 10, 3)]))
+            .lv(test_lv("lv1", None, &[(1, 0, 10)]))
+            .build();
+        vg.seqno = 12;
+
+        let dir = TestDir::new();
+        let path = dir.path().join("vg0.vg");
+        vg.backup_to_file(&path).unwrap();
+
+        let restored = VG::restore_from_file(&path).unwrap();
+        assert_eq!(lvs_json(&restored.lvs), lvs_json(&vg.lvs));
+        assert_eq!(restored.seqno, 12);
+    }
+
+    // Rewrite the metadata on a PV with the given seqno.
+    fn set_disk_seqno(pvh: &mut PvHeader, seqno: i64) {
+        let mut map = pvh.read_metadata().unwrap();
+        for value in map.values_mut() {
+            if let Entry::TextMap(ref mut vg_map) = *value {
+                vg_map.insert("seqno".to_string(), Entry::Number(seqno));
+            }
+        }
+        pvh.write_metadata_with_options(&map, &WriteOptions::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn normalize_seqno_takes_highest() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        let mut pvh0 = PvHeader::initialize(loop0.path()).unwrap();
+        let mut pvh1 = PvHeader::initialize(loop1.path()).unwrap();
+        let mut vg = VG::create("melvin-test-normalize", vec![loop0.path(), loop1.path()]).unwrap();
+
+        set_disk_seqno(&mut pvh0, 5);
+        set_disk_seqno(&mut pvh1, 7);
+
+        vg.normalize_seqno(&mut [pvh0.clone(), pvh1.clone()])
+            .unwrap();
+
+        for pvh in &[pvh0, pvh1] {
+            assert_eq!(disk_seqno(&pvh.dev_path), 8);
+            let map = pvh.read_metadata().unwrap();
+            let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+            assert_eq!(VG::from_textmap(vg.name(), vg_map).unwrap().seqno, 8);
+        }
+    }
+
+    #[test]
+    fn commit_archives_previous_metadata() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-archive", vec![loop0.path()]).unwrap();
+        let seqno = disk_seqno(loop0.path());
+
+        let dir = TestDir::new();
+        vg.set_archive_dir(Some(dir.path()));
+        vg.add_vg_tag("archived").unwrap();
+
+        let archive = dir.path().join("melvin-test-archive_00000.vg");
+        let mut buf = Vec::new();
+        File::open(&archive).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(vg_seqno(&buf_to_textmap(&buf).unwrap()), Some(seqno));
+        assert_eq!(disk_seqno(loop0.path()), seqno + 1);
+    }
+
+    #[test]
+    fn restore_reverts_lvs() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-restore", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, 0, 1)]),
+        );
+        vg.commit().unwrap();
+        let before = lvs_json(&vg.lvs);
+
+        let dir = TestDir::new();
+        vg.set_archive_dir(Some(dir.path()));
+        vg.lvs.insert(
+            "lv1".to_string(),
+            test_lv("lv1", None, &[(dev0.minor, 1, 1)]),
+        );
+        vg.commit().unwrap();
+        let seqno = disk_seqno(loop0.path());
+
+        let archive = dir.path().join("melvin-test-restore_00000.vg");
+        let restored = VG::restore(&archive, &mut [pvh.clone()]).unwrap();
+        assert_eq!(lvs_json(&restored.lvs), before);
+        assert!(disk_seqno(loop0.path()) > seqno);
+
+        let map = pvh.read_metadata().unwrap();
+        let vg_map = map.textmap_from_textmap(restored.name()).unwrap();
+        let on_disk = VG::from_textmap(restored.name(), vg_map).unwrap();
+        assert_eq!(lvs_json(&on_disk.lvs), before);
+    }
+
+    #[test]
+    fn lv_tags() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-lvtags", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, 0, 1)]),
+        );
+        vg.commit().unwrap();
+
+        vg.add_lv_tag("lv0", "tag0").unwrap();
+        vg.add_lv_tag("lv0", "tag1").unwrap();
+        vg.add_lv_tag("lv0", "tag0").unwrap();
+        assert_eq!(vg.lv_get("lv0").unwrap().tags, vec!["tag0", "tag1"]);
+
+        assert!(vg.add_lv_tag("lv0", "bad tag").is_err());
+        assert!(vg.add_lv_tag("lv0", "").is_err());
+        assert!(vg.add_lv_tag("missing", "tag0").is_err());
+
+        vg.remove_lv_tag("lv0", "tag0").unwrap();
+        vg.remove_lv_tag("lv0", "tag0").unwrap();
+        assert_eq!(vg.lv_get("lv0").unwrap().tags, vec!["tag1"]);
+
+        let map = PvHeader::find_in_dev(loop0.path())
+            .unwrap()
+            .read_metadata()
+            .unwrap();
+        let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+        let on_disk = VG::from_textmap(vg.name(), vg_map).unwrap();
+        assert_eq!(on_disk.lv_get("lv0").unwrap().tags, vec!["tag1"]);
+    }
+
+    #[test]
+    fn vg_and_pv_tags() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-vgtags", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+
+        vg.add_vg_tag("tag0").unwrap();
+        vg.add_vg_tag("tag1").unwrap();
+        vg.add_vg_tag("tag0").unwrap();
+        assert!(vg.add_vg_tag("bad tag").is_err());
+        vg.remove_vg_tag("tag0").unwrap();
+        vg.remove_vg_tag("tag0").unwrap();
+        assert_eq!(vg.tags, vec!["tag1"]);
+
+        vg.add_pv_tag(dev0, "pvtag0").unwrap();
+        vg.add_pv_tag(dev0, "pvtag1").unwrap();
+        vg.add_pv_tag(dev0, "pvtag0").unwrap();
+        assert!(vg.add_pv_tag(dev0, "bad tag").is_err());
+        assert!(vg.add_pv_tag(dev(200), "pvtag0").is_err());
+        vg.remove_pv_tag(dev0, "pvtag0").unwrap();
+        vg.remove_pv_tag(dev0, "pvtag0").unwrap();
+        assert_eq!(vg.pvs[&dev0].tags, vec!["pvtag1"]);
+
+        let map = PvHeader::find_in_dev(loop0.path())
+            .unwrap()
+            .read_metadata()
+            .unwrap();
+        let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+        let on_disk = VG::from_textmap(vg.name(), vg_map).unwrap();
+        assert_eq!(on_disk.tags, vec!["tag1"]);
+        assert_eq!(on_disk.pvs[&dev0].tags, vec!["pvtag1"]);
+    }
+
+    #[test]
+    fn what_is_at_finds_segment() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 2), (0, 10, 3)]))
+            .build();
+
+        let (name, seg) = vg.what_is_at(dev(0), 11).unwrap();
+        assert_eq!(name, "lv0");
+        assert_eq!(seg.start_extent(), 2);
+        assert_eq!(seg.extent_count(), 3);
+
+        // Free, out of range, and on a PV that isn't in the VG
+        assert!(vg.what_is_at(dev(0), 5).is_none());
+        assert!(vg.what_is_at(dev(0), 1000).is_none());
+        assert!(vg.what_is_at(dev(1), 0).is_none());
+    }
+
+    #[test]
+    fn builder_vg_commits() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loops = [LoopDevice::new(64 << 20), LoopDevice::new(64 << 20)];
+        let mut builder = VgBuilder::new("melvin-test-builder");
+        let mut devs = Vec::new();
+        for loop_dev in &loops {
+            let pvh = PvHeader::initialize(loop_dev.path()).unwrap();
+            let dev = Device::from_str(&loop_dev.path().to_string_lossy()).unwrap();
+            builder = builder.pv(PV {
+                id: pvh.uuid.clone(),
+                device: dev,
+                status: vec!["ALLOCATABLE".to_string()],
+                flags: Vec::new(),
+                tags: Vec::new(),
+                dev_size: pvh.size / 512,
+                pe_start: align_to(pvh.data_areas[0].offset / 512, DEFAULT_EXTENT_SIZE),
+                pe_count: 10,
+            });
+            devs.push(dev);
+        }
+        let mut vg = builder
+            .lv(test_lv(
+                "lv0",
+                None,
+                &[(devs[0].minor, 0, 5), (devs[1].minor, 0, 5)],
+            ))
+            .build();
+        vg.commit().unwrap();
+
+        for loop_dev in &loops {
+            assert_eq!(disk_seqno(loop_dev.path()), 1);
+            let map = PvHeader::find_in_dev(loop_dev.path())
+                .unwrap()
+                .read_metadata()
+                .unwrap();
+            let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+            let on_disk = VG::from_textmap(vg.name(), vg_map).unwrap();
+            assert_eq!(on_disk.pvs, vg.pvs);
+            assert_eq!(lvs_json(&on_disk.lvs), lvs_json(&vg.lvs));
+        }
+    }
+
+    #[test]
+    fn counts_and_summary() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 50))
+            .lv(test_lv("lv0", None, &[(0, 0, 10)]))
+            .lv(test_lv("lv1", None, &[(0, 10, 5), (1, 0, 5)]))
+            .lv(test_lv("lv2", None, &[(1, 5, 1)]))
+            .build();
+
+        assert_eq!(vg.pv_count(), 2);
+        assert_eq!(vg.lv_count(), 3);
+        assert_eq!(
+            vg.summary(),
+            VgSummary {
+                pv_count: 2,
+                lv_count: 3,
+                extent_size: DEFAULT_EXTENT_SIZE,
+                extents: 150,
+                extents_free: 129,
+            }
+        );
+    }
+
+    #[test]
+    fn suggest_stripes_across_three_pvs() {
+        // Largest free areas of 100, 50 and 20 extents
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 100))
+            .pv(test_pv(2, 20))
+            .lv(test_lv("lv0", None, &[(1, 0, 50)]))
+            .build();
+
+        assert_eq!(vg.suggest_stripe_count(60), 3);
+        assert_eq!(vg.suggest_stripe_count(90), 2);
+        assert_eq!(vg.suggest_stripe_count(100), 2);
+        // 101 extents fit in the VG, but not in one free area, nor in two
+        // or three stripes
+        assert_eq!(vg.suggest_stripe_count(101), 0);
+    }
+
+    #[test]
+    fn vg_alloc_policy_round_trips() {
+        let mut vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", Some("contiguous"), &[(0, 0, 1)]))
+            .build();
+        vg.alloc_policy = Some("cling".to_string());
+
+        let map = to_textmap(&vg);
+        assert_eq!(map.string_from_textmap("allocation_policy"), Some("cling"));
+
+        let parsed = VG::from_textmap("vg0", &map).unwrap();
+        assert_eq!(parsed.alloc_policy(), Some("cling"));
+        assert_eq!(
+            parsed.lv_get("lv0").unwrap().alloc_policy,
+            Some("contiguous".to_string())
+        );
+        assert_eq!(
+            parsed.alloc_policy_for(&AllocConstraints::default(), None),
+            AllocPolicy::Cling
+        );
+    }
+
+    #[test]
+    fn pending_changes_shows_added_lv() {
+        let image = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(image.path()).unwrap();
+        let mut vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 1)]))
+            .build();
+        pvh.write_metadata_with_options(&vg.disk_textmap(), &WriteOptions::default())
+            .unwrap();
+        assert_eq!(vg.pending_changes(&pvh).unwrap(), vec![]);
+
+        vg.lvs
+            .insert("lv1".to_string(), test_lv("lv1", None, &[(0, 1, 1)]));
+        assert_eq!(
+            vg.pending_changes(&pvh).unwrap(),
+            vec![MapDiff::Added("logical_volumes/lv1".to_string())]
+        );
+
+        let other = VgBuilder::new("vg1").build();
+        assert!(other.pending_changes(&pvh).is_err());
+    }
+
+    #[test]
+    fn structurally_eq_ignores_seqno() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 1)]))
+            .build();
+
+        let mut later = vg.clone();
+        later.seqno = 7;
+        assert!(vg != later);
+        assert!(vg.structurally_eq(&later));
+
+        let mut grown = vg.clone();
+        grown
+            .lvs
+            .insert("lv1".to_string(), test_lv("lv1", None, &[(0, 1, 1)]));
+        assert!(!vg.structurally_eq(&grown));
+    }
+
+    #[test]
+    fn availability_by_present_pvs() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 100))
+            .build();
+        let ids: Vec<String> = vg.pvs.values().map(|pv| pv.id.clone()).collect();
+
+        let present = |ids: &[&String]| ids.iter().map(|&x| x.clone()).collect();
+        assert_eq!(
+            vg.availability(&present(&[&ids[0], &ids[1]])),
+            VgAvailability::Complete
+        );
+        assert_eq!(
+            vg.availability(&present(&[&ids[1]])),
+            VgAvailability::Partial
+        );
+        assert_eq!(vg.availability(&present(&[])), VgAvailability::Unavailable);
+
+        // PVs of other VGs don't count
+        let other = "some-other-pv".to_string();
+        assert_eq!(
+            vg.availability(&present(&[&other])),
+            VgAvailability::Unavailable
+        );
+    }
+
+    #[test]
+    fn read_extent_reads_pattern() {
+        let vg = VgBuilder::new("vg0").pv(test_pv(0, 10)).build();
+        let extent_bytes = (DEFAULT_EXTENT_SIZE * 512) as usize;
+        let image = TestImage::new(12 * extent_bytes as u64);
+
+        // Extent 2 follows pe_start and extents 0 and 1
+        let pattern: Vec<u8> = (0..extent_bytes).map(|x| (x % 251) as u8).collect();
+        let mut f = OpenOptions::new().write(true).open(image.path()).unwrap();
+        f.seek(SeekFrom::Start(3 * extent_bytes as u64)).unwrap();
+        f.write_all(&pattern).unwrap();
+
+        let mut pv_devices = BTreeMap::new();
+        pv_devices.insert(dev(0), image.path().to_path_buf());
+        assert_eq!(vg.read_extent(&pv_devices, dev(0), 2).unwrap(), pattern);
+        assert_eq!(
+            vg.read_extent(&pv_devices, dev(0), 1).unwrap(),
+            vec![0; extent_bytes]
+        );
+
+        assert!(vg.read_extent(&pv_devices, dev(0), 10).is_err());
+        assert!(vg.read_extent(&BTreeMap::new(), dev(0), 2).is_err());
+        assert!(vg.read_extent(&pv_devices, dev(1), 2).is_err());
+    }
+
+    #[test]
+    fn creation_host_fallback() {
+        let mut vg = VgBuilder::new("vg0").build();
+
+        let expected = hostname().unwrap_or_else(|| DEFAULT_CREATION_HOST.to_string());
+        assert_eq!(vg.creation_host(), expected);
+        assert!(!vg.creation_host().is_empty());
+
+        vg.set_creation_host(Some("elsewhere"));
+        assert_eq!(vg.creation_host(), "elsewhere");
+        assert_eq!(
+            vg.disk_textmap().string_from_textmap("creation_host"),
+            Some("elsewhere")
+        );
+
+        vg.set_creation_host(None);
+        assert_eq!(vg.creation_host(), expected);
+    }
+
+    #[test]
+    fn is_active_follows_device_mapper() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-active", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        vg.lv_create_linear("lv1", 1).unwrap();
+        vg.deactivate_lv("lv1").unwrap();
+
+        let lv0_active = vg.is_active("lv0").unwrap();
+        let lv1_active = vg.is_active("lv1").unwrap();
+        let active = vg.active_lvs().unwrap().join(",");
+        vg.deactivate_lv("lv0").unwrap();
+
+        assert!(lv0_active);
+        assert!(!lv1_active);
+        assert_eq!(active, "lv0");
+        assert!(!vg.is_active("lv0").unwrap());
+        assert!(vg.is_active("missing").is_err());
+    }
+
+    #[test]
+    fn out_of_range_segment_rejected() {
+        let past_end = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 99, 2)]))
+            .build();
+        assert!(past_end.validate_allocation().is_err());
+
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-range", vec![loop0.path()]).unwrap();
+        let seqno = disk_seqno(loop0.path());
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let pe_count = vg.pvs[&dev0].pe_count;
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, pe_count - 1, 2)]),
+        );
+
+        assert!(vg.commit().is_err());
+        assert_eq!(disk_seqno(loop0.path()), seqno);
+    }
+
+    #[test]
+    fn lvs_by_size_order() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("small", None, &[(0, 0, 2)]))
+            .lv(test_lv("large", None, &[(0, 2, 10), (0, 20, 5)]))
+            .lv(test_lv("b", None, &[(0, 12, 4)]))
+            .lv(test_lv("a", None, &[(0, 16, 4)]))
+            .build();
+
+        // Ties are broken by name
+        assert_eq!(
+            vg.lvs_by_size(),
+            vec![("large", 15), ("a", 4), ("b", 4), ("small", 2)]
+        );
+    }
+
+    #[test]
+    fn create_check_flags_used_pv() {
+        let read_all = |path: &Path| {
+            let mut buf = Vec::new();
+            File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+            buf
+        };
+
+        let free = TestImage::new(16 << 20);
+        PvHeader::initialize(free.path()).unwrap();
+        let used = TestImage::new(16 << 20);
+        let mut pvh = PvHeader::initialize(used.path()).unwrap();
+        let mut map = buf_to_textmap(b"vg0 {\nid = \"x\"\n}\n").unwrap();
+        pvh.write_metadata(&mut map).unwrap();
+
+        let free_before = read_all(free.path());
+        let used_before = read_all(used.path());
+
+        VG::create_check("vg1", &[free.path()]).unwrap();
+        assert!(VG::create_check("vg1", &[used.path()]).is_err());
+        assert!(VG::create_check("vg1", &[free.path(), used.path()]).is_err());
+        assert!(VG::create_check("bad name", &[free.path()]).is_err());
+
+        assert!(read_all(free.path()) == free_before);
+        assert!(read_all(used.path()) == used_before);
+    }
+
+    #[test]
+    fn excluded_pv_avoided() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 20))
+            .build();
+        let mut constraints = AllocConstraints {
+            exclude_pvs: vec![dev(0)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vg.allocate(10, &constraints, None).unwrap(),
+            vec![(dev(1), 0, 10)]
+        );
+        // More than the other PV can hold
+        assert!(vg.allocate(30, &constraints, None).is_err());
+
+        constraints.exclude_pvs.push(dev(1));
+        assert!(vg.allocate(1, &constraints, None).is_err());
+    }
+
+    #[test]
+    fn free_size_histogram_counts_holes() {
+        // Free holes of 1 and 1 extents on PV 0, and 10 on PV 1
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 10))
+            .pv(test_pv(1, 15))
+            .lv(test_lv("lv0", None, &[(0, 0, 3), (0, 4, 2), (0, 7, 3)]))
+            .lv(test_lv("lv1", None, &[(1, 0, 5)]))
+            .build();
+
+        let expected: BTreeMap<u64, usize> = vec![(1, 2), (10, 1)].into_iter().collect();
+        assert_eq!(vg.free_size_histogram(), expected);
+    }
+
+    #[test]
+    fn lvm1_format_rejected() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 2)]))
+            .build();
+
+        let mut map = to_textmap(&vg);
+        assert!(VG::from_textmap("vg0", &map).is_ok());
+        map.insert("format".to_string(), Entry::String("lvm1".to_string()));
+        assert!(VG::from_textmap("vg0", &map).is_err());
+
+        let mut json = vg.to_json();
+        if let Json::Object(ref mut obj) = json {
+            obj.insert("format".to_string(), Json::String("lvm1".to_string()));
+        }
+        assert!(VG::from_json(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn lv_remove_frees_extents() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-lvremove", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+
+        // Creating an LV activates it, so without device-mapper put an
+        // inactive one in place instead
+        if can_use_dm() {
+            vg.lv_create_linear("lv0", 3).unwrap();
+        } else {
+            vg.lvs.insert(
+                "lv0".to_string(),
+                test_lv("lv0", None, &[(dev0.minor, 0, 3)]),
+            );
+            vg.commit().unwrap();
+        }
+        assert_eq!(vg.extents_in_use(), 3);
+
+        vg.lv_remove("lv0").unwrap();
+        assert_eq!(vg.extents_in_use(), 0);
+        assert_eq!(vg.extents_free(), vg.extents());
+        assert!(vg.lv_get("lv0").is_none());
+
+        let map = pvh.read_metadata().unwrap();
+        let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+        let on_disk = VG::from_textmap(vg.name(), vg_map).unwrap();
+        assert_eq!(on_disk.extents_in_use(), 0);
+        assert!(on_disk.lv_list().is_empty());
+
+        assert!(vg.lv_remove("lv0").is_err());
+    }
+
+    #[test]
+    fn lv_resize_extends_and_reduces() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-lvresize", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, 0, 3)]),
         );
-        disk_map.insert(
-            "creation_time".to_string(),
-            Entry::Number(now().to_timespec().sec),
+        vg.commit().unwrap();
+
+        let on_disk = || {
+            let map = pvh.read_metadata().unwrap();
+            let vg_map = map.textmap_from_textmap("melvin-test-lvresize").unwrap();
+            let vg = VG::from_textmap("melvin-test-lvresize", vg_map).unwrap();
+            vg.lvs["lv0"].used_extents()
+        };
+
+        // Free space directly follows the LV, so it grows in place
+        vg.lv_resize("lv0", 5).unwrap();
+        let lv = vg.lv_get("lv0").unwrap();
+        assert_eq!(lv.used_extents(), 5);
+        assert_eq!(lv.segments.len(), 1);
+        assert_eq!(lv.segments[0].used_areas(), vec![(dev0, 0, 5)]);
+        assert_eq!(on_disk(), 5);
+
+        vg.lv_resize("lv0", 2).unwrap();
+        let lv = vg.lv_get("lv0").unwrap();
+        assert_eq!(lv.used_extents(), 2);
+        assert_eq!(lv.segments[0].used_areas(), vec![(dev0, 0, 2)]);
+        assert_eq!(vg.extents_in_use(), 2);
+        assert_eq!(on_disk(), 2);
+
+        let seqno = vg.seqno;
+        vg.lv_resize("lv0", 2).unwrap();
+        assert_eq!(vg.seqno, seqno);
+
+        assert!(vg.lv_resize("lv0", 0).is_err());
+        assert!(vg.lv_resize("lv0", vg.extents() + 1).is_err());
+        assert_eq!(vg.lv_get("lv0").unwrap().used_extents(), 2);
+        assert!(vg.lv_resize("missing", 1).is_err());
+    }
+
+    #[test]
+    fn pv_free_extents_lists_every_pv() {
+        // PV 0 is full, PV 1 partly used and PV 2 unused
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 10))
+            .pv(test_pv(1, 20))
+            .pv(test_pv(2, 30))
+            .lv(test_lv("lv0", None, &[(0, 0, 10), (1, 5, 5)]))
+            .build();
+
+        let expected: BTreeMap<Device, u64> = vec![(dev(0), 0), (dev(1), 15), (dev(2), 30)]
+            .into_iter()
+            .collect();
+        assert_eq!(vg.pv_free_extents(), expected);
+        assert_eq!(
+            vg.pv_free_extents().values().sum::<u64>(),
+            vg.extents_free()
         );
-        disk_map.insert(self.name.clone(), Entry::TextMap(Box::new(map.clone())));
+    }
 
-        // TODO: atomicity of updating pvs, metad, dm
-        for pv in self.pvs.values() {
-            if let Some(path) = pv.path() {
-                let mut pvheader = PvHeader::find_in_dev(&path).expect("could not find pvheader");
+    #[test]
+    fn empty_pv_gets_new_lv() {
+        // PV 0 is full, so the only free space is on PV 1, which has no
+        // LVs on it
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 10))
+            .pv(test_pv(1, 20))
+            .lv(test_lv("lv0", None, &[(0, 0, 10)]))
+            .build();
 
-                pvheader.write_metadata(&disk_map)?;
-            }
-        }
+        let expected: BTreeMap<u64, u64> = vec![(0, 20)].into_iter().collect();
+        assert_eq!(vg.free_areas()[&dev(1)], expected);
 
-        Ok(())
+        let areas = vg.allocate(5, &AllocConstraints::default(), None).unwrap();
+        assert_eq!(areas, vec![(dev(1), 0, 5)]);
+        assert!(vg.allocate(21, &AllocConstraints::default(), None).is_err());
     }
 
-    // Returns used areas in the format: {Device: {start: len} }
-    //
-    // e.g. with {<Device 3:1>: {0: 45, 47: 100, 147: 200} }
-    // extents 0-44 (inclusive) are used, 45 and 46 are not, 47-146
-    // are used, then 147-346 are used.
-    //
-    // Adjacent used areas are not merged.
-    //
-    // PVs with no used areas are not in the outer map at all.
-    //
-    fn used_areas(&self) -> BTreeMap<Device, BTreeMap<u64, u64>> {
-        let mut used_map = BTreeMap::new();
+    #[test]
+    fn vg_rename() {
+        if !can_use_loop() {
+            return;
+        }
 
-        for lv in self.lvs.values() {
-            for (device, start, len) in lv::used_areas(lv) {
-                used_map
-                    .entry(device)
-                    .or_insert(BTreeMap::new())
-                    .insert(start, len);
-            }
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-vgrename", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, 0, 3)]),
+        );
+        vg.commit().unwrap();
+
+        vg.rename("melvin-test-vgrenamed").unwrap();
+        assert_eq!(vg.name(), "melvin-test-vgrenamed");
+        let map = pvh.read_metadata().unwrap();
+        assert!(map.textmap_from_textmap("melvin-test-vgrename").is_none());
+        let vg_map = map.textmap_from_textmap("melvin-test-vgrenamed").unwrap();
+        let on_disk = VG::from_textmap("melvin-test-vgrenamed", vg_map).unwrap();
+        assert_eq!(on_disk.id(), vg.id());
+        assert_eq!(lvs_json(&on_disk.lvs), lvs_json(&vg.lvs));
+
+        // Renaming to the current name does nothing
+        let seqno = vg.seqno;
+        vg.rename("melvin-test-vgrenamed").unwrap();
+        assert_eq!(vg.seqno, seqno);
+
+        let too_long = "a".repeat(200);
+        for bad in &["", "-vg", ".", "..", "vg/0", "vg 0", &too_long[..]] {
+            assert!(vg.rename(bad).is_err(), "{:?} accepted", bad);
+            assert_eq!(vg.name(), "melvin-test-vgrenamed");
         }
+        assert_eq!(vg.seqno, seqno);
+    }
 
-        used_map
+    #[test]
+    fn pv_add_grows_vg() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        // Smaller than one extent once the labels and MDA are in place
+        let small = LoopDevice::new(4 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        PvHeader::initialize(loop1.path()).unwrap();
+        PvHeader::initialize(small.path()).unwrap();
+
+        let mut vg = VG::create("melvin-test-pvadd", vec![loop0.path()]).unwrap();
+        let extents = vg.extents();
+        let dev1 = Device::from_str(&loop1.path().to_string_lossy()).unwrap();
+
+        vg.pv_add(loop1.path()).unwrap();
+        assert_eq!(vg.pv_list().len(), 2);
+        assert_eq!(vg.extents(), extents + vg.pv_get(dev1).unwrap().pe_count);
+        assert!(vg.pv_get(dev1).unwrap().pe_count > 0);
+        assert_eq!(vg.extents_free(), vg.extents());
+
+        // Already in the VG
+        assert!(vg.pv_add(loop1.path()).is_err());
+
+        let extents = vg.extents();
+        assert!(vg.pv_add(small.path()).is_err());
+        assert_eq!(vg.pv_list().len(), 2);
+        assert_eq!(vg.extents(), extents);
     }
 
-    // Returns unused areas in the format: {Device: {start: len} }
-    //
-    // e.g. assuming the same <Device 3:1> as above and it has 1000
-    // extents, calling free_areas would result in:
-    // {<Device 3:1>: {45: 2, 347: 653} }
-    //
-    fn free_areas(&self) -> BTreeMap<Device, BTreeMap<u64, u64>> {
-        let mut free_map = BTreeMap::new();
+    #[test]
+    fn thin_snapshot_round_trip() {
+        let thin_lv = |name: &str, device_id: u64, origin: Option<&str>| {
+            let mut lv = test_lv(name, None, &[]);
+            lv.segments = vec![Box::new(segment::ThinSegment {
+                start_extent: 0,
+                extent_count: 10,
+                thin_pool: "pool".to_string(),
+                transaction_id: device_id,
+                device_id: device_id,
+                origin: origin.map(|x| x.to_string()),
+                external_origin: None,
+            })];
+            lv
+        };
+        let mut pool = test_lv("pool", None, &[]);
+        pool.segments = vec![Box::new(segment::ThinpoolSegment {
+            start_extent: 0,
+            extent_count: 20,
+            metadata_lv: "pool_tmeta".to_string(),
+            data_lv: "pool_tdata".to_string(),
+            transaction_id: 2,
+            chunk_size: DEFAULT_THINPOOL_CHUNK_SIZE,
+            discards: segment::DiscardPolicy::Passdown,
+            zero_new_blocks: true,
+        })];
 
-        for (dev, mut area_map) in self.used_areas() {
-            // Insert an entry to mark the end of the PV so the fold works
-            // correctly
-            let pv = self
-                .pvs
-                .get(&dev)
-                .expect("area map name refers to nonexistent PV");
-            area_map.insert(pv.pe_count, 0);
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("pool_tmeta", None, &[(0, 0, 1)]))
+            .lv(test_lv("pool_tdata", None, &[(0, 1, 20)]))
+            .lv(pool)
+            .lv(thin_lv("thin0", 1, None))
+            .lv(thin_lv("snap0", 2, Some("thin0")))
+            .build();
 
-            area_map.iter().fold(0, |prev_end, (start, len)| {
-                if prev_end < *start {
-                    free_map
-                        .entry(dev)
-                        .or_insert(BTreeMap::new())
-                        .insert(prev_end, start - prev_end);
-                }
-                start + len
-            });
+        let mut parsed = VG::from_textmap("vg0", &to_textmap(&vg)).unwrap();
+        assert_eq!(lvs_json(&parsed.lvs), lvs_json(&vg.lvs));
+
+        let snap = parsed.lv_get("snap0").unwrap().segments[0]
+            .as_any()
+            .downcast_ref::<segment::ThinSegment>()
+            .unwrap();
+        assert_eq!(snap.thin_pool, "pool");
+        assert_eq!(snap.origin.as_ref().map(|x| &x[..]), Some("thin0"));
+        assert_eq!(snap.device_id, 2);
+        assert!(parsed.lv_get(&snap.thin_pool).is_some());
+        assert!(parsed.lv_get("thin0").is_some());
+
+        // The pool isn't active, so no snapshot can be made of it
+        assert!(parsed
+            .lv_create_thin_snapshot("pool", "thin0", "snap1")
+            .is_err());
+        assert!(parsed
+            .lv_create_thin_snapshot("thin0", "snap0", "snap1")
+            .is_err());
+        assert!(parsed.lv_get("snap1").is_none());
+    }
+
+    #[test]
+    fn to_dot_nodes_and_edges() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .pv(test_pv(1, 50))
+            .lv(test_lv("lv0", None, &[(0, 0, 2), (1, 0, 3), (0, 10, 4)]))
+            .lv(test_lv("lv1", None, &[(1, 3, 5)]))
+            .build();
+
+        let dot = vg.to_dot();
+        let lines: Vec<&str> = dot.lines().map(|x| x.trim()).collect();
+        assert_eq!(lines.first(), Some(&"digraph \"vg0\" {"));
+        assert_eq!(lines.last(), Some(&"}"));
+
+        for line in &[
+            "\"pv 7:0\" [shape=box, label=\"7:0\\n100 extents\"];",
+            "\"pv 7:1\" [shape=box, label=\"7:1\\n50 extents\"];",
+            "\"lv lv0\" [label=\"lv0\"];",
+            "\"lv lv1\" [label=\"lv1\"];",
+            // lv0's two areas on PV 0 make a single edge
+            "\"lv lv0\" -> \"pv 7:0\" [label=\"6\"];",
+            "\"lv lv0\" -> \"pv 7:1\" [label=\"3\"];",
+            "\"lv lv1\" -> \"pv 7:1\" [label=\"5\"];",
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
         }
+        assert_eq!(lines.iter().filter(|x| x.contains("->")).count(), 3);
+        assert_eq!(lines.len(), 9);
+    }
 
-        // Also return completely-unused PVs
-        for (dev, pv) in &self.pvs {
-            if !free_map.contains_key(dev) {
-                let mut map = BTreeMap::new();
-                map.insert(0, pv.pe_count);
-                free_map.insert(*dev, map);
-            }
+    #[test]
+    fn pv_remove_only_unused() {
+        if !can_use_loop() {
+            return;
         }
 
-        free_map
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        let loop2 = LoopDevice::new(64 << 20);
+        let pvh0 = PvHeader::initialize(loop0.path()).unwrap();
+        let pvh1 = PvHeader::initialize(loop1.path()).unwrap();
+        let pvh2 = PvHeader::initialize(loop2.path()).unwrap();
+        let mut vg = VG::create(
+            "melvin-test-pvremove",
+            vec![loop0.path(), loop1.path(), loop2.path()],
+        )
+        .unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let dev1 = Device::from_str(&loop1.path().to_string_lossy()).unwrap();
+        let dev2 = Device::from_str(&loop2.path().to_string_lossy()).unwrap();
+
+        // lv0 fills PV 0, so the reservation must go elsewhere
+        let pe_count = vg.pv_get(dev0).unwrap().pe_count;
+        vg.lvs.insert(
+            "lv0".to_string(),
+            test_lv("lv0", None, &[(dev0.minor, 0, pe_count)]),
+        );
+        vg.commit().unwrap();
+
+        vg.pv_remove(&pvh2).unwrap();
+        assert!(vg.pv_get(dev2).is_none());
+        let map = pvh0.read_metadata().unwrap();
+        let vg_map = map.textmap_from_textmap(vg.name()).unwrap();
+        let on_disk = VG::from_textmap(vg.name(), vg_map).unwrap();
+        assert_eq!(on_disk.pv_list(), vg.pv_list());
+
+        assert!(vg.pv_remove(&pvh0).is_err());
+        assert!(vg.pv_get(dev0).is_some());
+
+        let reservation = vg.reserve(1).unwrap();
+        assert!(vg.pv_remove(&pvh1).is_err());
+        assert!(vg.pv_get(dev1).is_some());
+        drop(reservation);
+        vg.pv_remove(&pvh1).unwrap();
+        assert_eq!(vg.pv_list(), vec![dev0]);
     }
 
-    /// Returns a list of PV Devices that make up the VG.
-    pub fn pv_list(&self) -> Vec<Device> {
-        self.pvs.keys().map(|key| *key).collect()
+    #[test]
+    fn pv_with_active_lv_not_removed() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        let pvh0 = PvHeader::initialize(loop0.path()).unwrap();
+        PvHeader::initialize(loop1.path()).unwrap();
+        let mut vg = VG::create("melvin-test-pvactive", vec![loop0.path(), loop1.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let dev1 = Device::from_str(&loop1.path().to_string_lossy()).unwrap();
+
+        vg.lv_create_linear_constrained(
+            "lv0",
+            1,
+            &AllocConstraints {
+                exclude_pvs: vec![dev1],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(vg.pv_has_active_lvs(dev0).unwrap());
+        assert!(!vg.pv_has_active_lvs(dev1).unwrap());
+
+        match vg.pv_remove(&pvh0) {
+            Err(Error::Io(e)) => assert_eq!(e.raw_os_error(), Some(libc::EBUSY)),
+            x => panic!("expected EBUSY, got {:?}", x),
+        }
+        assert!(vg.pv_get(dev0).is_some());
+
+        vg.deactivate_lv("lv0").unwrap();
+        assert!(!vg.pv_has_active_lvs(dev0).unwrap());
     }
 
-    /// Returns a reference to the PV matching the Device.
-    pub fn pv_get(&self, dev: Device) -> Option<&PV> {
-        self.pvs.get(&dev)
+    #[test]
+    fn mda_copy_count_counts_member_pvs() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        let other = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        PvHeader::initialize(loop1.path()).unwrap();
+        PvHeader::initialize(other.path()).unwrap();
+        let vg = VG::create("melvin-test-mdacount", vec![loop0.path(), loop1.path()]).unwrap();
+
+        // Metadata areas at the start and end of each member PV; other
+        // isn't in the VG
+        let headers: Vec<PvHeader> = [loop0.path(), loop1.path(), other.path()]
+            .iter()
+            .map(|path| PvHeader::find_in_dev(path).unwrap())
+            .collect();
+        assert_eq!(headers[0].metadata_areas.len(), 2);
+        assert_eq!(vg.mda_copy_count(&headers), 4);
+        assert_eq!(vg.mda_copy_count(&headers[..1]), 2);
+        assert_eq!(vg.mda_copy_count(&headers[2..]), 0);
     }
 
-    /// Returns a list of the names of LVs in the VG.
-    pub fn lv_list(&self) -> Vec<String> {
-        self.lvs.keys().map(|key| key.clone()).collect()
+    #[test]
+    fn backup_file_contents() {
+        let vg = VgBuilder::new("vg0")
+            .pv(test_pv(0, 100))
+            .lv(test_lv("lv0", None, &[(0, 0, 2)]))
+            .build();
+
+        let dir = TestDir::new();
+        let path = dir.path().join("vg0");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"not a backup")
+            .unwrap();
+        vg.backup_to_file(&path).unwrap();
+
+        let mut buf = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut buf).unwrap();
+        assert!(buf.starts_with(b"# Generated by melvin"));
+
+        let map = buf_to_textmap(&buf).unwrap();
+        assert_eq!(
+            map.string_from_textmap("contents"),
+            Some("Text Format Volume Group")
+        );
+        assert!(map.i64_from_textmap("creation_time").is_some());
+        let vg_map = map.textmap_from_textmap("vg0").unwrap();
+        let parsed = VG::from_textmap("vg0", vg_map).unwrap();
+        assert_eq!(parsed.id(), vg.id());
+        assert_eq!(lvs_json(&parsed.lvs), lvs_json(&vg.lvs));
     }
 
-    /// Returns a reference to the LV matching the name.
-    pub fn lv_get(&self, name: &str) -> Option<&LV> {
-        self.lvs.get(name)
+    #[test]
+    fn transaction_commits_once() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-tx", vec![loop0.path()]).unwrap();
+        let seqno = disk_seqno(loop0.path());
+
+        let mut tx = vg.begin();
+        // A new LV is activated once the Transaction is committed, which
+        // needs device-mapper, so without it the LV is added directly.
+        // Loop devices have major 7, as test_lv() assumes.
+        if can_use_dm() {
+            tx.lv_create_linear("lv0", 1).unwrap();
+        } else {
+            let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+            let lv = test_lv("lv0", None, &[(dev0.minor, 0, 1)]);
+            tx.lvs.insert("lv0".to_string(), lv);
+        }
+        tx.add_lv_tag("lv0", "staged").unwrap();
+        tx.set_lv_permission("lv0", false).unwrap();
+        tx.add_vg_tag("staged").unwrap();
+
+        assert!(VG::commit(&mut tx).is_err());
+        assert!(tx.commit_with_seqno(seqno as u64 + 5).is_err());
+        assert!(tx.normalize_seqno(&mut []).is_err());
+        assert_eq!(disk_seqno(loop0.path()), seqno);
+
+        tx.commit().unwrap();
+        assert_eq!(disk_seqno(loop0.path()), seqno + 1);
+        let lv = vg.lv_get("lv0").unwrap();
+        assert_eq!(lv.tags, vec!["staged".to_string()]);
+        assert!(!lv.is_writable());
+
+        if can_use_dm() {
+            vg.lv_remove("lv0").unwrap();
+        }
     }
 
-    /// Returns the name of the VG.
-    pub fn name(&self) -> &str {
-        &self.name
+    #[test]
+    fn dropped_transaction_reverts() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-txdrop", vec![loop0.path()]).unwrap();
+        let before = vg.clone();
+        let on_disk = pvh.read_metadata().unwrap();
+
+        {
+            let mut tx = vg.begin();
+            tx.lv_create_linear("lv0", 1).unwrap();
+            tx.add_lv_tag("lv0", "staged").unwrap();
+            tx.add_vg_tag("staged").unwrap();
+        }
+
+        assert_eq!(vg, before);
+        assert_eq!(pvh.read_metadata().unwrap(), on_disk);
     }
 
-    /// Returns the UUID of the VG.
-    pub fn id(&self) -> &str {
-        &self.id
+    #[test]
+    fn lv_rename_keeps_segments_and_uuid() {
+        if !can_use_loop() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        let pvh = PvHeader::initialize(loop0.path()).unwrap();
+        let mut vg = VG::create("melvin-test-lvrename", vec![loop0.path()]).unwrap();
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let lv = test_lv("lv0", None, &[(dev0.minor, 0, 2), (dev0.minor, 4, 1)]);
+        vg.lvs.insert("lv0".to_string(), lv.clone());
+        vg.commit().unwrap();
+
+        vg.lv_rename("lv0", "renamed").unwrap();
+
+        let mut expected = lv;
+        expected.name = "renamed".to_string();
+        assert!(vg.lv_get("lv0").is_none());
+        assert_eq!(lv_json(vg.lv_get("renamed").unwrap()), lv_json(&expected));
+
+        let map = pvh.read_metadata().unwrap();
+        let on_disk = map
+            .textmap_from_textmap(vg.name())
+            .and_then(|vg_map| vg_map.textmap_from_textmap("logical_volumes"))
+            .and_then(|lvs| lvs.textmap_from_textmap("renamed"))
+            .unwrap();
+        assert_eq!(on_disk.string_from_textmap("id"), Some(&expected.id[..]));
+
+        // Without the archive directory, the commit fails, so the LV
+        // keeps its name
+        let dir = TestDir::new();
+        vg.set_archive_dir(Some(&dir.path().join("missing")));
+        assert!(vg.lv_rename("renamed", "lv1").is_err());
+        assert!(vg.lv_get("lv1").is_none());
+        assert_eq!(lv_json(vg.lv_get("renamed").unwrap()), lv_json(&expected));
     }
 
-    /// Returns how many 512-byte sectors make up each extent in the VG.
-    pub fn extent_size(&self) -> u64 {
-        self.extent_size
+    #[test]
+    fn linear_all_leaves_reservations() {
+        if !can_use_dm() {
+            return;
+        }
+
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
+
+        let mut vg = VG::create("melvin-test-all", vec![loop0.path()]).unwrap();
+        let free = vg.extents_free();
+        let reservation = vg.reserve(2).unwrap();
+        vg.lv_create_linear_all("lv0", 1).unwrap();
+
+        assert_eq!(vg.lv_get("lv0").unwrap().used_extents(), free - 3);
+        drop(reservation);
+        vg.lv_remove("lv0").unwrap();
     }
-}
 
-fn to_textmap(vg: &VG) -> LvmTextMap {
-    let mut map = LvmTextMap::new();
+    #[test]
+    fn failed_activation_leaves_extents_free() {
+        if !can_use_dm() {
+            return;
+        }
 
-    map.insert("id".to_string(), Entry::String(vg.id.clone()));
-    map.insert("seqno".to_string(), Entry::Number(vg.seqno as i64));
-    map.insert("format".to_string(), Entry::String(vg.format.clone()));
+        let loop0 = LoopDevice::new(64 << 20);
+        PvHeader::initialize(loop0.path()).unwrap();
 
-    map.insert("max_pv".to_string(), Entry::Number(0));
-    map.insert("max_lv".to_string(), Entry::Number(0));
+        // Together, the names are too long for a device-mapper name
+        let vg_name = format!("melvin-test-{}", "v".repeat(100));
+        let lv_name = "l".repeat(100);
+        let mut vg = VG::create(&vg_name, vec![loop0.path()]).unwrap();
 
-    map.insert(
-        "status".to_string(),
-        Entry::List(Box::new(
-            vg.status.iter().map(|x| Entry::String(x.clone())).collect(),
-        )),
-    );
+        assert!(vg.lv_create_linear(&lv_name, 1).is_err());
+        let reservation = vg.reserve(2).unwrap();
+        assert!(vg.lv_create_reserved(&lv_name, reservation).is_err());
 
-    map.insert(
-        "flags".to_string(),
-        Entry::List(Box::new(
-            vg.flags.iter().map(|x| Entry::String(x.clone())).collect(),
-        )),
-    );
+        assert_eq!(vg.lv_count(), 0);
+        assert_eq!(vg.pv_free_extents().values().sum::<u64>(), vg.extents());
+    }
 
-    map.insert(
-        "extent_size".to_string(),
-        Entry::Number(vg.extent_size as i64),
-    );
-    map.insert(
-        "metadata_copies".to_string(),
-        Entry::Number(vg.metadata_copies as i64),
-    );
+    #[test]
+    fn grow_mda_relocating_one_extent() {
+        if !can_use_dm() {
+            return;
+        }
 
-    // See comment in from_textmap() - we need to assign ordinals to
-    // the PV map so the textmap can use "pv0"-style strings to link
-    // pvs with LV stripes.
-    let dev_to_idx: BTreeMap<Device, usize> = vg
-        .pvs
-        .values()
-        .enumerate()
-        .map(|(num, pv)| (pv.device, num))
-        .collect();
+        let loop0 = LoopDevice::new(64 << 20);
+        let loop1 = LoopDevice::new(64 << 20);
+        let mut pvh0 = PvHeader::initialize(loop0.path()).unwrap();
+        PvHeader::initialize(loop1.path()).unwrap();
 
-    map.insert(
-        "physical_volumes".to_string(),
-        Entry::TextMap(Box::new(
-            vg.pvs
-                .iter()
-                .map(|(k, v)| {
-                    (
-                        format!("pv{}", dev_to_idx.get(k).unwrap()),
-                        Entry::TextMap(Box::new(pv::to_textmap(v))),
-                    )
-                })
-                .collect(),
-        )),
-    );
+        // lv0 takes the first extent of loop0, which the MDA grows into
+        let mut vg = VG::create("melvin-test-mda", vec![loop0.path()]).unwrap();
+        vg.lv_create_linear("lv0", 1).unwrap();
+        vg.deactivate_lv("lv0").unwrap();
+        vg.pv_add(loop1.path()).unwrap();
 
-    if !vg.lvs.is_empty() {
-        map.insert(
-            "logical_volumes".to_string(),
-            Entry::TextMap(Box::new(
-                vg.lvs
-                    .iter()
-                    .map(|(k, v)| {
-                        (
-                            k.clone(),
-                            Entry::TextMap(Box::new(lv::to_textmap(v, &dev_to_idx))),
-                        )
-                    })
-                    .collect(),
-            )),
+        let dev0 = Device::from_str(&loop0.path().to_string_lossy()).unwrap();
+        let pe_start = vg.pv_get(dev0).unwrap().pe_start;
+        let mda0 = pvh0.metadata_areas[0];
+        let needed = pe_start * SECTOR_SIZE as u64 - mda0.offset + 1;
+
+        vg.ensure_mda_capacity(&mut pvh0, needed).unwrap();
+
+        assert!(pvh0.metadata_areas[0].size >= needed);
+        assert_eq!(
+            vg.pv_get(dev0).unwrap().pe_start,
+            pe_start + vg.extent_size()
         );
-    }
+        assert!(vg
+            .lv_get("lv0")
+            .unwrap()
+            .segments
+            .iter()
+            .all(|seg| { !seg.pv_dependencies().contains(&dev0) }));
+        vg.validate().unwrap();
 
-    map
+        let map = PvHeader::find_in_dev(loop0.path())
+            .unwrap()
+            .read_metadata()
+            .unwrap();
+        let on_disk =
+            VG::from_textmap(vg.name(), map.textmap_from_textmap(vg.name()).unwrap()).unwrap();
+        assert!(on_disk.structurally_eq(&vg));
+    }
 }